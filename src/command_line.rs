@@ -0,0 +1,88 @@
+/// Text typed at the `:` or Save as prompt, edited at a cursor.
+#[derive(Default)]
+pub struct CommandLine {
+    text: String,
+    /// Byte index in `text` where typed characters go.
+    cursor: usize,
+}
+
+impl CommandLine {
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+    /// The text left of the cursor.
+    #[must_use]
+    pub fn before_cursor(&self) -> &str {
+        self.text.get(..self.cursor).unwrap_or(&self.text)
+    }
+    /// Replaces the text and puts the cursor at its end.
+    pub fn set(&mut self, text: &str) {
+        text.clone_into(&mut self.text);
+        self.cursor = self.text.len();
+    }
+    pub fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor = self.cursor.saturating_add(c.len_utf8());
+    }
+    /// Deletes the character left of the cursor.
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.move_left();
+            self.text.remove(self.cursor);
+        }
+    }
+    /// Deletes the character under the cursor.
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.text.remove(self.cursor);
+        }
+    }
+    pub fn move_left(&mut self) {
+        if let Some(c) = self.before_cursor().chars().next_back() {
+            self.cursor = self.cursor.saturating_sub(c.len_utf8());
+        }
+    }
+    pub fn move_right(&mut self) {
+        if let Some(c) = self
+            .text
+            .get(self.cursor..)
+            .and_then(|rest| rest.chars().next())
+        {
+            self.cursor = self.cursor.saturating_add(c.len_utf8());
+        }
+    }
+    pub fn move_home(&mut self) {
+        self.cursor = 0;
+    }
+    pub fn move_end(&mut self) {
+        self.cursor = self.text.len();
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_edit_mid_command() {
+        let mut line = CommandLine::default();
+        line.set("s/fo/bar/");
+        line.move_home();
+        line.move_right();
+        line.move_right();
+        line.move_right();
+        line.move_right();
+        line.insert('ö');
+        assert_eq!(line.as_str(), "s/foö/bar/");
+        assert_eq!(line.before_cursor(), "s/foö");
+        line.backspace();
+        line.move_left();
+        line.delete();
+        assert_eq!(line.as_str(), "s/f/bar/");
+        line.move_end();
+        line.delete();
+        line.backspace();
+        assert_eq!(line.as_str(), "s/f/bar");
+    }
+}
@@ -0,0 +1,31 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directory holding the user's config and state files, `~/.wd40` unless
+/// `--config-dir=` names another.
+#[must_use]
+pub fn config_dir(args: &[String]) -> Option<PathBuf> {
+    match args
+        .iter()
+        .find_map(|arg| arg.strip_prefix("--config-dir="))
+    {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".wd40")),
+    }
+}
+
+/// Reads the `config` file in `dir`, returning each line as a `:` command.
+/// Blank lines and `#` comments are skipped, and a leading `:` is optional.
+#[must_use]
+pub fn config_commands(dir: &Path) -> Vec<String> {
+    let Ok(contents) = fs::read_to_string(dir.join("config")) else {
+        return Vec::new();
+    };
+    contents
+        .lines()
+        .map(|line| line.trim().trim_start_matches(':').trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
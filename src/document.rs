@@ -1,9 +1,40 @@
+use crate::highlighting;
 use crate::FileType;
+use crate::Pattern;
 use crate::Position;
 use crate::Row;
 use crate::SearchDirection;
+use core::cell::Cell;
+use core::ops::Range;
 use std::fs;
-use std::io::{Error, Write as _};
+use std::io::{Error, ErrorKind, Read as _, Write as _};
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt as _;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use unicode_segmentation::UnicodeSegmentation as _;
+use unicode_width::UnicodeWidthStr as _;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+const DOCX_CONTENT_TYPES: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types">
+<Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/>
+<Default Extension="xml" ContentType="application/xml"/>
+<Override PartName="/word/document.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.document.main+xml"/>
+</Types>"#;
+const DOCX_RELS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+<Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="word/document.xml"/>
+</Relationships>"#;
+const DOCX_DOCUMENT_RELS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"/>"#;
+const ODT_MIMETYPE: &str = "application/vnd.oasis.opendocument.text";
+const ODT_MANIFEST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest:manifest xmlns:manifest="urn:oasis:names:tc:opendocument:xmlns:manifest:1.0" manifest:version="1.2">
+<manifest:file-entry manifest:full-path="/" manifest:media-type="application/vnd.oasis.opendocument.text"/>
+<manifest:file-entry manifest:full-path="content.xml" manifest:media-type="text/xml"/>
+</manifest:manifest>"#;
 
 #[derive(Default)]
 pub struct Document {
@@ -11,42 +42,287 @@ pub struct Document {
     pub file_name: Option<String>,
     dirty: bool,
     file_type: FileType,
+    disk_state: Option<DiskState>,
+    /// Set when the text is saved without a final newline.
+    no_eol: bool,
+    line_ending: LineEnding,
+    /// Set when the file was not valid UTF-8 and invalid bytes were replaced.
+    lossy: bool,
+    /// Set for the `file:line: text` list made by `:grep`, which can't be edited.
+    grep_results: bool,
+    /// Permissions and owner of the file when it was opened.
+    mode: Option<FileMode>,
+    /// Graphemes and bytes in the rows, counted on first use after an edit.
+    counts: Cell<Option<(usize, usize)>>,
+    /// Rows replaced since `take_row_edits`, as the old rows and how many rows
+    /// took their place, so that what is kept by row number can follow edits.
+    row_edits: Vec<(Range<usize>, usize)>,
+}
+
+/// How `Document::sort_rows` orders rows.
+#[derive(Default, Clone, Copy)]
+pub struct SortOptions {
+    pub reverse: bool,
+    /// Order by each row's leading integer; rows without one come first, sorted
+    /// as text.
+    pub numeric: bool,
+}
+
+/// Terminator written after each row when saving plain text.
+#[derive(Default, PartialEq, Eq, Clone, Copy)]
+pub enum LineEnding {
+    #[default]
+    Unix,
+    Dos,
+}
+
+impl LineEnding {
+    /// Parses the `ff` option value, `unix` or `dos`.
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unix" => Some(Self::Unix),
+            "dos" => Some(Self::Dos),
+            _ => None,
+        }
+    }
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Unix => "unix",
+            Self::Dos => "dos",
+        }
+    }
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Unix => "\n",
+            Self::Dos => "\r\n",
+        }
+    }
+    fn as_bytes(self) -> &'static [u8] {
+        self.as_str().as_bytes()
+    }
+    /// Picks whichever terminator most lines in `contents` use.
+    fn detect(contents: &str) -> Self {
+        if !contents.contains('\r') {
+            return Self::Unix;
+        }
+        let total = contents.matches('\n').count();
+        let dos = contents.matches("\r\n").count();
+        if dos > total.saturating_sub(dos) {
+            Self::Dos
+        } else {
+            Self::Unix
+        }
+    }
+}
+
+/// Modification time and size of the file as last read or written.
+#[derive(PartialEq, Eq, Clone, Copy)]
+struct DiskState {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl DiskState {
+    fn of(file_name: &str) -> Option<Self> {
+        let metadata = fs::metadata(file_name).ok()?;
+        Some(Self {
+            modified: metadata.modified().ok()?,
+            len: metadata.len(),
+        })
+    }
+}
+
+/// Permission bits and owner of a file, given back to it when it's replaced on save.
+#[derive(Clone)]
+struct FileMode {
+    permissions: fs::Permissions,
+    #[cfg(unix)]
+    owner: (u32, u32),
+}
+
+impl FileMode {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(Self {
+            permissions: metadata.permissions(),
+            #[cfg(unix)]
+            owner: (metadata.uid(), metadata.gid()),
+        })
+    }
+    fn apply(&self, path: &Path) -> Result<(), Error> {
+        // Only root can give a file away, so a failed chown is not an error.
+        // It comes first because it may clear the setuid and setgid bits.
+        #[cfg(unix)]
+        let _ = std::os::unix::fs::chown(path, Some(self.owner.0), Some(self.owner.1));
+        fs::set_permissions(path, self.permissions.clone())
+    }
 }
 
 impl Document {
     pub fn open(filename: &str) -> Result<Self, std::io::Error> {
-        let contents = fs::read_to_string(filename)?;
-        let file_type = FileType::from(filename);
+        let mut file_type = FileType::from(filename);
         let mut rows = Vec::new();
-        for value in contents.lines() {
-            rows.push(Row::from(value));
+        let mut no_eol = false;
+        let mut line_ending = LineEnding::Unix;
+        let mut lossy = false;
+        if extension(filename).as_deref() == Some("docx") {
+            for paragraph in read_docx(filename)? {
+                rows.push(Row::from(paragraph.as_str()));
+            }
+        } else {
+            let contents = match String::from_utf8(fs::read(filename)?) {
+                Ok(contents) => contents,
+                Err(error) => {
+                    lossy = true;
+                    String::from_utf8_lossy(error.as_bytes()).into_owned()
+                }
+            };
+            no_eol = !contents.is_empty() && !contents.ends_with('\n');
+            line_ending = LineEnding::detect(&contents);
+            file_type =
+                FileType::from_contents(filename, contents.lines().next().unwrap_or_default());
+            for value in contents.lines() {
+                rows.push(Row::from(value));
+            }
         }
         Ok(Self {
             rows,
             file_name: Some(filename.to_owned()),
             dirty: false,
             file_type,
+            disk_state: DiskState::of(filename),
+            no_eol,
+            line_ending,
+            lossy,
+            grep_results: false,
+            mode: FileMode::of(Path::new(filename)),
+            counts: Cell::new(None),
+            row_edits: Vec::new(),
         })
     }
-    #[must_use] 
+    /// Re-reads the document from `file_name`, discarding unsaved changes.
+    pub fn reload(&mut self) -> Result<(), Error> {
+        if let Some(file_name) = self.file_name.clone() {
+            *self = Self::open(&file_name)?;
+        }
+        Ok(())
+    }
+    /// True when the file on disk no longer matches what was last read or saved.
+    /// A file that is gone doesn't count, see `deleted_on_disk`.
+    #[must_use]
+    pub fn changed_on_disk(&self) -> bool {
+        match (&self.file_name, self.disk_state) {
+            (Some(file_name), Some(state)) => {
+                DiskState::of(file_name).is_some_and(|current| current != state)
+            }
+            _ => false,
+        }
+    }
+    /// True when the file that was last read or saved no longer exists.
+    #[must_use]
+    pub fn deleted_on_disk(&self) -> bool {
+        match (&self.file_name, self.disk_state) {
+            (Some(file_name), Some(_)) => DiskState::of(file_name).is_none(),
+            _ => false,
+        }
+    }
+    #[must_use]
+    pub fn line_comment_start(&self) -> Option<&str> {
+        self.file_type.highlighting_options().line_comment_start()
+    }
+    #[must_use]
     pub fn file_type(&self) -> String {
         self.file_type.name()
     }
-    #[must_use] 
+    #[must_use]
     pub fn row(&self, index: usize) -> Option<&Row> {
         self.rows.get(index)
     }
-    #[must_use] 
+    pub fn rows(&self) -> impl Iterator<Item = &Row> {
+        self.rows.iter()
+    }
+    /// The whole text, with the rows joined by the line ending.
+    #[must_use]
+    pub fn text(&self) -> String {
+        self.rows
+            .iter()
+            .map(Row::as_str)
+            .collect::<Vec<_>>()
+            .join(self.line_ending.as_str())
+    }
+    #[must_use]
     pub fn is_empty(&self) -> bool {
         self.rows.is_empty()
     }
-    #[must_use] 
+    #[must_use]
     pub fn len(&self) -> usize {
         self.rows.len()
     }
+    /// Number of graphemes from `start` up to `end`, counting each line break as one.
+    #[must_use]
+    pub fn count_between(&self, start: &Position, end: &Position) -> usize {
+        if start.y == end.y {
+            return end.x.saturating_sub(start.x);
+        }
+        let first = self
+            .row(start.y)
+            .map_or(0, |row| row.len().saturating_sub(start.x));
+        let middle: usize = self
+            .rows
+            .iter()
+            .skip(start.y.saturating_add(1))
+            .take(end.y.saturating_sub(start.y).saturating_sub(1))
+            .map(|row| row.len().saturating_add(1))
+            .sum();
+        first
+            .saturating_add(1)
+            .saturating_add(middle)
+            .saturating_add(end.x)
+    }
+    /// Number of characters as the user sees them, that is grapheme clusters, so
+    /// that `é` written as `e` and a combining accent is one. Line breaks are not
+    /// counted.
     #[must_use]
     pub fn char_count(&self) -> usize {
-        self.rows.iter().map(|r| r.len()).sum()
+        self.counts().0
+    }
+    /// Number of bytes the rows take in UTF-8, not counting line breaks.
+    #[must_use]
+    pub fn byte_count(&self) -> usize {
+        self.counts().1
+    }
+    fn counts(&self) -> (usize, usize) {
+        if let Some(counts) = self.counts.get() {
+            return counts;
+        }
+        let counts = self
+            .rows
+            .iter()
+            .fold((0_usize, 0_usize), |(chars, bytes), row| {
+                (
+                    chars.saturating_add(row.len()),
+                    bytes.saturating_add(row.as_str().len()),
+                )
+            });
+        self.counts.set(Some(counts));
+        counts
+    }
+    /// Marks the document as modified since it was saved, and forgets counts
+    /// that the change may have made stale.
+    fn mark_changed(&mut self) {
+        self.dirty = true;
+        self.counts.set(None);
+    }
+    /// Notes that `rows` were replaced by `count` rows. Edits within a row
+    /// aren't noted.
+    fn note_row_edit(&mut self, rows: Range<usize>, count: usize) {
+        self.row_edits.push((rows, count));
+    }
+    /// The rows replaced since the last call, oldest first.
+    pub fn take_row_edits(&mut self) -> Vec<(Range<usize>, usize)> {
+        core::mem::take(&mut self.row_edits)
     }
     fn insert_newline(&mut self, at: &Position) {
         if at.y > self.rows.len() {
@@ -54,127 +330,1516 @@ impl Document {
         }
         if at.y == self.rows.len() {
             self.rows.push(Row::default());
+            self.note_row_edit(at.y..at.y, 1);
             return;
         }
         #[expect(clippy::indexing_slicing)]
         let current_row = &mut self.rows[at.y];
         let new_row = current_row.split(at.x);
-        #[expect(clippy::arithmetic_side_effects)]
-        self.rows.insert(at.y + 1, new_row);
+        let index = at.y.saturating_add(1);
+        self.rows.insert(index, new_row);
+        self.note_row_edit(index..index, 1);
     }
     pub fn insert(&mut self, at: &Position, c: char) {
         if at.y > self.rows.len() {
             return;
         }
-        self.dirty = true;
+        self.mark_changed();
         if c == '\n' {
             self.insert_newline(at);
         } else if at.y == self.rows.len() {
             let mut row = Row::default();
             row.insert(0, c);
             self.rows.push(row);
+            self.note_row_edit(at.y..at.y, 1);
         } else {
             #[expect(clippy::indexing_slicing)]
             let row = &mut self.rows[at.y];
             row.insert(at.x, c);
         }
-        self.unhighlight_rows(at.y);
+    }
+    /// Removes the text from `start` up to `end`, joining their rows.
+    pub fn delete_range(&mut self, start: &Position, end: &Position) {
+        if (end.y, end.x) <= (start.y, start.x) || end.y >= self.rows.len() {
+            return;
+        }
+        let Some(tail) = self.rows.get_mut(end.y).map(|row| row.split(end.x)) else {
+            return;
+        };
+        if let Some(row) = self.rows.get_mut(start.y) {
+            row.split(start.x);
+            row.append(&tail);
+        }
+        let removed = start.y.saturating_add(1)..end.y.saturating_add(1);
+        self.rows.drain(removed.clone());
+        self.note_row_edit(removed, 0);
+        self.mark_changed();
+    }
+    /// Inserts `text` at `at`, starting a new row at each `\n` or `\r\n`. Returns
+    /// the position just past the inserted text, or `at` when nothing was inserted.
+    pub fn insert_str(&mut self, at: &Position, text: &str) -> Position {
+        if at.y > self.rows.len() || text.is_empty() {
+            return at.clone();
+        }
+        let text = text.replace("\r\n", "\n");
+        if at.y == self.rows.len() {
+            self.rows.push(Row::default());
+            self.note_row_edit(at.y..at.y, 1);
+        }
+        let Some(row) = self.rows.get_mut(at.y) else {
+            return at.clone();
+        };
+        let tail = row.split(at.x.min(row.len()));
+        let mut lines = text.split('\n');
+        row.append(&Row::from(lines.next().unwrap_or_default()));
+        let mut new_rows: Vec<Row> = lines.map(Row::from).collect();
+        let y = at.y.saturating_add(new_rows.len());
+        let last = new_rows.last_mut().unwrap_or(row);
+        let x = last.len();
+        last.append(&tail);
+        let index = at.y.saturating_add(1);
+        let count = new_rows.len();
+        self.rows.splice(index..index, new_rows);
+        if count > 0 {
+            self.note_row_edit(index..index, count);
+        }
+        self.mark_changed();
+        Position { x, y }
     }
 
-    fn unhighlight_rows(&mut self, start: usize) {
-        let start = start.saturating_sub(1);
-        for row in self.rows.iter_mut().skip(start) {
-            row.is_highlighted = false;
+    /// Inserts a copy of row `index` right below it. Returns false if there is no such row.
+    pub fn duplicate_row(&mut self, index: usize) -> bool {
+        let Some(row) = self.rows.get(index) else {
+            return false;
+        };
+        let copy = Row::from(row.as_str());
+        let below = index.saturating_add(1);
+        self.rows.insert(below, copy);
+        self.note_row_edit(below..below, 1);
+        self.mark_changed();
+        true
+    }
+
+    /// Swaps two rows. Returns false if either index is out of bounds.
+    pub fn swap_rows(&mut self, a: usize, b: usize) -> bool {
+        if a >= self.rows.len() || b >= self.rows.len() {
+            return false;
+        }
+        if a != b {
+            self.rows.swap(a, b);
+            let rows = a.min(b)..a.max(b).saturating_add(1);
+            self.note_row_edit(rows.clone(), rows.len());
+            self.mark_changed();
+        }
+        true
+    }
+
+    /// Appends row `index + 1` onto row `index` with a single space between them
+    /// (the next row's indentation is dropped). Returns the column of the join point,
+    /// or None when `index` is the last row.
+    pub fn join_rows(&mut self, index: usize) -> Option<usize> {
+        let next_index = index.saturating_add(1);
+        if next_index >= self.rows.len() {
+            return None;
         }
+        let next = self.rows.remove(next_index);
+        self.note_row_edit(next_index..next_index.saturating_add(1), 0);
+        let next_text = next.as_str().trim_start();
+        let row = self.rows.get_mut(index)?;
+        let join_at = row.len();
+        if !row.is_empty() && !next_text.is_empty() {
+            row.append(&Row::from(" "));
+        }
+        row.append(&Row::from(next_text));
+        self.mark_changed();
+        Some(join_at)
+    }
+    /// Stably sorts `rows` as text, or numerically with `options.numeric`.
+    /// Returns false, leaving the document unchanged, when they are in order already.
+    pub fn sort_rows(&mut self, range: Range<usize>, options: SortOptions) -> bool {
+        let Some(rows) = self.rows.get_mut(range.clone()) else {
+            return false;
+        };
+        let compare = |a: &Row, b: &Row| {
+            let ordering = if options.numeric {
+                leading_integer(a.as_str())
+                    .cmp(&leading_integer(b.as_str()))
+                    .then_with(|| a.as_str().cmp(b.as_str()))
+            } else {
+                a.as_str().cmp(b.as_str())
+            };
+            if options.reverse {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        };
+        if rows.is_sorted_by(|a, b| compare(a, b).is_le()) {
+            return false;
+        }
+        rows.sort_by(compare);
+        let count = range.len();
+        self.note_row_edit(range, count);
+        self.mark_changed();
+        true
+    }
+    /// Adds `delta` to the integer under or after `at` in its row, keeping the
+    /// width of a zero-padded number. Returns the column of the number's last
+    /// digit, or None when there is no number or the result would overflow.
+    pub fn add_to_number(&mut self, at: &Position, delta: i64) -> Option<usize> {
+        let row = self.rows.get_mut(at.y)?;
+        let graphemes: Vec<&str> = row.as_str().graphemes(true).collect();
+        let is_digit = |index: usize| {
+            graphemes.get(index).is_some_and(
+                |grapheme| matches!(grapheme.as_bytes(), [byte] if byte.is_ascii_digit()),
+            )
+        };
+        let mut start = if is_digit(at.x) {
+            at.x
+        } else {
+            (at.x..graphemes.len()).find(|&index| is_digit(index))?
+        };
+        while start > 0 && is_digit(start.saturating_sub(1)) {
+            start = start.saturating_sub(1);
+        }
+        let mut end = start;
+        while is_digit(end) {
+            end = end.saturating_add(1);
+        }
+        let digits = graphemes.get(start..end)?.concat();
+        let negative = start > 0 && graphemes.get(start.saturating_sub(1)) == Some(&"-");
+        if negative {
+            start = start.saturating_sub(1);
+        }
+        let value: i64 = digits.parse().ok()?;
+        let value = if negative {
+            value.checked_neg()?
+        } else {
+            value
+        };
+        let result = value.checked_add(delta)?;
+        let width = if digits.len() > 1 && digits.starts_with('0') {
+            digits.len()
+        } else {
+            0
+        };
+        let sign = if result < 0 { "-" } else { "" };
+        let number = format!("{sign}{:0width$}", result.unsigned_abs());
+        let text = format!(
+            "{}{number}{}",
+            graphemes.get(..start)?.concat(),
+            graphemes.get(end..)?.concat()
+        );
+        *row = Row::from(text.as_str());
+        self.mark_changed();
+        Some(start.saturating_add(number.len()).saturating_sub(1))
+    }
+    /// Inserts the lines of `text` as rows starting at `index`, or at the end when
+    /// `index` is past it. Returns how many rows were inserted.
+    pub fn insert_rows(&mut self, index: usize, text: &str) -> usize {
+        let index = index.min(self.rows.len());
+        self.replace_rows(index..index, text)
+    }
+    /// Replaces `rows` with the lines of `text`. Returns how many rows the text
+    /// now occupies.
+    pub fn replace_rows(&mut self, rows: Range<usize>, text: &str) -> usize {
+        let end = rows.end.min(self.rows.len());
+        let start = rows.start.min(end);
+        let new_rows: Vec<Row> = text.lines().map(Row::from).collect();
+        let count = new_rows.len();
+        if count > 0 || start < end {
+            self.rows.splice(start..end, new_rows);
+            self.note_row_edit(start..end, count);
+            self.mark_changed();
+        }
+        count
+    }
+    /// The graphemes in display `columns` of each row in `rows`, cut short where
+    /// a row ends before the block does. See `Row::indices_in_columns`.
+    #[must_use]
+    pub fn block_text(&self, rows: Range<usize>, columns: Range<usize>) -> Vec<String> {
+        self.rows
+            .get(rows.start.min(self.rows.len())..rows.end.min(self.rows.len()))
+            .unwrap_or_default()
+            .iter()
+            .map(|row| {
+                let indices = row.indices_in_columns(columns.clone());
+                row.as_str()
+                    .graphemes(true)
+                    .skip(indices.start)
+                    .take(indices.len())
+                    .collect()
+            })
+            .collect()
+    }
+    /// Removes the graphemes in display `columns` from each row in `rows`. Rows
+    /// ending inside the block lose only what they have.
+    pub fn delete_block(&mut self, rows: Range<usize>, columns: Range<usize>) {
+        let mut changed = false;
+        for row in self.rows.iter_mut().take(rows.end).skip(rows.start) {
+            let indices = row.indices_in_columns(columns.clone());
+            if indices.is_empty() {
+                continue;
+            }
+            let text: String = row
+                .as_str()
+                .graphemes(true)
+                .enumerate()
+                .filter(|(index, _)| !indices.contains(index))
+                .map(|(_, grapheme)| grapheme)
+                .collect();
+            *row = Row::from(text.as_str());
+            changed = true;
+        }
+        if changed {
+            self.mark_changed();
+        }
+    }
+    /// Inserts each of `lines` at display column `at.x` of consecutive rows from
+    /// `at.y`, after a wide grapheme that column falls inside. Rows narrower than
+    /// `at.x` are padded with spaces, and rows are added past the end of the document.
+    pub fn insert_block(&mut self, at: &Position, lines: &[String]) {
+        for (y, line) in (at.y..).zip(lines) {
+            if y >= self.rows.len() {
+                self.rows.push(Row::default());
+                self.note_row_edit(y..y, 1);
+            }
+            let Some(row) = self.rows.get_mut(y) else {
+                break;
+            };
+            let index = row.index_from_width(at.x);
+            let mut graphemes: Vec<&str> = row.as_str().graphemes(true).collect();
+            let padding = at.x.saturating_sub(row.width());
+            graphemes.extend(core::iter::repeat_n(" ", padding));
+            graphemes.insert(index.saturating_add(padding), line);
+            *row = Row::from(graphemes.concat().as_str());
+            self.mark_changed();
+        }
+    }
+    /// Collapses runs of identical consecutive rows within `rows` into one row.
+    /// Returns how many rows were removed.
+    pub fn dedup_rows(&mut self, rows: Range<usize>) -> usize {
+        let Some(range) = self.rows.get(rows.clone()) else {
+            return 0;
+        };
+        let mut kept: Vec<Row> = Vec::with_capacity(range.len());
+        for row in range {
+            if kept.last().is_none_or(|last| last.as_str() != row.as_str()) {
+                kept.push(Row::from(row.as_str()));
+            }
+        }
+        let removed = range.len().saturating_sub(kept.len());
+        if removed > 0 {
+            let count = kept.len();
+            self.rows.splice(rows.clone(), kept);
+            self.note_row_edit(rows, count);
+            self.mark_changed();
+        }
+        removed
+    }
+    /// Replaces each character from `start` up to `end` with `f` of it. `f` is
+    /// also given `'\n'` between rows, with the result dropped, so it can track
+    /// word boundaries.
+    pub fn transform_range(
+        &mut self,
+        start: &Position,
+        end: &Position,
+        mut f: impl FnMut(char) -> String,
+    ) {
+        for y in start.y..=end.y {
+            let Some(row) = self.rows.get_mut(y) else {
+                break;
+            };
+            let from = if y == start.y { start.x } else { 0 };
+            let to = if y == end.y { end.x } else { row.len() };
+            let mut text = String::new();
+            for (index, grapheme) in row.as_str().graphemes(true).enumerate() {
+                if (from..to).contains(&index) {
+                    text.extend(grapheme.chars().map(&mut f));
+                } else {
+                    text.push_str(grapheme);
+                }
+            }
+            *row = Row::from(text.as_str());
+            if y < end.y {
+                f('\n');
+            }
+        }
+        self.mark_changed();
+    }
+    /// Comments out `rows` by putting `token` and a space after each row's
+    /// indentation, or uncomments them when every non-blank row is already
+    /// commented. Returns true when a row changed.
+    pub fn toggle_comment(&mut self, rows: Range<usize>, token: &str) -> bool {
+        let Some(rows) = self.rows.get_mut(rows).filter(|rows| !rows.is_empty()) else {
+            return false;
+        };
+        let add = rows.iter().any(|row| {
+            let text = row.as_str().trim_start();
+            !text.is_empty() && !text.starts_with(token)
+        });
+        let mut changed = false;
+        for row in rows.iter_mut() {
+            let text = row.as_str();
+            let body = text.trim_start();
+            let indent = text.strip_suffix(body).unwrap_or_default();
+            let toggled = if add {
+                if body.is_empty() {
+                    continue;
+                }
+                format!("{indent}{token} {body}")
+            } else if let Some(rest) = body.strip_prefix(token) {
+                format!("{indent}{}", rest.strip_prefix(' ').unwrap_or(rest))
+            } else {
+                continue;
+            };
+            *row = Row::from(toggled.as_str());
+            changed = true;
+        }
+        if changed {
+            self.mark_changed();
+        }
+        changed
+    }
+    /// Indents each non-blank row of `rows` by `width` spaces, or by a tab when its
+    /// indentation starts with one. With `unindent`, removes one leading tab or up
+    /// to `width` leading spaces instead. Returns true when a row changed.
+    pub fn indent_rows(&mut self, rows: Range<usize>, width: usize, unindent: bool) -> bool {
+        let Some(rows) = self.rows.get_mut(rows) else {
+            return false;
+        };
+        let mut changed = false;
+        for row in rows.iter_mut() {
+            let text = row.as_str();
+            let indented = if unindent {
+                if let Some(rest) = text.strip_prefix('\t') {
+                    rest.to_owned()
+                } else {
+                    let spaces = text.chars().take(width).take_while(|&c| c == ' ').count();
+                    text.get(spaces..).unwrap_or_default().to_owned()
+                }
+            } else if text.trim().is_empty() {
+                continue;
+            } else if text.starts_with('\t') {
+                format!("\t{text}")
+            } else {
+                format!("{}{text}", " ".repeat(width))
+            };
+            if indented != text {
+                *row = Row::from(indented.as_str());
+                changed = true;
+            }
+        }
+        if changed {
+            self.mark_changed();
+        }
+        changed
     }
+    /// The rows of the paragraph of consecutive non-blank rows around `index`, or
+    /// None when `index` is a blank row.
+    #[must_use]
+    pub fn paragraph(&self, index: usize) -> Option<Range<usize>> {
+        let is_blank = |row: &Row| row.as_str().trim().is_empty();
+        if self.rows.get(index).is_none_or(is_blank) {
+            return None;
+        }
+        let start = self
+            .rows
+            .iter()
+            .take(index)
+            .rposition(is_blank)
+            .map_or(0, |blank| blank.saturating_add(1));
+        let end = self
+            .rows
+            .iter()
+            .skip(index)
+            .position(is_blank)
+            .map_or(self.rows.len(), |blank| index.saturating_add(blank));
+        Some(start..end)
+    }
+    /// Rewraps the paragraph of consecutive non-empty rows around `index` so that
+    /// no row is wider than `width`, keeping the first row's indentation. Returns
+    /// the rows the paragraph now occupies, or None when `index` is a blank row.
+    pub fn reflow_paragraph(&mut self, index: usize, width: usize) -> Option<Range<usize>> {
+        let Range { start, end } = self.paragraph(index)?;
+        let lines: Vec<&str> = self.rows.get(start..end)?.iter().map(Row::as_str).collect();
+        let reflowed: Vec<Row> = reflow(&lines, width)
+            .iter()
+            .map(|line| Row::from(line.as_str()))
+            .collect();
+        let new_end = start.saturating_add(reflowed.len());
+        self.rows.splice(start..end, reflowed);
+        self.note_row_edit(start..end, new_end.saturating_sub(start));
+        self.mark_changed();
+        Some(start..new_end)
+    }
+
     #[expect(clippy::arithmetic_side_effects, clippy::indexing_slicing)]
     pub fn delete(&mut self, at: &Position) {
         let len = self.rows.len();
         if at.y >= len {
             return;
         }
-        self.dirty = true;
+        self.mark_changed();
         if at.x == self.rows[at.y].len() && at.y + 1 < len {
             let next_row = self.rows.remove(at.y + 1);
+            self.note_row_edit(at.y + 1..at.y + 2, 0);
             let row = &mut self.rows[at.y];
             row.append(&next_row);
         } else {
             let row = &mut self.rows[at.y];
             row.delete(at.x);
         }
-        self.unhighlight_rows(at.y);
     }
-    pub fn save(&mut self) -> Result<(), Error> {
+    /// Detects the file type again from the file name and first row, and
+    /// highlights every row afresh.
+    pub fn detect_file_type(&mut self) {
         if let Some(file_name) = &self.file_name {
-            let mut file = fs::File::create(file_name)?;
-            self.file_type = FileType::from(file_name);
-            for row in &mut self.rows {
+            self.file_type =
+                FileType::from_contents(file_name, self.rows.first().map_or("", Row::as_str));
+        }
+        for row in &mut self.rows {
+            row.is_highlighted = false;
+        }
+    }
+    /// Writes the document to `file_name`. Returns false when the file had to be
+    /// overwritten in place, because a temporary copy couldn't be renamed over it.
+    pub fn save(&mut self) -> Result<bool, Error> {
+        let mut replaced = true;
+        if let Some(file_name) = self.file_name.clone() {
+            let file_name = file_name.as_str();
+            replaced = match extension(file_name).as_deref() {
+                Some("docx") => self.save_docx(file_name)?,
+                Some("odt") => self.save_odt(file_name)?,
+                _ => self.save_text(file_name)?,
+            };
+            self.detect_file_type();
+            self.dirty = false;
+            self.disk_state = DiskState::of(file_name);
+            self.remove_swap();
+        }
+        Ok(replaced)
+    }
+    pub fn save_text(&self, file_name: &str) -> Result<bool, Error> {
+        write_replacing(file_name, self.mode.as_ref(), |file| {
+            for (index, row) in self.rows.iter().enumerate() {
+                file.write_all(row.as_bytes())?;
+                if !self.no_eol || index.saturating_add(1) < self.rows.len() {
+                    file.write_all(self.line_ending.as_bytes())?;
+                }
+            }
+            Ok(())
+        })
+    }
+    /// Writes a minimal OOXML package with one `<w:p>` paragraph per row.
+    pub fn save_docx(&self, file_name: &str) -> Result<bool, Error> {
+        let mut body = String::new();
+        for row in &self.rows {
+            let text = xml_escape(row.as_str());
+            if text.is_empty() {
+                body.push_str("<w:p/>");
+            } else {
+                body.push_str(&format!(
+                    "<w:p><w:r><w:t xml:space=\"preserve\">{text}</w:t></w:r></w:p>"
+                ));
+            }
+        }
+        let document = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+             <w:document xmlns:w=\"http://schemas.openxmlformats.org/wordprocessingml/2006/main\">\
+             <w:body>{body}</w:body></w:document>"
+        );
+
+        write_replacing(file_name, self.mode.as_ref(), |file| {
+            let mut zip = ZipWriter::new(file);
+            let options = SimpleFileOptions::default();
+            for (name, contents) in [
+                ("[Content_Types].xml", DOCX_CONTENT_TYPES),
+                ("_rels/.rels", DOCX_RELS),
+                ("word/_rels/document.xml.rels", DOCX_DOCUMENT_RELS),
+                ("word/document.xml", document.as_str()),
+            ] {
+                zip.start_file(name, options).map_err(Error::other)?;
+                zip.write_all(contents.as_bytes())?;
+            }
+            zip.finish().map_err(Error::other)?;
+            Ok(())
+        })
+    }
+    /// Writes an OpenDocument Text package with one `<text:p>` paragraph per row.
+    pub fn save_odt(&self, file_name: &str) -> Result<bool, Error> {
+        let mut body = String::new();
+        for row in &self.rows {
+            body.push_str(&format!("<text:p>{}</text:p>", odt_escape(row.as_str())));
+        }
+        let content = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <office:document-content \
+             xmlns:office=\"urn:oasis:names:tc:opendocument:xmlns:office:1.0\" \
+             xmlns:text=\"urn:oasis:names:tc:opendocument:xmlns:text:1.0\" \
+             office:version=\"1.2\">\
+             <office:body><office:text>{body}</office:text></office:body>\
+             </office:document-content>"
+        );
+
+        write_replacing(file_name, self.mode.as_ref(), |file| {
+            let mut zip = ZipWriter::new(file);
+            // The mimetype entry must come first and be stored uncompressed
+            zip.start_file(
+                "mimetype",
+                SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+            )
+            .map_err(Error::other)?;
+            zip.write_all(ODT_MIMETYPE.as_bytes())?;
+            let options = SimpleFileOptions::default();
+            for (name, contents) in [
+                ("META-INF/manifest.xml", ODT_MANIFEST),
+                ("content.xml", content.as_str()),
+            ] {
+                zip.start_file(name, options).map_err(Error::other)?;
+                zip.write_all(contents.as_bytes())?;
+            }
+            zip.finish().map_err(Error::other)?;
+            Ok(())
+        })
+    }
+    /// Sibling backup path for the current file, e.g. `notes.txt` -> `.notes.txt.swp`.
+    #[must_use]
+    pub fn swap_path(&self) -> Option<PathBuf> {
+        let file_name = self.file_name.as_ref()?;
+        let path = Path::new(file_name);
+        let base = path.file_name()?.to_str()?;
+        Some(path.with_file_name(format!(".{base}.swp")))
+    }
+    pub fn write_swap(&self) -> Result<(), Error> {
+        if let Some(swap_path) = self.swap_path() {
+            let mut file = fs::File::create(swap_path)?;
+            for row in &self.rows {
                 file.write_all(row.as_bytes())?;
                 file.write_all(b"\n")?;
             }
-            self.dirty = false;
         }
         Ok(())
     }
-    #[must_use] 
+    /// True when a swap file exists that was written after the file itself.
+    #[must_use]
+    pub fn has_newer_swap(&self) -> bool {
+        let Some(swap_path) = self.swap_path() else {
+            return false;
+        };
+        let Ok(swap_modified) = fs::metadata(swap_path).and_then(|m| m.modified()) else {
+            return false;
+        };
+        match self
+            .file_name
+            .as_ref()
+            .map(|name| fs::metadata(name).and_then(|m| m.modified()))
+        {
+            Some(Ok(file_modified)) => swap_modified > file_modified,
+            _ => true,
+        }
+    }
+    pub fn recover_from_swap(&mut self) -> Result<(), Error> {
+        if let Some(swap_path) = self.swap_path() {
+            let contents = fs::read_to_string(swap_path)?;
+            let old_len = self.rows.len();
+            self.rows = contents.lines().map(Row::from).collect();
+            self.note_row_edit(0..old_len, self.rows.len());
+            self.mark_changed();
+        }
+        Ok(())
+    }
+    pub fn remove_swap(&self) {
+        if let Some(swap_path) = self.swap_path() {
+            let _ = fs::remove_file(swap_path);
+        }
+    }
+    #[must_use]
     pub fn is_dirty(&self) -> bool {
         self.dirty
     }
-    #[expect(clippy::indexing_slicing)]
-    #[must_use] 
-    pub fn find(&self, query: &str, at: &Position, direction: SearchDirection) -> Option<Position> {
-        if at.y >= self.rows.len() {
-            return None;
+    /// A `:grep` results list holding the lines of `text`.
+    #[must_use]
+    pub fn grep_results(text: &str) -> Self {
+        Self {
+            rows: text.lines().map(Row::from).collect(),
+            grep_results: true,
+            ..Self::default()
         }
-        let mut position = Position { x: at.x, y: at.y };
-
-        let start = if direction == SearchDirection::Forward {
-            at.y
+    }
+    #[must_use]
+    pub fn is_grep_results(&self) -> bool {
+        self.grep_results
+    }
+    /// True when the file was not valid UTF-8, so saving it would corrupt it.
+    #[must_use]
+    pub fn is_lossy(&self) -> bool {
+        self.lossy
+    }
+    /// True when saving appends a newline after the last row.
+    #[must_use]
+    pub fn eol(&self) -> bool {
+        !self.no_eol
+    }
+    #[must_use]
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        if self.line_ending != line_ending {
+            self.line_ending = line_ending;
+            self.mark_changed();
+        }
+    }
+    pub fn set_eol(&mut self, eol: bool) {
+        if self.no_eol == eol {
+            self.no_eol = !eol;
+            self.mark_changed();
+        }
+    }
+    /// Finds the next match of `pattern` from `at`, returning its position, its
+    /// length in graphemes and whether the search wrapped past the end of the
+    /// document (or the start, searching backward) to find it. Every row is
+    /// searched at most once more after wrapping, so a pattern with no matches
+    /// gives None.
+    #[must_use]
+    pub fn find(
+        &self,
+        pattern: &Pattern,
+        at: &Position,
+        direction: SearchDirection,
+    ) -> Option<(Position, usize, bool)> {
+        let len = self.rows.len();
+        let y = at.y.min(len);
+        // Rows in search order, each with whether the search wrapped to reach it
+        let mut forward = (y..len)
+            .map(|row_y| (row_y, false))
+            .chain((0..=y).map(|row_y| (row_y, true)));
+        let mut backward = (0..=y)
+            .rev()
+            .map(|row_y| (row_y, false))
+            .chain((y..len).rev().map(|row_y| (row_y, true)));
+        let rows: &mut dyn Iterator<Item = (usize, bool)> = if direction == SearchDirection::Forward
+        {
+            &mut forward
         } else {
-            0
+            &mut backward
         };
-        let end = if direction == SearchDirection::Forward {
-            self.rows.len()
-        } else {
-            at.y.saturating_add(1)
+        for (index, (row_y, wrapped)) in rows.enumerate() {
+            let Some(row) = self.rows.get(row_y) else {
+                continue;
+            };
+            let x = if index == 0 {
+                at.x
+            } else if direction == SearchDirection::Forward {
+                0
+            } else {
+                row.len()
+            };
+            if let Some((x, match_len)) = row.find_pattern(pattern, x, direction) {
+                return Some((Position { x, y: row_y }, match_len, wrapped));
+            }
+        }
+        None
+    }
+    /// Counts every match of `pattern` in the document, and returns the 1-based
+    /// index of the match starting at `at`, if there is one.
+    #[must_use]
+    pub fn match_stats(&self, pattern: &Pattern, at: &Position) -> (Option<usize>, usize) {
+        let mut total: usize = 0;
+        let mut current = None;
+        for (y, row) in self.rows.iter().enumerate() {
+            let mut x = 0;
+            while let Some((found, len)) = row.find_pattern(pattern, x, SearchDirection::Forward) {
+                total = total.saturating_add(1);
+                if y == at.y && found == at.x {
+                    current = Some(total);
+                }
+                x = found.saturating_add(len.max(1));
+            }
+        }
+        (current, total)
+    }
+    /// Finds the bracket matching the one at `at`, tracking nesting across rows.
+    /// Brackets highlighted differently from the starting one (e.g. inside a
+    /// string or comment) are ignored.
+    #[must_use]
+    pub fn find_matching_bracket(&self, at: &Position) -> Option<Position> {
+        let row = self.rows.get(at.y)?;
+        let bracket = row.as_str().graphemes(true).nth(at.x)?;
+        let (counterpart, direction) = match bracket {
+            "(" => (")", SearchDirection::Forward),
+            "[" => ("]", SearchDirection::Forward),
+            "{" => ("}", SearchDirection::Forward),
+            ")" => ("(", SearchDirection::Backward),
+            "]" => ("[", SearchDirection::Backward),
+            "}" => ("{", SearchDirection::Backward),
+            _ => return None,
+        };
+        let region = row.highlighting_at(at.x);
+        let mut depth: usize = 0;
+        let mut check = |row: &Row, grapheme: &str, x: usize| {
+            if row.highlighting_at(x) != region {
+                return false;
+            }
+            if grapheme == bracket {
+                depth = depth.saturating_add(1);
+            } else if grapheme == counterpart {
+                depth = depth.saturating_sub(1);
+                return depth == 0;
+            }
+            false
         };
-        for _ in start..end {
-            if let Some(row) = self.rows.get(position.y) {
-                if let Some(x) = row.find(query, position.x, direction) {
-                    position.x = x;
-                    return Some(position);
+
+        if direction == SearchDirection::Forward {
+            for (y, row) in self.rows.iter().enumerate().skip(at.y) {
+                let start = if y == at.y { at.x } else { 0 };
+                for (x, grapheme) in row.as_str().graphemes(true).enumerate().skip(start) {
+                    if check(row, grapheme, x) {
+                        return Some(Position { x, y });
+                    }
                 }
-                if direction == SearchDirection::Forward {
-                    position.y = position.y.saturating_add(1);
-                    position.x = 0;
+            }
+        } else {
+            for (y, row) in self
+                .rows
+                .iter()
+                .enumerate()
+                .take(at.y.saturating_add(1))
+                .rev()
+            {
+                let graphemes: Vec<&str> = row.as_str().graphemes(true).collect();
+                let end = if y == at.y {
+                    at.x.saturating_add(1)
                 } else {
-                    position.y = position.y.saturating_sub(1);
-                    position.x = self.rows[position.y].len();
+                    graphemes.len()
+                };
+                for (x, grapheme) in graphemes.iter().enumerate().take(end).rev() {
+                    if check(row, grapheme, x) {
+                        return Some(Position { x, y });
+                    }
                 }
-            } else {
-                return None;
             }
         }
         None
     }
-    pub fn highlight(&mut self, word: &Option<String>, until: Option<usize>) {
-        let mut start_with_comment = false;
-        let until = if let Some(until) = until {
-            if until.saturating_add(1) < self.rows.len() {
-                until.saturating_add(1)
-            } else {
-                self.rows.len()
+    pub fn set_highlight(&mut self, at: &Position, hl_type: highlighting::Type) {
+        if let Some(row) = self.rows.get_mut(at.y) {
+            row.set_highlight(at.x, hl_type);
+        }
+    }
+    /// Forces `rows` to be re-highlighted on the next pass. Edits mark the rows they
+    /// change on their own.
+    pub fn invalidate_highlighting(&mut self, rows: Range<usize>) {
+        for row in self.rows.iter_mut().take(rows.end).skip(rows.start) {
+            row.is_highlighted = false;
+        }
+    }
+    /// Highlights the rows up to the end of `visible`, re-highlighting only rows that
+    /// changed or whose preceding row now leaves a different block open. Matches of
+    /// `word` are only recomputed inside `visible`; rows above it just carry the
+    /// open comment or string state down.
+    pub fn highlight(&mut self, word: &Option<Pattern>, visible: Range<usize>) {
+        let mut open_block = highlighting::Block::None;
+        let until = visible.end.saturating_add(1).min(self.rows.len());
+        #[expect(clippy::indexing_slicing)]
+        for (index, row) in self.rows[..until].iter_mut().enumerate() {
+            let word = if index < visible.start { &None } else { word };
+            open_block = row.highlight(self.file_type.highlighting_options(), word, open_block);
+        }
+    }
+}
+
+/// Writes `file_name` by filling a temporary file beside it with `write`,
+/// syncing it to disk and renaming it over the original, so a failed save
+/// leaves the old contents intact. The new file keeps the permissions and owner
+/// of the one it replaces, or `mode` when there is none. Symlinks are followed,
+/// not replaced.
+///
+/// When the temporary file can't be created or renamed, such as in a directory
+/// that isn't writable, the file is overwritten in place and false is returned.
+fn write_replacing(
+    file_name: &str,
+    mode: Option<&FileMode>,
+    write: impl Fn(&mut fs::File) -> Result<(), Error>,
+) -> Result<bool, Error> {
+    let target = fs::canonicalize(file_name).unwrap_or_else(|_| PathBuf::from(file_name));
+    let base = target
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "not a file name"))?;
+    let temp = target.with_file_name(format!(".{base}.{}.tmp", std::process::id()));
+    let mode = FileMode::of(&target).or_else(|| mode.cloned());
+    let Ok(mut file) = fs::File::create(&temp) else {
+        write_in_place(&target, &write)?;
+        return Ok(false);
+    };
+    let written = write(&mut file)
+        .and_then(|()| file.sync_all())
+        .and_then(|()| mode.map_or(Ok(()), |mode| mode.apply(&temp)));
+    if let Err(error) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(error);
+    }
+    if fs::rename(&temp, &target).is_err() {
+        let _ = fs::remove_file(&temp);
+        write_in_place(&target, &write)?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+fn write_in_place(
+    target: &Path,
+    write: &impl Fn(&mut fs::File) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut file = fs::File::create(target)?;
+    write(&mut file)?;
+    file.sync_all()
+}
+
+/// Joins `lines` and splits the words again so each line fits in `width` columns,
+/// prefixing every line with the first line's indentation. A word wider than the
+/// remaining space gets a line to itself.
+fn reflow(lines: &[&str], width: usize) -> Vec<String> {
+    let first = lines.first().copied().unwrap_or_default();
+    let indent = first.strip_suffix(first.trim_start()).unwrap_or_default();
+    let mut reflowed = Vec::new();
+    let mut line = indent.to_owned();
+    for word in lines.iter().flat_map(|line| line.split_whitespace()) {
+        let is_first_word = line.len() == indent.len();
+        if !is_first_word && line.width().saturating_add(1).saturating_add(word.width()) > width {
+            reflowed.push(core::mem::replace(&mut line, indent.to_owned()));
+        } else if !is_first_word {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    reflowed.push(line);
+    reflowed
+}
+
+/// The integer at the start of `line`, after any indentation.
+fn leading_integer(line: &str) -> Option<i64> {
+    let line = line.trim_start();
+    let digits = line
+        .strip_prefix('-')
+        .unwrap_or(line)
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(line.len(), |end| {
+            end.saturating_add(usize::from(line.starts_with('-')))
+        });
+    line.get(..digits)?.parse().ok()
+}
+
+fn extension(file_name: &str) -> Option<String> {
+    Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+}
+
+fn read_docx(filename: &str) -> Result<Vec<String>, Error> {
+    let corrupt = || Error::new(ErrorKind::InvalidData, "Unsupported or corrupt docx");
+    let mut archive = ZipArchive::new(fs::File::open(filename)?).map_err(|_| corrupt())?;
+    let mut xml = String::new();
+    archive
+        .by_name("word/document.xml")
+        .map_err(|_| corrupt())?
+        .read_to_string(&mut xml)
+        .map_err(|_| corrupt())?;
+    docx_paragraphs(&xml).ok_or_else(corrupt)
+}
+
+/// Extracts the text of each top-level `<w:p>` in a `word/document.xml` body,
+/// concatenating its runs. Tables and other non-text elements are skipped.
+fn docx_paragraphs(xml: &str) -> Option<Vec<String>> {
+    let mut rest = xml.get(xml.find("<w:body")?..)?;
+    let mut paragraphs = Vec::new();
+    let mut paragraph: Option<String> = None;
+    let mut table_depth: usize = 0;
+    let mut in_text = false;
+    // Tabs outside a run, such as the tab stops in `<w:tabs>`, are not text
+    let mut in_run = false;
+    while let Some(open) = rest.find('<') {
+        if in_text && table_depth == 0 {
+            if let (Some(p), Some(text)) = (paragraph.as_mut(), rest.get(..open)) {
+                p.push_str(&xml_unescape(text));
             }
-        } else {
-            self.rows.len()
+        }
+        let close = open.saturating_add(rest.get(open..)?.find('>')?);
+        let tag = rest.get(open.saturating_add(1)..close)?;
+        rest = rest.get(close.saturating_add(1)..)?;
+
+        let is_end = tag.starts_with('/');
+        let is_empty = tag.ends_with('/');
+        let name = tag
+            .trim_matches('/')
+            .split_whitespace()
+            .next()
+            .unwrap_or_default();
+        match name {
+            "w:tbl" if is_end => table_depth = table_depth.saturating_sub(1),
+            "w:tbl" if !is_empty => table_depth = table_depth.saturating_add(1),
+            "w:p" if table_depth == 0 => {
+                if is_end {
+                    paragraphs.push(paragraph.take().unwrap_or_default());
+                } else if is_empty {
+                    paragraphs.push(String::new());
+                } else {
+                    paragraph = Some(String::new());
+                }
+            }
+            "w:r" => in_run = !is_end && !is_empty,
+            "w:t" => in_text = !is_end && !is_empty,
+            "w:tab" if in_run && table_depth == 0 => {
+                if let Some(p) = paragraph.as_mut() {
+                    p.push('\t');
+                }
+            }
+            _ => (),
+        }
+    }
+    Some(paragraphs)
+}
+
+fn xml_unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        unescaped.push_str(rest.get(..amp).unwrap_or_default());
+        rest = rest.get(amp..).unwrap_or_default();
+        let Some(semi) = rest.find(';') else {
+            break;
         };
-        #[expect(clippy::indexing_slicing)]
-        for row in &mut self.rows[..until] {
-            start_with_comment = row.highlight(
-                self.file_type.highlighting_options(),
-                word,
-                start_with_comment,
-            );
+        let entity = rest.get(1..semi).unwrap_or_default();
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        if let Some(c) = decoded {
+            unescaped.push(c);
+            rest = rest.get(semi.saturating_add(1)..).unwrap_or_default();
+        } else {
+            unescaped.push('&');
+            rest = rest.get(1..).unwrap_or_default();
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// XML-escapes a row and encodes runs of spaces and tabs the way ODF expects.
+fn odt_escape(text: &str) -> String {
+    let mut escaped = String::new();
+    let mut spaces = 0;
+    for c in xml_escape(text).chars() {
+        if c == ' ' {
+            spaces += 1;
+            continue;
+        }
+        push_odt_spaces(&mut escaped, spaces);
+        spaces = 0;
+        if c == '\t' {
+            escaped.push_str("<text:tab/>");
+        } else {
+            escaped.push(c);
         }
     }
+    push_odt_spaces(&mut escaped, spaces);
+    escaped
+}
+
+fn push_odt_spaces(escaped: &mut String, count: usize) {
+    if count > 0 {
+        escaped.push(' ');
+    }
+    if count > 1 {
+        escaped.push_str(&format!("<text:s text:c=\"{}\"/>", count.saturating_sub(1)));
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_docx_paragraphs() {
+        let xml = r#"<w:document><w:body>
+            <w:p><w:r><w:t>Hello</w:t></w:r><w:r><w:t xml:space="preserve"> &amp; welcome</w:t></w:r></w:p>
+            <w:p/>
+            <w:tbl><w:tr><w:tc><w:p><w:r><w:t>cell</w:t></w:r></w:p></w:tc></w:tr></w:tbl>
+            <w:p><w:r><w:tab/><w:t>&lt;tag&gt;</w:t></w:r></w:p>
+        </w:body></w:document>"#;
+        assert_eq!(
+            docx_paragraphs(xml),
+            Some(vec![
+                "Hello & welcome".to_owned(),
+                String::new(),
+                "\t<tag>".to_owned()
+            ])
+        );
+        assert_eq!(docx_paragraphs("not xml"), None);
+    }
+
+    #[test]
+    fn test_docx_tab_stops_are_not_text() {
+        let xml = r#"<w:document><w:body>
+            <w:p><w:pPr><w:tabs><w:tab w:val="left" w:pos="720"/><w:tab w:val="right" w:pos="9000"/></w:tabs></w:pPr><w:r><w:t>a</w:t><w:tab/><w:t>b</w:t></w:r></w:p>
+        </w:body></w:document>"#;
+        assert_eq!(docx_paragraphs(xml), Some(vec!["a\tb".to_owned()]));
+    }
+
+    #[test]
+    fn test_highlight_propagates_blocks() {
+        let mut doc = Document {
+            rows: ["/*", "a", "*/", "b"].map(Row::from).into(),
+            file_type: FileType::from("test.rs"),
+            ..Document::default()
+        };
+        doc.highlight(&None, 0..4);
+        let at = |doc: &Document, y| doc.row(y).unwrap().highlighting_at(0);
+        assert_eq!(at(&doc, 1), highlighting::Type::MultilineComment);
+        doc.delete(&Position::default());
+        assert!(doc.row(1).unwrap().is_highlighted);
+        doc.highlight(&None, 0..4);
+        assert_eq!(at(&doc, 1), highlighting::Type::None);
+        assert_eq!(at(&doc, 3), highlighting::Type::None);
+        doc.insert(&Position::default(), '/');
+        doc.highlight(&None, 0..4);
+        assert_eq!(at(&doc, 1), highlighting::Type::MultilineComment);
+    }
+
+    #[test]
+    fn test_highlight_matches_only_visible_rows() {
+        let mut doc = Document {
+            rows: ["one", "two", "one", "two"].map(Row::from).into(),
+            ..Document::default()
+        };
+        let word = Some(Pattern::new("one", false).unwrap());
+        let current = format!(
+            "{}",
+            crossterm::style::SetForegroundColor(
+                crate::ColorScheme::default().color(highlighting::Type::CurrentMatch)
+            )
+        );
+        doc.highlight(&word, 0..4);
+        doc.set_highlight(&Position::default(), highlighting::Type::CurrentMatch);
+        doc.highlight(&word, 2..4);
+        assert!(doc.row(0).unwrap().render(0, 3, None).contains(&current));
+        doc.highlight(&word, 0..4);
+        assert!(!doc.row(0).unwrap().render(0, 3, None).contains(&current));
+    }
+
+    #[test]
+    fn test_find_matching_bracket() {
+        let doc = Document {
+            rows: vec![
+                Row::from("fn a() {"),
+                Row::from("    b(\")\");"),
+                Row::from("}"),
+            ],
+            ..Document::default()
+        };
+        let open = Position { x: 7, y: 0 };
+        let close = doc.find_matching_bracket(&open);
+        assert_eq!(close.as_ref().map(|p| (p.x, p.y)), Some((0, 2)));
+        let back = doc.find_matching_bracket(&Position { x: 0, y: 2 });
+        assert_eq!(back.map(|p| (p.x, p.y)), Some((7, 0)));
+        assert!(doc
+            .find_matching_bracket(&Position { x: 1, y: 0 })
+            .is_none());
+    }
+
+    #[test]
+    fn test_char_count() {
+        let mut doc = Document {
+            rows: vec![
+                Row::from("e\u{301}t\u{e9}"),
+                Row::from("\u{1f44d}\u{1f3fd}!"),
+            ],
+            ..Document::default()
+        };
+        assert_eq!((doc.char_count(), doc.byte_count()), (5, 15));
+        doc.insert(&Position { x: 1, y: 1 }, 'x');
+        assert_eq!((doc.char_count(), doc.byte_count()), (6, 16));
+        doc.delete_range(&Position { x: 0, y: 0 }, &Position { x: 0, y: 1 });
+        assert_eq!((doc.char_count(), doc.byte_count()), (3, 10));
+    }
+
+    #[test]
+    fn test_find_wraps_around() {
+        let doc = Document {
+            rows: vec![Row::from("one"), Row::from("two"), Row::from("one")],
+            ..Document::default()
+        };
+        let pattern = Pattern::new("one", false).unwrap();
+        let forward = doc.find(&pattern, &Position { x: 1, y: 2 }, SearchDirection::Forward);
+        assert_eq!(forward, Some((Position { x: 0, y: 0 }, 3, true)));
+        let backward = doc.find(
+            &pattern,
+            &Position { x: 0, y: 0 },
+            SearchDirection::Backward,
+        );
+        assert_eq!(backward, Some((Position { x: 0, y: 2 }, 3, true)));
+        let next = doc.find(&pattern, &Position { x: 1, y: 0 }, SearchDirection::Forward);
+        assert_eq!(next, Some((Position { x: 0, y: 2 }, 3, false)));
+        let missing = Pattern::new("three", false).unwrap();
+        assert_eq!(
+            doc.find(&missing, &Position::default(), SearchDirection::Forward),
+            None
+        );
+        assert_eq!(
+            Document::default().find(&pattern, &Position::default(), SearchDirection::Backward),
+            None
+        );
+    }
+
+    #[test]
+    fn test_save_preserves_missing_eol() {
+        let path = std::env::temp_dir().join("wd40_test_noeol.txt");
+        let file_name = path.to_str().unwrap();
+        fs::write(file_name, "one\ntwo").unwrap();
+        let mut document = Document::open(file_name).unwrap();
+        assert!(!document.eol());
+        document.save_text(file_name).unwrap();
+        assert_eq!(fs::read_to_string(file_name).unwrap(), "one\ntwo");
+        document.set_eol(true);
+        document.save_text(file_name).unwrap();
+        assert_eq!(fs::read_to_string(file_name).unwrap(), "one\ntwo\n");
+        fs::remove_file(file_name).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_save_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt as _;
+        let path = std::env::temp_dir().join("wd40_test_mode.sh");
+        let file_name = path.to_str().unwrap();
+        fs::write(&path, "echo a\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+        let mut document = Document::open(file_name).unwrap();
+        document.insert(&Position { x: 6, y: 0 }, 'b');
+        document.save().unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o750);
+    }
+
+    #[test]
+    fn test_open_detects_shebang() {
+        let path = std::env::temp_dir().join("wd40_test_script");
+        let file_name = path.to_str().unwrap();
+        fs::write(file_name, "#!/usr/bin/env -S python3 -u\nprint(1)\n").unwrap();
+        let document = Document::open(file_name).unwrap();
+        assert_eq!(document.file_type(), "Python");
+        fs::write(file_name, "#![allow(unused)]\n").unwrap();
+        let document = Document::open(file_name).unwrap();
+        fs::remove_file(file_name).unwrap();
+        assert_eq!(document.file_type(), "No filetype");
+    }
+
+    #[test]
+    fn test_open_invalid_utf8() {
+        let path = std::env::temp_dir().join("wd40_test_lossy.bin");
+        let file_name = path.to_str().unwrap();
+        fs::write(file_name, b"ok\n\xff\xfeab\n").unwrap();
+        let document = Document::open(file_name).unwrap();
+        fs::remove_file(file_name).unwrap();
+        assert!(document.is_lossy());
+        assert_eq!(document.row(1).unwrap().as_str(), "\u{fffd}\u{fffd}ab");
+        fs::write(file_name, "ok\n").unwrap();
+        let document = Document::open(file_name).unwrap();
+        fs::remove_file(file_name).unwrap();
+        assert!(!document.is_lossy());
+    }
+
+    #[test]
+    fn test_add_to_number() {
+        let mut doc = Document {
+            rows: ["x = 7 + 9", "id-007", "a-1", "none"].map(Row::from).into(),
+            ..Document::default()
+        };
+        assert_eq!(doc.add_to_number(&Position { x: 1, y: 0 }, 5), Some(5));
+        assert_eq!(doc.row(0).unwrap().as_str(), "x = 12 + 9");
+        // A minus sign makes the number negative, zero padding is kept
+        assert_eq!(doc.add_to_number(&Position { x: 5, y: 1 }, 10), Some(4));
+        assert_eq!(doc.row(1).unwrap().as_str(), "id003");
+        assert_eq!(doc.add_to_number(&Position { x: 0, y: 2 }, 3), Some(1));
+        assert_eq!(doc.row(2).unwrap().as_str(), "a2");
+        assert_eq!(doc.add_to_number(&Position { x: 0, y: 3 }, 1), None);
+    }
+
+    #[test]
+    fn test_insert_str() {
+        let mut doc = Document {
+            rows: ["abcd", "e"].map(Row::from).into(),
+            ..Document::default()
+        };
+        let end = doc.insert_str(&Position { x: 2, y: 0 }, "x\nyz\n1");
+        assert_eq!(end, Position { x: 1, y: 2 });
+        let end = doc.insert_str(&Position { x: 1, y: 0 }, "-");
+        assert_eq!(end, Position { x: 2, y: 0 });
+        let end = doc.insert_str(&Position { x: 0, y: 4 }, "!\n");
+        assert_eq!(end, Position { x: 0, y: 5 });
+        assert_eq!(
+            doc.insert_str(&Position { x: 0, y: 9 }, "?"),
+            Position { x: 0, y: 9 }
+        );
+        let rows: Vec<&str> = (0..doc.len())
+            .map(|y| doc.row(y).unwrap().as_str())
+            .collect();
+        assert_eq!(rows, ["a-bx", "yz", "1cd", "e", "!", ""]);
+        assert!(doc.is_dirty());
+
+        let mut empty = Document::default();
+        let end = empty.insert_str(&Position::default(), "ab\ncd");
+        assert_eq!(end, Position { x: 2, y: 1 });
+        assert_eq!(empty.row(1).unwrap().as_str(), "cd");
+        assert_eq!(empty.len(), 2);
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let mut doc = Document {
+            rows: ["abc", "def", "ghi"].map(Row::from).into(),
+            ..Document::default()
+        };
+        doc.delete_range(&Position { x: 1, y: 0 }, &Position { x: 2, y: 0 });
+        doc.delete_range(&Position { x: 2, y: 0 }, &Position { x: 1, y: 2 });
+        doc.delete_range(&Position { x: 1, y: 0 }, &Position { x: 0, y: 0 });
+        assert_eq!(doc.len(), 1);
+        assert_eq!(doc.row(0).unwrap().as_str(), "achi");
+        assert!(doc.is_dirty());
+    }
+
+    #[test]
+    fn test_insert_rows() {
+        let mut doc = Document {
+            rows: ["a", "d"].map(Row::from).into(),
+            ..Document::default()
+        };
+        assert_eq!(doc.insert_rows(1, "b\nc\n"), 2);
+        assert_eq!(doc.insert_rows(9, "e"), 1);
+        assert_eq!(doc.insert_rows(0, ""), 0);
+        let rows: Vec<&str> = (0..doc.len())
+            .map(|y| doc.row(y).unwrap().as_str())
+            .collect();
+        assert_eq!(rows, ["a", "b", "c", "d", "e"]);
+        assert!(doc.is_dirty());
+        assert_eq!(doc.replace_rows(1..4, "x"), 1);
+        let rows: Vec<&str> = (0..doc.len())
+            .map(|y| doc.row(y).unwrap().as_str())
+            .collect();
+        assert_eq!(rows, ["a", "x", "e"]);
+    }
+
+    #[test]
+    fn test_block_edits() {
+        let mut doc = Document {
+            rows: ["abcd", "a", "abcdef"].map(Row::from).into(),
+            ..Document::default()
+        };
+        assert_eq!(doc.block_text(0..3, 1..3), ["bc", "", "bc"]);
+        doc.delete_block(0..3, 1..3);
+        let rows: Vec<&str> = (0..doc.len())
+            .map(|y| doc.row(y).unwrap().as_str())
+            .collect();
+        assert_eq!(rows, ["ad", "a", "adef"]);
+        let lines = ["x", "y", "z", "w"].map(String::from);
+        doc.insert_block(&Position { x: 3, y: 0 }, &lines);
+        let rows: Vec<&str> = (0..doc.len())
+            .map(|y| doc.row(y).unwrap().as_str())
+            .collect();
+        assert_eq!(rows, ["ad x", "a  y", "adezf", "   w"]);
+    }
+
+    #[test]
+    fn test_block_edits_by_display_column() {
+        let mut doc = Document {
+            rows: ["a你b", "abcd", "你"].map(Row::from).into(),
+            ..Document::default()
+        };
+        // Columns 1..2 cut the wide character, which is taken whole
+        assert_eq!(doc.block_text(0..3, 1..2), ["你", "b", "你"]);
+        assert_eq!(doc.block_text(0..2, 3..4), ["b", "d"]);
+        let lines = ["x", "y", "z"].map(String::from);
+        doc.insert_block(&Position { x: 3, y: 0 }, &lines);
+        let rows: Vec<&str> = (0..doc.len())
+            .map(|y| doc.row(y).unwrap().as_str())
+            .collect();
+        assert_eq!(rows, ["a你xb", "abcyd", "你 z"]);
+        doc.delete_block(0..3, 3..4);
+        let rows: Vec<&str> = (0..doc.len())
+            .map(|y| doc.row(y).unwrap().as_str())
+            .collect();
+        assert_eq!(rows, ["a你b", "abcd", "你 "]);
+    }
+
+    #[test]
+    fn test_sort_rows() {
+        let mut doc = Document {
+            rows: ["10 b", "x", "9 a", "-3", "a"].map(Row::from).into(),
+            ..Document::default()
+        };
+        let numeric = SortOptions {
+            numeric: true,
+            ..SortOptions::default()
+        };
+        assert!(doc.sort_rows(0..5, numeric));
+        let text: Vec<&str> = doc.rows.iter().map(Row::as_str).collect();
+        assert_eq!(text, vec!["a", "x", "-3", "9 a", "10 b"]);
+        doc.sort_rows(
+            1..4,
+            SortOptions {
+                reverse: true,
+                ..SortOptions::default()
+            },
+        );
+        let text: Vec<&str> = doc.rows.iter().map(Row::as_str).collect();
+        assert_eq!(text, vec!["a", "x", "9 a", "-3", "10 b"]);
+        doc.dirty = false;
+        assert!(!doc.sort_rows(0..2, SortOptions::default()));
+        assert!(!doc.is_dirty());
+    }
+
+    #[test]
+    fn test_dedup_rows() {
+        let mut doc = Document {
+            rows: ["a", "a", "", "", "b", "a", "a"].map(Row::from).into(),
+            ..Document::default()
+        };
+        assert_eq!(doc.dedup_rows(0..6), 2);
+        let text: Vec<&str> = doc.rows.iter().map(Row::as_str).collect();
+        assert_eq!(text, vec!["a", "", "b", "a", "a"]);
+        assert_eq!(doc.dedup_rows(4..5), 0);
+        assert!(doc.is_dirty());
+    }
+
+    #[test]
+    fn test_transform_range() {
+        let mut doc = Document {
+            rows: vec![Row::from("straße und"), Row::from("mehr")],
+            ..Document::default()
+        };
+        doc.transform_range(&Position { x: 3, y: 0 }, &Position { x: 2, y: 1 }, |c| {
+            c.to_uppercase().collect()
+        });
+        let text: Vec<&str> = doc.rows.iter().map(Row::as_str).collect();
+        assert_eq!(text, vec!["strASSE UND", "MEhr"]);
+    }
+
+    #[test]
+    fn test_toggle_comment() {
+        let mut doc = Document {
+            rows: vec![Row::from("    // a"), Row::from(""), Row::from("  b")],
+            ..Document::default()
+        };
+        assert!(doc.toggle_comment(0..3, "//"));
+        let text: Vec<&str> = doc.rows.iter().map(Row::as_str).collect();
+        assert_eq!(text, vec!["    // // a", "", "  // b"]);
+        assert!(doc.toggle_comment(0..3, "//"));
+        let text: Vec<&str> = doc.rows.iter().map(Row::as_str).collect();
+        assert_eq!(text, vec!["    // a", "", "  b"]);
+        doc.dirty = false;
+        assert!(!doc.toggle_comment(1..2, "//"));
+        assert!(!doc.is_dirty());
+    }
+
+    #[test]
+    fn test_indent_rows() {
+        let mut doc = Document {
+            rows: vec![
+                Row::from("a"),
+                Row::from(""),
+                Row::from("\tb"),
+                Row::from("  c"),
+            ],
+            ..Document::default()
+        };
+        assert!(doc.indent_rows(0..4, 4, false));
+        let text: Vec<&str> = doc.rows.iter().map(Row::as_str).collect();
+        assert_eq!(text, vec!["    a", "", "\t\tb", "      c"]);
+        assert!(doc.indent_rows(0..4, 4, true));
+        assert!(doc.indent_rows(0..4, 4, true));
+        let text: Vec<&str> = doc.rows.iter().map(Row::as_str).collect();
+        assert_eq!(text, vec!["a", "", "b", "c"]);
+        assert!(!doc.indent_rows(0..4, 4, true));
+    }
+
+    #[test]
+    fn test_reflow() {
+        let lines = ["  The quick brown", "fox jumps over", "the lazy dog"];
+        assert_eq!(
+            reflow(&lines, 16),
+            vec![
+                "  The quick",
+                "  brown fox",
+                "  jumps over the",
+                "  lazy dog"
+            ]
+        );
+        assert_eq!(reflow(&["unbreakable"], 4), vec!["unbreakable"]);
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let path = std::env::temp_dir().join("wd40_test_text.txt");
+        let file_name = path.to_str().unwrap();
+        fs::write(file_name, "one\r\n\r\ntwo\r\n").unwrap();
+        let document = Document::open(file_name).unwrap();
+        assert_eq!(document.text(), "one\r\n\r\ntwo");
+        let mut copy = Document::default();
+        copy.insert_str(&Position::default(), &document.text());
+        assert!(copy.rows().map(Row::as_str).eq(["one", "", "two"]));
+        fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn test_line_ending_detect() {
+        assert!(LineEnding::detect("a\r\nb\r\nc\n") == LineEnding::Dos);
+        assert!(LineEnding::detect("a\nb\r\nc\n") == LineEnding::Unix);
+        assert!(LineEnding::detect("") == LineEnding::Unix);
+    }
+
+    #[test]
+    fn test_row_edits() {
+        let mut doc = Document {
+            rows: ["a", "b", "c"].map(Row::from).into(),
+            ..Document::default()
+        };
+        doc.insert(&Position { x: 1, y: 1 }, 'x');
+        assert!(doc.take_row_edits().is_empty());
+        doc.insert(&Position { x: 1, y: 0 }, '\n');
+        doc.delete(&Position { x: 1, y: 0 });
+        doc.sort_rows(0..3, SortOptions::default());
+        assert_eq!(doc.take_row_edits(), vec![(1..1, 1), (1..2, 0)]);
+        doc.replace_rows(1..3, "y");
+        assert_eq!(doc.take_row_edits(), vec![(1..3, 1)]);
+        assert!(doc.take_row_edits().is_empty());
+    }
 }
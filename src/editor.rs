@@ -1,23 +1,203 @@
+use crate::config;
+use crate::finder;
+use crate::highlighting;
+use crate::keymap;
+use crate::options;
+use crate::positions;
+use crate::recent;
+use crate::terminal;
+use crate::theme;
+use crate::Backend;
+use crate::ColorMode;
+use crate::ColorScheme;
+use crate::CommandLine;
 use crate::Document;
+use crate::Finder;
+use crate::Keymap;
+use crate::LineEnding;
+use crate::Mappings;
+use crate::Options;
+use crate::Pattern;
+use crate::RenderOptions;
 use crate::Row;
+use crate::SortOptions;
 use crate::Terminal;
+use core::cmp::Ordering;
+use core::ops::Range;
 use core::time::Duration;
-use crossterm::event::KeyCode;
-use crossterm::style::Color;
+use crossterm::cursor::MoveToColumn;
+use crossterm::event::{KeyCode, KeyModifiers};
+use crossterm::style::{Attribute, Color, SetAttribute};
+use std::collections::HashMap;
 use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
+use unicode_width::{UnicodeWidthChar as _, UnicodeWidthStr as _};
 
-const STATUS_FG_COLOR: Color = Color::Rgb {
-    r: 63,
-    g: 63,
-    b: 63,
-};
-const STATUS_BG_COLOR: Color = Color::Rgb {
-    r: 239,
-    g: 239,
-    b: 239,
-};
-const VERSION: &str = env!("CARGO_PKG_VERSION");
+const SWAP_INTERVAL: usize = 20;
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait for a key before doing timed work.
+const TICK: Duration = Duration::from_millis(100);
+/// Longest time the screen goes without a redraw while no keys are pressed.
+const IDLE_REDRAW_INTERVAL: Duration = Duration::from_secs(1);
+/// Pause after the last edit before the swap file is written.
+const SWAP_IDLE_DELAY: Duration = Duration::from_secs(2);
+const JUMP_LIST_LIMIT: usize = 100;
+/// Shown before the file name asked for by `:w` on an unnamed buffer.
+const SAVE_AS_PROMPT: &str = "Save as: ";
+/// Shown before the query of the Ctrl-P file picker.
+const FINDER_PROMPT: &str = "Open (Up/Down pick, Enter opens, Esc cancels): ";
+/// Shown when a save had to overwrite the file instead of replacing it.
+const SAVED_IN_PLACE: &str =
+    "File saved in place - it could not be replaced safely, so a failed write could truncate it.";
+/// Most lines `:grep` collects.
+const MAX_GREP_RESULTS: usize = 1000;
+/// Files larger than this many bytes are skipped by `:grep`.
+const MAX_GREP_FILE_SIZE: u64 = 1024 * 1024;
+/// Largest count that Alt+digits can build up.
+const MAX_COUNT: usize = 99_999;
+/// Most files listed by `:set startscreen`.
+const START_SCREEN_FILES: usize = 10;
+/// Largest value accepted by `:set confirm`.
+const MAX_QUIT_TIMES: u8 = 10;
+
+/// Lines of the `:help` overlay.
+const HELP: &[&str] = &[
+    "wd40text help",
+    "",
+    "Keys",
+    "  i.:               Enter command mode (Esc cancels)",
+    "  Esc               Enter Normal mode; i, a, A, I or o go back to Insert mode",
+    "  Normal mode       h j k l move, 0 $ go to the line start / end, x deletes,",
+    "                    digits give a count, : opens a command, / searches, . repeats,",
+    "                    n / N go to the next / previous match of the last search",
+    "  Up/Down           Recall earlier commands in command mode",
+    "  Left/Right        Move within the command in command mode; Home/End too",
+    "  Tab               Complete a command or file name in command mode",
+    "  Ctrl-F            Search; Ctrl-R toggles regex, arrows step through matches",
+    "  Esc  :noh         Clear search highlights",
+    "  Shift+arrows      Select text",
+    "  Ctrl-] or Ctrl-5  Jump to the matching bracket",
+    "  Ctrl-O / Alt-I    Jump back / forward",
+    "  Ctrl-D            Duplicate the current line",
+    "  Ctrl-J            Join the current line with the next",
+    "  Ctrl-A / Ctrl-X   Add / subtract the count (default 1) to the number at the cursor",
+    "  Ctrl-/            Toggle a line comment on the line or selection",
+    "  Tab / Shift-Tab   Indent / unindent the selected lines (Tab alone inserts a tab)",
+    "  Alt-Up/Alt-Down   Move the current line",
+    "  Alt+digits        Count for the next movement or Ctrl-A/Ctrl-X, e.g. Alt-5 Down",
+    "  Alt-W             Switch split pane",
+    "  Ctrl-K  Ctrl-U    Delete to the end / start of the line",
+    "  Ctrl-W            Delete the word before the cursor",
+    "  Ctrl-B            Select a block; type to insert on each row, Delete removes it",
+    "  Ctrl-Y            Copy the selected block; Alt-P pastes it at the cursor",
+    "  Ctrl-P            Find a file under the current directory by typing part of its name",
+    "  Ctrl-N            Add a cursor at the next match, or on the next line; Esc removes them",
+    "",
+    "Commands",
+    "  :w  :w!  :wq      Save, save as, save and quit",
+    "  :w file  :w! file Save under a name; ! replaces an existing file without asking",
+    "  :q  :q!           Close the buffer (quit with the last one), discarding changes",
+    "  :qa  :qa!         Quit, quit discarding changes in every buffer",
+    "  :e!               Reload the file from disk",
+    "  :e file           Open a file in a new buffer",
+    "  :colorscheme name Use the dark or light colors, or those in ~/.wd40/colors/name",
+    "  :grep text        List matching lines of the files below; Enter on one opens it",
+    "  :bn :bp :ls :bd   Next, previous, list and close buffers (:bd! discards)",
+    "  :b N              Switch to buffer N",
+    "  :recent           Pick a recently opened file to open again",
+    "  :split :only      Split the window, close the other pane",
+    "  :N                Go to line N",
+    "  :mark a  :`a      Set mark a, jump to mark a",
+    "  :j                Join the current line with the next",
+    "  :.                Repeat the last edit",
+    "  :reflow [N]  :gq  Rewrap the paragraph to N columns",
+    "  :upper :lower :title   Change the case of the selection or word",
+    "  :sort[!] [n]      Sort the selection or file (! reverses, n is numeric)",
+    "  :uniq             Remove repeated consecutive lines",
+    "  :zz :zt :zb       Scroll the cursor line to the middle, top or bottom",
+    "  :fold             Fold the selected lines into one; Left or Right on it opens it",
+    "  :unfold  :zR      Open every fold",
+    "  :r file  :r !cmd  Insert a file or a command's output below the line",
+    "  :!cmd             Show a command's output, or filter the selection through it",
+    "  :%!cmd            Filter the whole file through a shell command",
+    "  :rec a  :@[N]a    Record keys into register a (:rec again stops), play them N times",
+    "  :filetypes        Show where file types come from and problems in filetypes.txt",
+    "  :source           Rerun ~/.wd40/config and reload file types and syntax files",
+    "  :map key action   Make a key such as ctrl-s run save, find, ... (plain keys: Normal mode)",
+    "  :map  :unmap key  List the mapped keys, remove a mapping",
+    "  :ab word text     Expand word to text (\\n for new lines) after a space or Enter",
+    "  :ab  :unab word   List the abbreviations, remove one",
+    "  :help             Show this help",
+    "",
+    "Options (:set name, :set noname or :set name=value; :set lists them, :set name? shows one)",
+    "  wrap  scrollbar  autopairs  list  showtrailing  eol  readonly  number  relativenumber",
+    "  showtabs  startscreen  hlsearch  welcome=text (\\n for new lines; nowelcome hides it)",
+    "  ff=unix|dos  textwidth=N  tabwidth=N  scrolloff=N  confirm=N  statusfg=#rrggbb  statusbg=#rrggbb",
+];
+
+/// Command names offered by Tab completion in the `:` prompt.
+const COMMANDS: &[&str] = &[
+    "ab",
+    "abbreviate",
+    "bd",
+    "bd!",
+    "bdelete",
+    "bn",
+    "bnext",
+    "bp",
+    "bprevious",
+    "buffers",
+    "checkhealth",
+    "colorscheme",
+    "e",
+    "e!",
+    "edit",
+    "filetypes",
+    "fold",
+    "gq",
+    "grep",
+    "help",
+    "j",
+    "join",
+    "lower",
+    "ls",
+    "map",
+    "mark",
+    "noh",
+    "nohlsearch",
+    "only",
+    "q",
+    "q!",
+    "qa",
+    "qa!",
+    "quit",
+    "r",
+    "read",
+    "rec",
+    "recent",
+    "reflow",
+    "set",
+    "sort",
+    "sort!",
+    "source",
+    "split",
+    "title",
+    "unab",
+    "unfold",
+    "uniq",
+    "unmap",
+    "upper",
+    "w",
+    "w!",
+    "wq",
+    "zR",
+    "zb",
+    "zt",
+    "zz",
+];
 
 #[derive(PartialEq, Copy, Clone)]
 pub enum SearchDirection {
@@ -25,12 +205,142 @@ pub enum SearchDirection {
     Backward,
 }
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, PartialEq, Eq, Debug)]
 pub struct Position {
     pub x: usize,
     pub y: usize,
 }
 
+/// State of an active Ctrl-F search prompt.
+struct Search {
+    query: String,
+    regex: bool,
+    invalid: bool,
+    /// 1-based index of the match under the cursor, and the total match count.
+    matches: (Option<usize>, usize),
+    /// Cursor and scroll offset to restore when the search is cancelled.
+    origin: Position,
+    origin_offset: Position,
+    /// The direction of the last step, when it wrapped around the document.
+    wrapped: Option<SearchDirection>,
+    /// The direction the arrows last stepped in, Forward until they are used.
+    direction: SearchDirection,
+}
+
+/// The last accepted search, which `n` and `N` look for again.
+struct LastSearch {
+    query: String,
+    regex: bool,
+    direction: SearchDirection,
+}
+
+impl Search {
+    fn prompt(&self) -> String {
+        let mode = if self.regex { " (regex)" } else { "" };
+        let status = if self.invalid {
+            " [Invalid pattern]".to_owned()
+        } else if self.query.is_empty() {
+            String::new()
+        } else if let Some(direction) = self.wrapped {
+            format!(" [{}]", wrap_message(direction))
+        } else {
+            match self.matches {
+                (_, 0) => " [no matches]".to_owned(),
+                (Some(current), total) => format!(" [match {current} of {total}]"),
+                (None, total) => format!(" [{total} matches]"),
+            }
+        };
+        format!("Search{mode}{status} (Esc cancel, arrows next/prev, Ctrl-R regex): ")
+    }
+}
+
+/// What to tell the user when a search in `direction` wraps around.
+fn wrap_message(direction: SearchDirection) -> &'static str {
+    match direction {
+        SearchDirection::Forward => "search hit BOTTOM, continuing at TOP",
+        SearchDirection::Backward => "search hit TOP, continuing at BOTTOM",
+    }
+}
+
+/// An open document along with the cursor and scroll position it was left at.
+#[derive(Default)]
+struct Buffer {
+    document: Document,
+    cursor_position: Position,
+    offset: Position,
+    marks: HashMap<char, Position>,
+}
+
+/// The inactive half of a split window.
+struct Pane {
+    buffer: usize,
+    cursor_position: Position,
+    offset: Position,
+    /// Whether this pane is drawn above the active one.
+    top: bool,
+}
+
+/// Whether typed characters insert text, act as commands or go to the `:` prompt.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    Insert,
+    /// Entered with Esc: letters move the cursor and run commands, and `i`, `a`
+    /// or `o` go back to Insert mode.
+    Normal,
+    /// The `:` prompt is open.
+    Command,
+}
+
+impl Mode {
+    /// Shown on the message bar while in this mode.
+    #[must_use]
+    pub fn indicator(self) -> &'static str {
+        match self {
+            Self::Insert => "-- INSERT --",
+            Self::Normal => "-- NORMAL --",
+            Self::Command => "-- COMMAND --",
+        }
+    }
+}
+
+/// An edit that `:.` can replay at the cursor.
+#[derive(Clone)]
+enum Action {
+    /// Text typed in one run, with `\n` for Enter.
+    Insert(String),
+    /// Graphemes removed with Delete.
+    Delete(usize),
+    /// Graphemes removed with Backspace.
+    Backspace(usize),
+    Join,
+    Duplicate,
+}
+
+/// Edit applied at every cursor while there are several.
+#[derive(Clone, Copy)]
+enum CursorEdit {
+    Insert(char),
+    Backspace,
+    Delete,
+    Newline,
+}
+
+/// Full-screen text shown over the document, such as `:help`.
+struct Overlay {
+    title: String,
+    lines: Vec<String>,
+    /// First line shown.
+    scroll: usize,
+}
+
+/// Matches found by Tab in the `:` prompt; repeated Tabs cycle through them.
+struct Completion {
+    matches: Vec<String>,
+    next: usize,
+    /// Prompt text before the word being completed.
+    base: String,
+}
+
 struct StatusMessage {
     text: String,
     time: Instant,
@@ -46,116 +356,653 @@ impl StatusMessage {
 
 pub struct Editor {
     should_quit: bool,
-    terminal: Terminal,
+    /// Whether the next turn of the main loop draws the screen.
+    redraw: bool,
+    /// Insert or Normal; the mode returned to when the `:` prompt closes.
+    mode: Mode,
+    /// Keys bound with `:map`.
+    keymap: Keymap,
+    /// Files listed by `:set startscreen`, most recent first.
+    recent_files: Vec<String>,
+    /// Words replaced with their expansion when followed by a space or Enter,
+    /// set with `:ab`.
+    abbreviations: HashMap<String, String>,
+    terminal: Box<dyn Backend>,
     cursor_position: Position,
     offset: Position,
     document: Document,
     status_message: StatusMessage,
-    command_buffer: Option<String>,
+    command_buffer: Option<CommandLine>,
     last_keys: Vec<char>,
     pending_save_command: Option<String>,
     pending_force_save: bool,
+    pending_swap_recovery: bool,
+    /// File name, and whether to quit afterwards, of a save waiting for the
+    /// answer to "Overwrite? (y/n)".
+    pending_overwrite: Option<(String, bool)>,
+    edits_since_swap: usize,
+    last_edit: Instant,
+    last_redraw: Instant,
+    options: Options,
+    matched_brackets: Option<[Position; 2]>,
+    last_disk_check: Instant,
+    disk_change_reported: bool,
+    selection_anchor: Option<Position>,
+    /// Corner of the block selection started with Ctrl-B; the cursor is the other.
+    block_anchor: Option<Position>,
+    /// Block copied with Ctrl-Y, pasted with Alt-P.
+    block_register: Vec<String>,
+    search: Option<Search>,
+    last_search: Option<LastSearch>,
+    highlighted_word: Option<Pattern>,
+    current_match: Option<(Position, usize)>,
+    /// Every open buffer; the slot at `current_buffer` is empty while its
+    /// contents live in `document`, `cursor_position` and `offset`.
+    buffers: Vec<Buffer>,
+    current_buffer: usize,
+    split: Option<Pane>,
+    /// Positions left by large cursor moves, oldest first.
+    jump_list: Vec<Position>,
+    /// Where Ctrl-O/Alt-I are in `jump_list`; equal to its length when not navigating.
+    jump_index: usize,
+    /// Named positions set with `:mark`, for the active buffer.
+    marks: HashMap<char, Position>,
+    /// Row ranges `(start, end, folded)` made with `:fold`; a folded range shows
+    /// as one line. They follow lines added or removed around them, and are
+    /// dropped by an edit across their first or last line.
+    folds: Vec<(usize, usize, bool)>,
+    /// Keypresses recorded with `:rec`, by register.
+    macros: HashMap<char, Vec<(KeyCode, KeyModifiers)>>,
+    /// Register being recorded into and the keys typed so far.
+    recording: Option<(char, Vec<(KeyCode, KeyModifiers)>)>,
+    playing_macro: bool,
+    /// How many more `:q` warnings are shown before unsaved changes are dropped.
+    quit_times: u8,
+    /// Executed `:` commands, oldest first.
+    command_history: Vec<String>,
+    /// Entry of `command_history` shown in the prompt while browsing with Up/Down,
+    /// and the text typed before browsing started.
+    history_index: Option<usize>,
+    history_draft: String,
+    completion: Option<Completion>,
+    /// Repeat count typed with Alt+digits for the next movement.
+    pending_count: Option<usize>,
+    overlay: Option<Overlay>,
+    /// File picker opened with Ctrl-P, drawn in `overlay`.
+    finder: Option<Finder>,
+    /// Cursors besides `cursor_position`, added with Ctrl-N.
+    extra_cursors: Vec<Position>,
+    /// Selection saved while "i.:" is typed, and handed to the command it opens.
+    pending_selection: Option<(Position, Position)>,
+    command_selection: Option<(Position, Position)>,
+    last_action: Option<Action>,
+    /// `last_action` from before the `i` that may start "i.:", given back when the
+    /// `:` arrives so that `:.` repeats the edit made before it.
+    action_before_command: Option<Action>,
+    /// Keypress that last extended `last_action`, so typing runs merge into one.
+    last_action_key: usize,
+    keypress_count: usize,
+    /// Warning to print once the screen is restored, such as after `:wq` saved in place.
+    exit_message: Option<String>,
+    /// Where the config file and color schemes are read from, and the recent
+    /// files and cursor positions kept: `~/.wd40` unless `--config-dir=` names
+    /// another directory.
+    config_dir: Option<PathBuf>,
 }
 
 impl Editor {
     pub fn run(&mut self) {
-        loop {
+        while self.step() {}
+    }
+
+    /// Runs one turn of the main loop: draws the screen when due, then handles
+    /// the next key from the backend, or does timed work when none comes.
+    /// Returns false once the editor has quit.
+    pub fn step(&mut self) -> bool {
+        if self.redraw {
             if let Err(error) = self.refresh_screen() {
                 die(error);
             }
-            if self.should_quit {
-                break;
-            }
-            if let Err(error) = self.process_keypress() {
+            self.last_redraw = Instant::now();
+        }
+        if self.should_quit {
+            self.document.remove_swap();
+            self.store_positions();
+            return false;
+        }
+        self.redraw = match self.poll_keypress() {
+            Ok(true) => true,
+            Ok(false) => self.tick(),
+            Err(error) => {
                 die(error);
+                false
             }
-        }
+        };
+        true
     }
 
+    /// Starts on the terminal with the program's command-line arguments.
+    #[expect(clippy::should_implement_trait)]
     pub fn default() -> Self {
-        let args: Vec<String> = env::args().collect();
+        terminal::install_panic_hook();
+        let args: Vec<String> = env::args().skip(1).collect();
+        let terminal = Terminal::default().expect("Failed to initialize terminal");
+        Self::with_backend(Box::new(terminal), &args)
+    }
+
+    /// Starts on `backend`, with `args` read like the command-line arguments
+    /// after the program name.
+    #[must_use]
+    pub fn with_backend(backend: Box<dyn Backend>, args: &[String]) -> Self {
         let mut initial_status =
             String::from("Good Luck, have fun! Type i.: to enter command mode.");
-        let document = if let Some(file_name) = args.get(1) {
-            let doc = Document::open(file_name);
-            if let Ok(doc) = doc {
-                doc
-            } else {
-                initial_status = format!("ERR: Could not open file: {file_name}");
-                Document::default()
+        let mut pending_swap_recovery = false;
+        let read_only = args.iter().any(|arg| arg == "--readonly");
+        let color_mode = ColorMode::detect(args.iter().any(|arg| arg == "--no-color"));
+        let line_number = |arg: &str| arg.strip_prefix('+')?.parse::<usize>().ok();
+        let line_arg = args.iter().find_map(|arg| line_number(arg));
+        let file_arg = args
+            .iter()
+            .filter(|arg| !arg.starts_with("--"))
+            .find(|arg| line_number(arg).is_none());
+        let (file_arg, mut start_position) = match file_arg {
+            Some(arg) => {
+                let (file_name, position) = parse_file_location(arg);
+                (Some(file_name), position)
+            }
+            None => (None, None),
+        };
+        if let Some(line) = line_arg {
+            start_position = Some(Position {
+                x: 0,
+                y: line.saturating_sub(1),
+            });
+        }
+        let config_dir = config::config_dir(args);
+        if start_position.is_none() {
+            start_position = file_arg
+                .zip(config_dir.as_deref())
+                .and_then(|(file_name, dir)| positions::load(dir, file_name));
+        }
+        let document = if let Some(file_name) = file_arg {
+            match Document::open(file_name) {
+                Ok(doc) => {
+                    if let Some(dir) = &config_dir {
+                        recent::add(dir, file_name);
+                    }
+                    if doc.is_lossy() {
+                        initial_status =
+                            format!("{file_name} is not valid UTF-8 - opened read-only.");
+                    }
+                    if doc.has_newer_swap() {
+                        pending_swap_recovery = true;
+                        initial_status =
+                            format!("Swap file found for {file_name}. Recover it? (y/n)");
+                    }
+                    doc
+                }
+                Err(error) if error.kind() == ErrorKind::InvalidData => {
+                    initial_status = format!("ERR: {error}: {file_name}");
+                    Document::default()
+                }
+                Err(error) => {
+                    initial_status = format!(
+                        "ERR: Could not open file: {file_name}: {}",
+                        os_error_message(&error)
+                    );
+                    Document::default()
+                }
             }
         } else {
             Document::default()
         };
 
-        Self {
+        let mut editor = Self {
             should_quit: false,
-            terminal: Terminal::default().expect("Failed to initialize terminal"),
+            redraw: true,
+            mode: Mode::Insert,
+            keymap: Keymap::default(),
+            abbreviations: HashMap::new(),
+            recent_files: Vec::new(),
+            terminal: backend,
             document,
             cursor_position: Position::default(),
             offset: Position::default(),
-            status_message: StatusMessage::from(initial_status),
+            status_message: StatusMessage::from(String::new()),
             command_buffer: None,
             last_keys: Vec::new(),
             pending_save_command: None,
             pending_force_save: false,
+            pending_swap_recovery,
+            pending_overwrite: None,
+            edits_since_swap: 0,
+            last_edit: Instant::now(),
+            last_redraw: Instant::now(),
+            options: Options {
+                read_only,
+                render: RenderOptions {
+                    color_mode,
+                    ..RenderOptions::default()
+                },
+                ..Options::default()
+            },
+            matched_brackets: None,
+            last_disk_check: Instant::now(),
+            disk_change_reported: false,
+            selection_anchor: None,
+            block_anchor: None,
+            block_register: Vec::new(),
+            search: None,
+            last_search: None,
+            highlighted_word: None,
+            current_match: None,
+            buffers: vec![Buffer::default()],
+            current_buffer: 0,
+            split: None,
+            jump_list: Vec::new(),
+            jump_index: 0,
+            marks: HashMap::new(),
+            folds: Vec::new(),
+            macros: HashMap::new(),
+            recording: None,
+            playing_macro: false,
+            quit_times: Options::default().confirm,
+            command_history: Vec::new(),
+            history_index: None,
+            history_draft: String::new(),
+            completion: None,
+            pending_count: None,
+            overlay: None,
+            finder: None,
+            extra_cursors: Vec::new(),
+            pending_selection: None,
+            command_selection: None,
+            last_action: None,
+            action_before_command: None,
+            last_action_key: 0,
+            keypress_count: 0,
+            exit_message: None,
+            config_dir,
+        };
+        editor.recent_files = editor
+            .config_dir
+            .as_deref()
+            .map(recent::files)
+            .unwrap_or_default();
+        editor.recent_files.truncate(START_SCREEN_FILES);
+        let config_errors = editor.run_config();
+        if let Some(position) = start_position {
+            editor.cursor_position = position;
+            editor.clamp_cursor();
+            editor.reposition_view("zz");
+        }
+        if !config_errors.is_empty() && !pending_swap_recovery {
+            initial_status = format!("~/.wd40/config: {}", config_errors.join("; "));
         }
+        editor.status_message = StatusMessage::from(initial_status);
+        editor
     }
 
-    fn refresh_screen(&mut self) -> Result<(), std::io::Error> {
-        Terminal::cursor_hide();
-        Terminal::cursor_position(&Position::default());
+    /// Runs the commands in the `config` file of `config_dir`, returning what was wrong with its
+    /// `map` lines.
+    fn run_config(&mut self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let commands = self
+            .config_dir
+            .as_deref()
+            .map(config::config_commands)
+            .unwrap_or_default();
+        for command in commands {
+            if let Some(binding) = command.strip_prefix("map ") {
+                if let Err(error) = self.map_key(binding) {
+                    errors.push(error);
+                }
+            } else if command != "source" {
+                // A `source` in the config would run it forever
+                self.execute_command(&command);
+            }
+        }
+        errors
+    }
+
+    /// Whether the editor is done, after `:q` or the like.
+    #[must_use]
+    pub fn should_quit(&self) -> bool {
+        self.should_quit
+    }
+
+    /// A warning that the message bar can no longer show once the editor quits,
+    /// for printing after the terminal is restored.
+    #[must_use]
+    pub fn exit_message(&self) -> Option<&str> {
+        self.exit_message.as_deref()
+    }
+
+    /// The current mode, which is Command while the `:` prompt is open.
+    #[must_use]
+    pub fn mode(&self) -> Mode {
+        if self.command_buffer.is_some() {
+            Mode::Command
+        } else {
+            self.mode
+        }
+    }
+
+    /// The document being edited.
+    #[must_use]
+    pub fn document(&self) -> &Document {
+        &self.document
+    }
+
+    /// Draws the whole frame on the backend.
+    pub fn refresh_screen(&mut self) -> Result<(), std::io::Error> {
+        self.update_layout();
+        self.terminal.cursor_hide()?;
+        self.terminal.cursor_position(&Position::default())?;
         if self.should_quit {
-            Terminal::clear_screen();
-            println!("Come Again!.\r");
-        } else {
-            self.document.highlight(
-                &None,
-                Some(
-                    self.offset
-                        .y
-                        .saturating_add(self.terminal.size().height as usize),
-                ),
-            );
-            self.draw_rows();
-            self.draw_status_bar();
-            self.draw_message_bar();
-            if let Some(ref buffer) = self.command_buffer {
-                Terminal::cursor_position(&Position {
-                    x: buffer.len() + 1,
-                    y: self.terminal.size().height as usize + 1,
-                });
+            self.terminal.clear_screen()?;
+            self.terminal.print_line("Come Again!.")?;
+        } else {
+            self.check_disk_changes();
+            let mut visible = self.offset.y..self.offset.y.saturating_add(self.text_height());
+            if let Some(pane) = &self.split {
+                let pane_visible =
+                    pane.offset.y..pane.offset.y.saturating_add(self.pane_height(pane.top));
+                if pane.buffer == self.current_buffer {
+                    visible =
+                        visible.start.min(pane_visible.start)..visible.end.max(pane_visible.end);
+                } else if let Some(buffer) = self.buffers.get_mut(pane.buffer) {
+                    buffer.document.highlight(&None, pane_visible);
+                }
+            }
+            self.document.highlight(&self.highlighted_word, visible);
+            if let Some((position, len)) = &self.current_match {
+                for x in position.x..position.x.saturating_add(*len) {
+                    let at = Position { x, y: position.y };
+                    self.document
+                        .set_highlight(&at, highlighting::Type::CurrentMatch);
+                }
+            }
+            if let Some(pair) = &self.matched_brackets {
+                for position in pair {
+                    self.document
+                        .set_highlight(position, highlighting::Type::MatchingBracket);
+                }
+            }
+            if self.tab_bar_rows() > 0 {
+                self.draw_tab_bar()?;
+            }
+            if let Some(overlay) = &self.overlay {
+                self.draw_overlay(overlay)?;
             } else {
-                Terminal::cursor_position(&Position {
-                    x: self.cursor_position.x.saturating_sub(self.offset.x),
-                    y: self.cursor_position.y.saturating_sub(self.offset.y),
-                });
+                self.draw_rows()?;
+            }
+            self.draw_status_bar()?;
+            self.draw_message_bar()?;
+            if self.overlay.is_none() {
+                self.draw_extra_cursors()?;
+            }
+            if let Some(finder) = &self.finder {
+                self.terminal.cursor_position(&Position {
+                    x: FINDER_PROMPT.width().saturating_add(finder.query.width()),
+                    y: self.message_bar_row(),
+                })?;
+            } else if self.overlay.is_some() {
+                self.terminal.cursor_position(&Position::default())?;
+            } else if let Some(search) = &self.search {
+                self.terminal.cursor_position(&Position {
+                    x: search.prompt().width().saturating_add(search.query.width()),
+                    y: self.message_bar_row(),
+                })?;
+            } else if let Some(buffer) = &self.command_buffer {
+                self.terminal.cursor_position(&Position {
+                    x: self
+                        .command_prompt()
+                        .width()
+                        .saturating_add(buffer.before_cursor().width()),
+                    y: self.message_bar_row(),
+                })?;
+            } else {
+                let mut position = self.cursor_screen_position();
+                position.x = position.x.saturating_add(self.gutter_width(&self.document));
+                position.y = position.y.saturating_add(self.text_top());
+                self.terminal.cursor_position(&position)?;
             }
         }
-        Terminal::cursor_show();
-        Terminal::flush()
+        self.terminal.cursor_show()?;
+        self.terminal.flush()
     }
     fn execute_command(&mut self, command: &str) {
+        // Commands act at the main cursor and may move rows under the others
+        self.extra_cursors.clear();
+        if !matches!(command.trim(), "q" | "quit" | "qa" | "qall") {
+            self.quit_times = self.options.confirm;
+        }
         match command.trim() {
+            "filetypes" | "checkhealth" => self.show_file_types(),
+            "recent" => self.show_recent_files(),
+            "noh" | "nohlsearch" => {
+                if self.highlighted_word.is_some() {
+                    self.set_highlighted_word(None);
+                }
+            }
+            "source" => {
+                let errors = self.run_config();
+                Mappings::reload();
+                self.detect_file_types();
+                self.status_message = StatusMessage::from(if errors.is_empty() {
+                    "Reloaded the config, file types and syntax.".to_owned()
+                } else {
+                    format!("~/.wd40/config: {}", errors.join("; "))
+                });
+            }
+            "map" => {
+                let lines = self.keymap.lines();
+                self.overlay = Some(Overlay {
+                    title: "Mapped keys".to_owned(),
+                    lines: if lines.is_empty() {
+                        vec!["No keys are mapped.".to_owned()]
+                    } else {
+                        lines
+                    },
+                    scroll: 0,
+                });
+            }
+            cmd if cmd.starts_with("map ") => {
+                let binding = cmd.trim_start_matches("map ");
+                self.status_message = StatusMessage::from(match self.map_key(binding) {
+                    Ok(()) => format!("Mapped {}", binding.trim()),
+                    Err(error) => error,
+                });
+            }
+            "ab" | "abbreviate" => {
+                let mut lines: Vec<String> = self
+                    .abbreviations
+                    .iter()
+                    .map(|(word, expansion)| format!("{word:<16} {}", expansion.escape_default()))
+                    .collect();
+                lines.sort();
+                if lines.is_empty() {
+                    lines.push("No abbreviations are set.".to_owned());
+                }
+                self.overlay = Some(Overlay {
+                    title: "Abbreviations".to_owned(),
+                    lines,
+                    scroll: 0,
+                });
+            }
+            cmd if cmd.starts_with("ab ") || cmd.starts_with("abbreviate ") => {
+                let args = cmd.split_once(' ').map_or("", |(_, args)| args.trim());
+                self.add_abbreviation(args);
+            }
+            cmd if cmd.starts_with("unab ") => {
+                let word = cmd.trim_start_matches("unab ").trim();
+                self.status_message =
+                    StatusMessage::from(if self.abbreviations.remove(word).is_some() {
+                        format!("Removed the abbreviation {word}")
+                    } else {
+                        format!("No abbreviation {word}")
+                    });
+            }
+            cmd if cmd.starts_with("unmap ") => {
+                let key = cmd.trim_start_matches("unmap ").trim();
+                self.status_message = StatusMessage::from(if self.keymap.unbind(key) {
+                    format!("Unmapped {key}")
+                } else {
+                    format!("No mapping for {key}")
+                });
+            }
             "help" | "h" => {
-                self.status_message = StatusMessage::from(
-                    "Commands: :w=save | :w!=force save | :q=quit | :wq=save&quit | :help"
-                        .to_owned(),
-                );
+                self.overlay = Some(Overlay {
+                    title: "Help".to_owned(),
+                    lines: HELP.iter().map(|&line| line.to_owned()).collect(),
+                    scroll: 0,
+                });
             }
-            "w" | "save" => {
-                if self.document.file_name.is_some() {
-                    if self.document.save().is_ok() {
+            "set" => {
+                self.overlay = Some(Overlay {
+                    title: "Options".to_owned(),
+                    lines: self.option_values(),
+                    scroll: 0,
+                });
+            }
+            "j" | "join" => self.join_lines(),
+            "." => self.repeat_last_action(),
+            "upper" | "lower" | "title" => self.change_case(command.trim()),
+            "uniq" => self.uniq(),
+            "zz" | "zt" | "zb" => self.reposition_view(command.trim()),
+            "fold" => self.fold(),
+            "unfold" | "zR" => self.unfold_all(),
+            cmd if cmd == "rec" || cmd.starts_with("rec ") => {
+                self.toggle_recording(cmd.trim_start_matches("rec").trim());
+            }
+            cmd if cmd.starts_with('@') => self.play_macro(cmd.trim_start_matches('@')),
+            cmd if cmd.starts_with("sort!") => self.sort(cmd.trim_start_matches("sort!"), true),
+            cmd if cmd.starts_with("sort") => self.sort(cmd.trim_start_matches("sort"), false),
+            "gq" | "reflow" => self.reflow(self.options.textwidth),
+            cmd if cmd.starts_with("reflow ") => {
+                match cmd.trim_start_matches("reflow ").trim().parse::<usize>() {
+                    Ok(width) if width > 0 => self.reflow(width),
+                    _ => {
                         self.status_message =
-                            StatusMessage::from("File saved successfully.".to_owned());
-                    } else {
-                        self.status_message = StatusMessage::from("Error writing file!".to_owned());
+                            StatusMessage::from("Usage: :reflow [width]".to_owned());
+                    }
+                }
+            }
+            "bn" | "bnext" => {
+                let next = self.current_buffer.saturating_add(1) % self.buffers.len();
+                self.switch_buffer(next);
+            }
+            "bp" | "bprevious" => {
+                let previous = self
+                    .current_buffer
+                    .checked_sub(1)
+                    .unwrap_or_else(|| self.buffers.len().saturating_sub(1));
+                self.switch_buffer(previous);
+            }
+            "ls" | "buffers" => self.list_buffers(),
+            cmd if cmd
+                .strip_prefix('b')
+                .is_some_and(|number| number.trim().parse::<usize>().is_ok()) =>
+            {
+                let number = cmd
+                    .trim_start_matches('b')
+                    .trim()
+                    .parse::<usize>()
+                    .unwrap_or(0);
+                if (1..=self.buffers.len()).contains(&number) {
+                    self.switch_buffer(number.saturating_sub(1));
+                } else {
+                    self.status_message = StatusMessage::from(format!("No buffer {number}."));
+                }
+            }
+            "sp" | "split" => {
+                if self.split.is_some() {
+                    self.status_message =
+                        StatusMessage::from("Window is already split.".to_owned());
+                } else {
+                    self.split = Some(Pane {
+                        buffer: self.current_buffer,
+                        cursor_position: self.cursor_position.clone(),
+                        offset: self.offset.clone(),
+                        top: false,
+                    });
+                    self.scroll();
+                }
+            }
+            "on" | "only" => self.split = None,
+            "bd" | "bdelete" => self.close_buffer(false),
+            "bd!" | "bdelete!" => self.close_buffer(true),
+            cmd if cmd.starts_with("%!") => self.filter(
+                0..self.document.len(),
+                cmd.strip_prefix("%!").unwrap_or_default(),
+            ),
+            cmd if cmd.starts_with('!') => {
+                let command = cmd.strip_prefix('!').unwrap_or_default();
+                if let Some(selection) = &self.command_selection {
+                    self.filter(self.selected_rows(selection), command);
+                } else {
+                    self.show_command_output(command.trim());
+                }
+            }
+            cmd if cmd == "r" || cmd.starts_with("r ") || cmd.starts_with("read ") => {
+                let (_, source) = cmd.split_once(' ').unwrap_or_default();
+                self.read_below(source.trim());
+            }
+            cmd if cmd.starts_with("w ") || cmd.starts_with("w! ") => {
+                let (force, file_name) = cmd.split_once(' ').unwrap_or_default();
+                let file_name = file_name.trim();
+                if file_name.is_empty() {
+                    self.status_message = StatusMessage::from("No filename provided.".to_owned());
+                } else {
+                    self.save_as(file_name, false, force == "w!");
+                }
+            }
+            cmd if cmd.starts_with("e ") || cmd.starts_with("edit ") => {
+                let (_, file_name) = cmd.split_once(' ').unwrap_or_default();
+                self.open_buffer(file_name.trim());
+            }
+            "e!" | "edit!" => {
+                if self.document.file_name.is_none() {
+                    self.status_message = StatusMessage::from("No file name to reload.".to_owned());
+                } else {
+                    match self.document.reload() {
+                        Ok(()) => {
+                            self.folds.clear();
+                            self.clamp_cursor();
+                            self.disk_change_reported = false;
+                            self.status_message = StatusMessage::from("File reloaded.".to_owned());
+                        }
+                        Err(error) => {
+                            self.status_message = StatusMessage::from(format!(
+                                "Error reading file: {}",
+                                os_error_message(&error)
+                            ));
+                        }
                     }
+                }
+            }
+            "w" | "save" => {
+                if self.is_read_only() {
+                    self.status_message = StatusMessage::from(
+                        "File is read-only - use :w! to save under a name.".to_owned(),
+                    );
+                } else if self.document.changed_on_disk() {
+                    self.status_message = StatusMessage::from(
+                        "File changed on disk - :e! to reload, or :w! to save under a name."
+                            .to_owned(),
+                    );
+                } else if self.document.file_name.is_some() {
+                    self.status_message = StatusMessage::from(match self.document.save() {
+                        Ok(true) => "File saved successfully.".to_owned(),
+                        Ok(false) => SAVED_IN_PLACE.to_owned(),
+                        Err(error) => write_error_message(&error),
+                    });
                 } else {
                     // Prompt for filename
                     self.pending_save_command = Some("w".to_owned());
-                    self.command_buffer = Some(String::new());
-                    self.status_message = StatusMessage::from("Save as: ".to_owned());
+                    self.command_buffer = Some(CommandLine::default());
+                    self.status_message = StatusMessage::from(SAVE_AS_PROMPT.to_owned());
                 }
             }
 
@@ -165,77 +1012,416 @@ impl Editor {
 
                 self.pending_force_save = true;
 
-                self.command_buffer = Some(String::new());
+                self.command_buffer = Some(CommandLine::default());
 
-                self.status_message = StatusMessage::from("Save as: ".to_owned());
+                self.status_message = StatusMessage::from(SAVE_AS_PROMPT.to_owned());
             }
 
-            "q!" | "quit!" => {
-                // Force quit: discard unsaved changes and exit immediately
-                self.should_quit = true;
-            }
+            "q!" | "quit!" => self.quit_buffer(true),
+            // Other buffers stay open, so only this one's changes are at stake
+            "q" | "quit" if self.buffers.len() > 1 => self.quit_buffer(false),
             "q" | "quit" => {
-                if self.document.is_dirty() {
-                    self.status_message = StatusMessage::from(
-                        "File has unsaved changes! Use :wq to save and quit, or :q! to quit without saving.".to_owned(),
-                    );
+                let times = self.quit_times;
+                self.quit_times = self.quit_times.saturating_sub(1);
+                let plural = if times == 1 { "" } else { "s" };
+                if times == 0 || !self.document.is_dirty() {
+                    self.should_quit = true;
                 } else {
+                    self.status_message = StatusMessage::from(format!(
+                        "File has unsaved changes! Use :wq to save and quit, or :q! (or :q {times} more time{plural}) to quit without saving."
+                    ));
+                }
+            }
+            "qa!" | "qall!" => self.should_quit = true,
+            "qa" | "qall" => {
+                let times = self.quit_times;
+                self.quit_times = self.quit_times.saturating_sub(1);
+                let plural = if times == 1 { "" } else { "s" };
+                let dirty = self
+                    .buffers
+                    .iter()
+                    .filter(|buffer| buffer.document.is_dirty())
+                    .count()
+                    .saturating_add(usize::from(self.document.is_dirty()));
+                if times == 0 || dirty == 0 {
                     self.should_quit = true;
+                } else {
+                    self.status_message = StatusMessage::from(format!(
+                        "{dirty} buffer{} unsaved changes! Use :ls to find {}, or :qa! (or :qa {times} more time{plural}) to quit without saving.",
+                        if dirty == 1 { " has" } else { "s have" },
+                        if dirty == 1 { "it" } else { "them" }
+                    ));
                 }
             }
             "wq" => {
-                if self.document.file_name.is_some() {
-                    if self.document.save().is_ok() {
-                        self.should_quit = true;
-                    } else {
-                        self.status_message = StatusMessage::from("Error writing file!".to_owned());
+                if self.is_read_only() {
+                    self.status_message = StatusMessage::from(
+                        "File is read-only - use :w! to save under a name.".to_owned(),
+                    );
+                } else if self.document.changed_on_disk() {
+                    self.status_message = StatusMessage::from(
+                        "File changed on disk - :e! to reload, or :w! to save under a name."
+                            .to_owned(),
+                    );
+                } else if self.document.file_name.is_some() {
+                    match self.document.save() {
+                        Ok(replaced) => {
+                            if !replaced {
+                                self.status_message =
+                                    StatusMessage::from(SAVED_IN_PLACE.to_owned());
+                            }
+                            self.quit_after_save(replaced);
+                        }
+                        Err(error) => {
+                            self.status_message = StatusMessage::from(write_error_message(&error));
+                        }
                     }
                 } else {
                     // Prompt for filename then save and quit
                     self.pending_save_command = Some("wq".to_owned());
-                    self.command_buffer = Some(String::new());
-                    self.status_message = StatusMessage::from("Save as: ".to_owned());
+                    self.command_buffer = Some(CommandLine::default());
+                    self.status_message = StatusMessage::from(SAVE_AS_PROMPT.to_owned());
                 }
             }
+            cmd if !cmd.is_empty() && cmd.bytes().all(|byte| byte.is_ascii_digit()) => {
+                let line = cmd.parse::<usize>().unwrap_or(usize::MAX);
+                self.push_jump(self.cursor_position.clone());
+                self.cursor_position = Position {
+                    x: 0,
+                    y: line.saturating_sub(1),
+                };
+                self.clamp_cursor();
+            }
+            cmd if cmd.starts_with("colorscheme ") || cmd.starts_with("colo ") => {
+                let (_, name) = cmd.split_once(' ').unwrap_or_default();
+                self.set_color_scheme(name.trim());
+            }
+            cmd if cmd.starts_with("grep ") => self.grep(cmd.trim_start_matches("grep ").trim()),
+            cmd if cmd.starts_with("mark ") || cmd.starts_with("k ") => {
+                let (_, name) = cmd.split_once(' ').unwrap_or_default();
+                self.set_mark(name.trim());
+            }
+            cmd if cmd.starts_with('`') || cmd.starts_with('\'') => {
+                self.jump_to_mark(cmd.get(1..).unwrap_or_default());
+            }
+            cmd if cmd.starts_with("set ") => {
+                self.set_option(cmd.trim_start_matches("set ").trim());
+            }
             _ => {
                 self.status_message = StatusMessage::from(format!("Unknown command: :{}", command));
             }
         }
     }
 
-    fn process_keypress(&mut self) -> Result<(), std::io::Error> {
-        let pressed_key = Terminal::read_key()?;
+    fn set_color_scheme(&mut self, name: &str) {
+        match ColorScheme::named(name, self.config_dir.as_deref()) {
+            Ok(colors) => {
+                self.options.render.colors = colors;
+                self.options.colorscheme = name.to_owned();
+            }
+            Err(message) => self.status_message = StatusMessage::from(message),
+        }
+    }
+    /// Detects the file type of every open buffer again.
+    fn detect_file_types(&mut self) {
+        self.document.detect_file_type();
+        for buffer in &mut self.buffers {
+            buffer.document.detect_file_type();
+        }
+    }
+    /// Reads the file type mappings again and shows the file they came from and
+    /// the lines of it that were skipped.
+    fn show_file_types(&mut self) {
+        let mappings = Mappings::reload();
+        self.detect_file_types();
+        let mut lines = vec![
+            format!("This file: {}", self.document.file_type()),
+            match &mappings.path {
+                Some(path) => format!(
+                    "{} mappings from {path}{}",
+                    mappings.rule_count(),
+                    if mappings.from_variable {
+                        " (set by WD40_FILETYPES)"
+                    } else {
+                        ""
+                    }
+                ),
+                None => "No filetypes.txt found; WD40_FILETYPES can name one.".to_owned(),
+            },
+        ];
+        if mappings.warnings.is_empty() {
+            lines.push("No problems found.".to_owned());
+        } else {
+            lines.push(format!("{} lines skipped:", mappings.warnings.len()));
+            lines.extend(mappings.warnings.iter().map(|warning| {
+                format!(
+                    "  {}:{}: {}: {}",
+                    warning.file, warning.line, warning.reason, warning.text
+                )
+            }));
+        }
+        self.overlay = Some(Overlay {
+            title: "File types".to_owned(),
+            lines,
+            scroll: 0,
+        });
+    }
+    /// Every option and its current value, including those of the document.
+    fn option_values(&self) -> Vec<String> {
+        let mut values = self.options.values();
+        values.push(if self.document.eol() { "eol" } else { "noeol" }.to_owned());
+        values.push(format!("ff={}", self.document.line_ending().name()));
+        values.sort();
+        values
+    }
+    /// Shows the value of the option `name` in the status bar.
+    fn query_option(&mut self, name: &str) {
+        let name = match name {
+            "tw" => "textwidth",
+            "so" => "scrolloff",
+            "ts" => "tabwidth",
+            "nu" => "number",
+            "rnu" => "relativenumber",
+            "ro" => "readonly",
+            name => name,
+        };
+        let value = self.option_values().into_iter().find(|value| {
+            let (option, _) = value.split_once('=').unwrap_or((value, ""));
+            option == name || option.strip_prefix("no") == Some(name)
+        });
+        self.status_message =
+            StatusMessage::from(value.unwrap_or_else(|| format!("Unknown option: {name}")));
+    }
+    fn set_option(&mut self, option: &str) {
+        if let Some(name) = option.strip_suffix('?') {
+            self.query_option(name.trim());
+            return;
+        }
+        let (name, value) = option.split_once('=').unwrap_or((option, ""));
+        match name.trim() {
+            "statusbg" | "statusfg" => {
+                if let Some(color) = theme::parse_hex_color(value) {
+                    if name.trim() == "statusbg" {
+                        self.options.theme.status_bg = color;
+                    } else {
+                        self.options.theme.status_fg = color;
+                    }
+                } else {
+                    self.status_message =
+                        StatusMessage::from(format!("Invalid color: {value} (expected #rrggbb)"));
+                }
+            }
+            "wrap" => {
+                self.options.wrap = true;
+                self.offset.x = 0;
+            }
+            "nowrap" => self.options.wrap = false,
+            "confirm" => match value.trim().parse::<u8>() {
+                Ok(times) if times <= MAX_QUIT_TIMES => {
+                    self.options.confirm = times;
+                    self.quit_times = times;
+                }
+                _ => {
+                    self.status_message = StatusMessage::from(format!(
+                        "Invalid confirm: {value} (expected 0 to {MAX_QUIT_TIMES})"
+                    ));
+                }
+            },
+            "textwidth" | "tw" => match value.trim().parse::<usize>() {
+                Ok(width) if width > 0 => self.options.textwidth = width,
+                _ => {
+                    self.status_message =
+                        StatusMessage::from(format!("Invalid textwidth: {value}"));
+                }
+            },
+            "tabwidth" | "ts" => match value.trim().parse::<usize>() {
+                Ok(width) if width > 0 => self.options.tab_width = width,
+                _ => {
+                    self.status_message = StatusMessage::from(format!("Invalid tabwidth: {value}"));
+                }
+            },
+            "scrolloff" | "so" => match value.trim().parse::<usize>() {
+                Ok(lines) => self.options.scroll_off = lines,
+                Err(_) => {
+                    self.status_message =
+                        StatusMessage::from(format!("Invalid scrolloff: {value}"));
+                }
+            },
+            "hlsearch" | "hls" => self.options.hlsearch = true,
+            "nohlsearch" | "nohls" => {
+                self.options.hlsearch = false;
+                if self.search.is_none() && self.highlighted_word.is_some() {
+                    self.set_highlighted_word(None);
+                }
+            }
+            "autopairs" => self.options.autopairs = true,
+            "noautopairs" => self.options.autopairs = false,
+            "list" => self.options.render.list = true,
+            "nolist" => self.options.render.list = false,
+            "showtrailing" => self.options.render.show_trailing = true,
+            "noshowtrailing" => self.options.render.show_trailing = false,
+            "number" | "nu" => self.options.number = true,
+            "nonumber" | "nonu" => self.options.number = false,
+            "relativenumber" | "rnu" => self.options.relative_number = true,
+            "norelativenumber" | "nornu" => self.options.relative_number = false,
+            "welcome" if option.contains('=') => {
+                self.options.welcome_message = Some(value.replace("\\n", "\n"));
+            }
+            "welcome" => self.options.welcome_message = Some(options::default_welcome_message()),
+            "nowelcome" => self.options.welcome_message = None,
+            "startscreen" => self.options.start_screen = true,
+            "nostartscreen" => self.options.start_screen = false,
+            "showtabs" => self.options.showtabs = true,
+            "noshowtabs" => self.options.showtabs = false,
+            "scrollbar" => self.options.scrollbar = true,
+            "noscrollbar" => self.options.scrollbar = false,
+            "eol" => self.document.set_eol(true),
+            "noeol" => self.document.set_eol(false),
+            "readonly" | "ro" => self.options.read_only = true,
+            "noreadonly" | "noro" => self.options.read_only = false,
+            "ff" => {
+                if let Some(line_ending) = LineEnding::from_name(value.trim()) {
+                    self.document.set_line_ending(line_ending);
+                } else {
+                    self.status_message = StatusMessage::from(format!(
+                        "Invalid file format: {value} (expected unix or dos)"
+                    ));
+                }
+            }
+            _ => {
+                self.status_message = StatusMessage::from(format!("Unknown option: {name}"));
+            }
+        }
+    }
+
+    /// Waits up to `TICK` for a key and handles it. Returns false when none came.
+    fn poll_keypress(&mut self) -> Result<bool, std::io::Error> {
+        let Some((pressed_key, modifiers)) = self.terminal.poll_key(TICK)? else {
+            return Ok(false);
+        };
+        self.process_keypress(pressed_key, modifiers);
+        Ok(true)
+    }
+    /// Handles a key as if it were typed, recording it into a macro if one is
+    /// being recorded.
+    pub fn process_keypress(&mut self, pressed_key: KeyCode, modifiers: KeyModifiers) {
+        if let Some((_, keys)) = &mut self.recording {
+            keys.push((pressed_key, modifiers));
+        }
+        self.handle_key(pressed_key, modifiers);
+    }
+    /// Timed work done while no keys are pressed: writes the swap file once
+    /// editing pauses. Returns true when the screen is due for a redraw, which
+    /// expires the status message and checks for changes on disk.
+    pub fn tick(&mut self) -> bool {
+        if self.edits_since_swap > 0 && self.last_edit.elapsed() >= SWAP_IDLE_DELAY {
+            self.write_swap();
+        }
+        self.last_redraw.elapsed() >= IDLE_REDRAW_INTERVAL
+    }
+    /// Acts on one keypress, typed or replayed from a macro.
+    fn handle_key(&mut self, pressed_key: KeyCode, modifiers: KeyModifiers) {
+        self.keypress_count = self.keypress_count.wrapping_add(1);
+
+        // The bracket pair highlight only lasts until the next keypress
+        if let Some(pair) = self.matched_brackets.take() {
+            let first_row = pair[0].y.min(pair[1].y);
+            let last_row = pair[0].y.max(pair[1].y);
+            self.document
+                .invalidate_highlighting(first_row..last_row.saturating_add(1));
+        }
+
+        if let Some((file_name, quit)) = self.pending_overwrite.take() {
+            if pressed_key == KeyCode::Char('y') || pressed_key == KeyCode::Char('Y') {
+                self.write_as(&file_name, quit, false);
+            } else {
+                self.status_message = StatusMessage::from("Not saved.".to_owned());
+            }
+            return;
+        }
+
+        // Answer to the swap recovery prompt shown at startup
+        if self.pending_swap_recovery {
+            self.pending_swap_recovery = false;
+            if pressed_key == KeyCode::Char('y') || pressed_key == KeyCode::Char('Y') {
+                self.status_message =
+                    StatusMessage::from(match self.document.recover_from_swap() {
+                        Ok(()) => "Recovered unsaved changes from swap file.".to_owned(),
+                        Err(error) => {
+                            format!("Error reading swap file: {}", os_error_message(&error))
+                        }
+                    });
+            } else {
+                self.document.remove_swap();
+                self.status_message = StatusMessage::from("Swap file discarded.".to_owned());
+            }
+            return;
+        }
+
+        if let Some(finder) = &mut self.finder {
+            match pressed_key {
+                KeyCode::Enter => {
+                    let file = finder.selected().map(str::to_owned);
+                    self.close_finder();
+                    if let Some(file) = file {
+                        self.open_buffer(&file);
+                    }
+                    return;
+                }
+                KeyCode::Up | KeyCode::Down => finder.move_selection(pressed_key == KeyCode::Down),
+                KeyCode::Backspace => finder.pop(),
+                KeyCode::Char(c) if !modifiers.contains(KeyModifiers::CONTROL) => finder.push(c),
+                KeyCode::Esc | KeyCode::Char(_) => {
+                    self.close_finder();
+                    return;
+                }
+                _ => (),
+            }
+            self.show_finder();
+            return;
+        }
+
+        if let Some(overlay) = &mut self.overlay {
+            let height = self.terminal.size().height as usize;
+            let max_scroll = overlay.lines.len().saturating_sub(height);
+            let scroll = overlay.scroll;
+            overlay.scroll = match pressed_key {
+                KeyCode::Up => scroll.saturating_sub(1),
+                KeyCode::Down => scroll.saturating_add(1).min(max_scroll),
+                KeyCode::PageUp => scroll.saturating_sub(height),
+                KeyCode::PageDown => scroll.saturating_add(height).min(max_scroll),
+                _ => {
+                    self.overlay = None;
+                    return;
+                }
+            };
+            return;
+        }
+
+        if self.search.is_some() {
+            self.process_search_key(pressed_key, modifiers);
+            self.scroll();
+            return;
+        }
 
         // Handle command buffer first (highest priority)
+        if pressed_key != KeyCode::Tab {
+            self.completion = None;
+        }
         if let Some(ref mut buffer) = self.command_buffer {
             match pressed_key {
                 KeyCode::Enter => {
-                    let input = buffer.clone();
+                    let input = buffer.as_str().to_owned();
                     // clear the command buffer since we're processing it now
                     self.command_buffer = None;
+                    self.history_index = None;
 
                     // If there's a pending save command, treat this input as the filename
                     if let Some(pending_cmd) = self.pending_save_command.take() {
                         let filename = input.trim();
                         if !filename.is_empty() {
-                            self.document.file_name = Some(filename.to_owned());
-                            if self.document.save().is_ok() {
-                                if self.pending_force_save {
-                                    self.status_message = StatusMessage::from(format!(
-                                        "File force-saved as: {}",
-                                        filename
-                                    ));
-                                } else {
-                                    self.status_message =
-                                        StatusMessage::from(format!("File saved as: {}", filename));
-                                }
-                                if pending_cmd == "wq" {
-                                    self.should_quit = true;
-                                }
-                            } else {
-                                self.status_message =
-                                    StatusMessage::from("Error writing file!".to_owned());
-                            }
+                            let filename = filename.to_owned();
+                            self.save_as(&filename, pending_cmd == "wq", self.pending_force_save);
                         } else {
                             self.status_message =
                                 StatusMessage::from("No filename provided.".to_owned());
@@ -245,7 +1431,11 @@ impl Editor {
                         self.last_keys.clear();
                     } else {
                         // No pending special prompt — this is a normal command
+                        if !input.trim().is_empty() && self.command_history.last() != Some(&input) {
+                            self.command_history.push(input.clone());
+                        }
                         self.execute_command(&input);
+                        self.command_selection = None;
                         self.last_keys.clear();
                     }
                 }
@@ -253,30 +1443,212 @@ impl Editor {
                     // Cancel any active command or pending prompt
                     self.command_buffer = None;
                     self.pending_save_command = None;
+                    self.command_selection = None;
+                    self.history_index = None;
                     self.status_message = StatusMessage::from("Command cancelled".to_owned());
                     self.last_keys.clear();
                 }
-                KeyCode::Backspace => {
-                    buffer.pop();
+                KeyCode::Backspace => buffer.backspace(),
+                KeyCode::Delete => buffer.delete(),
+                // Ctrl keys such as Ctrl-F are not typed into the command
+                KeyCode::Char(c) if !modifiers.contains(KeyModifiers::CONTROL) => {
+                    buffer.insert(c);
                 }
-                KeyCode::Char(c) => {
-                    buffer.push(c);
+                KeyCode::Left => buffer.move_left(),
+                KeyCode::Right => buffer.move_right(),
+                KeyCode::Home => buffer.move_home(),
+                KeyCode::End => buffer.move_end(),
+                KeyCode::Tab => self.complete_command(),
+                KeyCode::Up if self.pending_save_command.is_none() => {
+                    let index = match self.history_index {
+                        Some(index) => index.saturating_sub(1),
+                        None if self.command_history.is_empty() => return,
+                        None => {
+                            self.history_draft = buffer.as_str().to_owned();
+                            self.command_history.len().saturating_sub(1)
+                        }
+                    };
+                    if let Some(command) = self.command_history.get(index) {
+                        buffer.set(command);
+                        self.history_index = Some(index);
+                    }
+                }
+                KeyCode::Down if self.pending_save_command.is_none() => {
+                    if let Some(index) = self.history_index {
+                        let next = index.saturating_add(1);
+                        if let Some(command) = self.command_history.get(next) {
+                            buffer.set(command);
+                            self.history_index = Some(next);
+                        } else {
+                            buffer.set(&self.history_draft);
+                            self.history_index = None;
+                        }
+                    }
                 }
                 _ => (),
             }
             self.scroll();
-            return Ok(());
+            return;
         }
 
-        // Handle keypresses
+        let selection = self.selection();
+        // Typing "i.:" ends the selection, so keep it for the command being opened
         match pressed_key {
-            KeyCode::Enter => {
-                self.document.insert(&self.cursor_position, '\n');
-                self.cursor_position.x = 0;
-                self.cursor_position.y = self.cursor_position.y.saturating_add(1);
-                self.last_keys.clear();
-            }
+            KeyCode::Char('i') => self.pending_selection = selection.clone(),
+            KeyCode::Char('.') if self.last_keys.last() == Some(&'i') => {}
+            KeyCode::Char(':') if self.last_keys.ends_with(&['i', '.']) => {}
+            _ => self.pending_selection = None,
+        }
+        // Any key other than Shift+movement ends the selection
+        if !(modifiers.contains(KeyModifiers::SHIFT) && is_movement_key(pressed_key)) {
+            self.selection_anchor = None;
+        }
+
+        if self.block_anchor.is_some() && self.handle_block_key(pressed_key, modifiers) {
+            self.last_keys.clear();
+            self.scroll();
+            return;
+        }
+
+        // A key that types a character is only mapped in Normal mode
+        let types_char = matches!(pressed_key, KeyCode::Char(_))
+            && !modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT);
+        if let Some(action) = self
+            .keymap
+            .get(pressed_key, modifiers)
+            .filter(|_| self.mode == Mode::Normal || !types_char)
+        {
+            self.run_action(action, selection);
+            self.last_keys.clear();
+            self.scroll();
+            return;
+        }
+
+        if self.mode == Mode::Normal
+            && !modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+            && self.handle_normal_key(pressed_key, selection.clone())
+        {
+            self.last_keys.clear();
+            self.scroll();
+            return;
+        }
+
+        // Digits insert text, so counts are typed with Alt held: Alt-5 Down moves five lines
+        let count = self.pending_count.take();
+        let repeat = count.unwrap_or(1).max(1);
+
+        if modifiers.contains(KeyModifiers::ALT) {
+            // Row edits act at the main cursor only and would leave the others stale
+            if matches!(
+                pressed_key,
+                KeyCode::Char('p') | KeyCode::Up | KeyCode::Down
+            ) {
+                self.extra_cursors.clear();
+            }
+            match pressed_key {
+                KeyCode::Char(digit @ '0'..='9') => {
+                    self.pending_count = count;
+                    self.push_count_digit(digit);
+                }
+                KeyCode::Char('p') => self.paste_block(),
+                // Legacy terminals report Ctrl-I as Tab, so jumping forward is on Alt-I
+                KeyCode::Char('i') => self.jump_forward(),
+                KeyCode::Char('w') => {
+                    self.extra_cursors.clear();
+                    self.switch_pane();
+                }
+                KeyCode::Up | KeyCode::Down if self.refuse_read_only() => (),
+                KeyCode::Up => {
+                    for _ in 0..repeat {
+                        self.move_line_up();
+                    }
+                }
+                KeyCode::Down => {
+                    for _ in 0..repeat {
+                        self.move_line_down();
+                    }
+                }
+                _ => (),
+            }
+            self.last_keys.clear();
+            self.scroll();
+            return;
+        }
+
+        if modifiers.contains(KeyModifiers::CONTROL) {
+            if matches!(
+                pressed_key,
+                KeyCode::Char('a' | 'd' | 'j' | 'k' | 'u' | 'w' | 'x' | '/' | '7')
+            ) {
+                self.extra_cursors.clear();
+            }
+            match pressed_key {
+                // Ctrl-] is reported as Ctrl-5 by terminals using legacy key encoding
+                KeyCode::Char(']' | '5') => self.jump_to_matching_bracket(),
+                KeyCode::Char('a' | 'x') => {
+                    let count = i64::try_from(repeat).unwrap_or(1);
+                    let negative = pressed_key == KeyCode::Char('x');
+                    self.add_to_number(if negative {
+                        count.saturating_neg()
+                    } else {
+                        count
+                    });
+                }
+                KeyCode::Char('d') => self.duplicate_line(),
+                KeyCode::Char('n') => self.add_cursor(),
+                KeyCode::Char('b') => {
+                    self.extra_cursors.clear();
+                    self.block_anchor = Some(self.cursor_position.clone());
+                    self.status_message = StatusMessage::from(
+                        "Block selection - type to insert, Delete removes, Ctrl-Y copies"
+                            .to_owned(),
+                    );
+                }
+                KeyCode::Char('p') => self.open_finder(),
+                KeyCode::Char('f') => self.start_search(),
+                KeyCode::Char('j') => self.join_lines(),
+                KeyCode::Char('k' | 'u' | 'w') => self.delete_in_line(pressed_key),
+                // Terminals using legacy key encoding report Ctrl-/ as Ctrl-7
+                KeyCode::Char('/' | '7') => self.toggle_comment(selection),
+                KeyCode::Char('o') => self.jump_back(),
+                // Reaches here only on terminals that tell Ctrl-I from Tab
+                KeyCode::Char('i') => self.jump_forward(),
+                _ => (),
+            }
+            self.last_keys.clear();
+            self.scroll();
+            return;
+        }
+
+        // Moving or editing after a :q warning starts the count over; typing
+        // characters doesn't, since that's how command mode is entered
+        if !matches!(pressed_key, KeyCode::Char(_)) {
+            self.quit_times = self.options.confirm;
+        }
+
+        // Handle keypresses
+        match pressed_key {
+            KeyCode::Enter if self.document.is_grep_results() => {
+                self.open_grep_result();
+                self.last_keys.clear();
+            }
+            KeyCode::Enter | KeyCode::Delete | KeyCode::Backspace if self.refuse_read_only() => {
+                self.last_keys.clear();
+            }
+            KeyCode::Enter => {
+                if self.extra_cursors.is_empty() {
+                    self.expand_abbreviation();
+                    self.insert_newline();
+                } else {
+                    self.edit_at_cursors(CursorEdit::Newline);
+                }
+                self.record_action(Action::Insert("\n".to_owned()));
+                self.last_keys.clear();
+            }
             KeyCode::Char(c) => {
+                if c == 'i' {
+                    self.action_before_command.clone_from(&self.last_action);
+                }
                 // Track last keys for command sequence
                 self.last_keys.push(c);
                 if self.last_keys.len() > 3 {
@@ -289,31 +1661,84 @@ impl Editor {
                     && self.last_keys[self.last_keys.len() - 2] == '.'
                     && self.last_keys[self.last_keys.len() - 1] == ':'
                 {
-                    // Remove the "i.:" that was just typed
-                    for _ in 0..3 {
-                        if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
-                            self.move_cursor(KeyCode::Left);
-                            self.document.delete(&self.cursor_position);
+                    // Remove the "i." typed before ':'; nothing was inserted when read-only
+                    if !self.is_read_only() && !self.extra_cursors.is_empty() {
+                        self.edit_at_cursors(CursorEdit::Backspace);
+                        self.edit_at_cursors(CursorEdit::Backspace);
+                    } else if !self.is_read_only() {
+                        for _ in 0..2 {
+                            if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
+                                self.move_cursor(KeyCode::Left);
+                                self.document.delete(&self.cursor_position);
+                            }
                         }
                     }
 
-                    // Enter command mode
-                    self.command_buffer = Some(String::new());
-                    self.status_message = StatusMessage::from("-- COMMAND MODE --".to_owned());
-                    self.last_keys.clear();
-                } else {
-                    self.document.insert(&self.cursor_position, c);
-                    self.move_cursor(KeyCode::Right);
+                    // The "i." typed before ':' is not part of the repeatable edit
+                    self.last_action = self.action_before_command.take();
+
+                    let selection = self.pending_selection.take();
+                    self.open_command_line(selection);
+                } else if !self.extra_cursors.is_empty() {
+                    if !self.refuse_read_only() {
+                        self.edit_at_cursors(CursorEdit::Insert(c));
+                    }
+                } else if !self.refuse_read_only() {
+                    if c == ' ' {
+                        self.expand_abbreviation();
+                    }
+                    self.insert_char(c);
+                    self.record_action(Action::Insert(c.to_string()));
+                }
+            }
+            KeyCode::Tab | KeyCode::BackTab
+                if selection
+                    .as_ref()
+                    .is_some_and(|(start, end)| start.y != end.y) =>
+            {
+                self.extra_cursors.clear();
+                if let Some(selection) = selection {
+                    for _ in 0..repeat {
+                        self.indent(Some(selection.clone()), pressed_key == KeyCode::BackTab);
+                    }
+                }
+                self.last_keys.clear();
+            }
+            KeyCode::BackTab => {
+                self.extra_cursors.clear();
+                for _ in 0..repeat {
+                    self.indent(None, true);
+                }
+                self.last_keys.clear();
+            }
+            KeyCode::Tab => {
+                if !self.refuse_read_only() {
+                    if self.extra_cursors.is_empty() {
+                        self.insert_char('\t');
+                    } else {
+                        self.edit_at_cursors(CursorEdit::Insert('\t'));
+                    }
+                    self.record_action(Action::Insert("\t".to_owned()));
                 }
+                self.last_keys.clear();
+            }
+            KeyCode::Delete | KeyCode::Backspace if !self.extra_cursors.is_empty() => {
+                self.edit_at_cursors(if pressed_key == KeyCode::Delete {
+                    CursorEdit::Delete
+                } else {
+                    CursorEdit::Backspace
+                });
+                self.last_keys.clear();
             }
             KeyCode::Delete => {
                 self.document.delete(&self.cursor_position);
+                self.record_action(Action::Delete(1));
                 self.last_keys.clear();
+                self.record_edit();
             }
             KeyCode::Backspace => {
-                if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
-                    self.move_cursor(KeyCode::Left);
-                    self.document.delete(&self.cursor_position);
+                if self.backspace() {
+                    self.record_action(Action::Backspace(1));
                 }
                 self.last_keys.clear();
             }
@@ -325,7 +1750,30 @@ impl Editor {
             | KeyCode::PageDown
             | KeyCode::End
             | KeyCode::Home => {
-                self.move_cursor(pressed_key);
+                if modifiers.contains(KeyModifiers::SHIFT) && self.selection_anchor.is_none() {
+                    self.selection_anchor = Some(self.cursor_position.clone());
+                }
+                if matches!(pressed_key, KeyCode::PageUp | KeyCode::PageDown) {
+                    self.push_jump(self.cursor_position.clone());
+                }
+                for _ in 0..repeat {
+                    self.move_cursor(pressed_key);
+                }
+                if !self.extra_cursors.is_empty() {
+                    self.move_extra_cursors(pressed_key, repeat);
+                }
+                self.last_keys.clear();
+            }
+            KeyCode::Esc if !self.extra_cursors.is_empty() => {
+                self.extra_cursors.clear();
+                self.mode = Mode::Normal;
+                self.last_keys.clear();
+            }
+            KeyCode::Esc => {
+                if self.highlighted_word.is_some() {
+                    self.end_search();
+                }
+                self.mode = Mode::Normal;
                 self.last_keys.clear();
             }
             _ => {
@@ -334,50 +1782,2153 @@ impl Editor {
         }
 
         self.scroll();
-        Ok(())
     }
-    fn scroll(&mut self) {
+    /// Adds an abbreviation from the text after `:ab`: a word, then what it
+    /// expands to, where `\n` starts a new line.
+    fn add_abbreviation(&mut self, args: &str) {
+        let Some((word, expansion)) = args.split_once(char::is_whitespace) else {
+            self.status_message =
+                StatusMessage::from("Expected a word and its expansion: ab word text".to_owned());
+            return;
+        };
+        if !word.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            self.status_message =
+                StatusMessage::from(format!("An abbreviation must be one word: {word}"));
+            return;
+        }
+        let expansion = expansion.trim_start().replace("\\n", "\n");
+        self.abbreviations.insert(word.to_owned(), expansion);
+    }
+    /// Replaces the word just typed before the cursor with its expansion, unless
+    /// the cursor is inside a longer word.
+    fn expand_abbreviation(&mut self) {
+        if self.abbreviations.is_empty() || !self.extra_cursors.is_empty() {
+            return;
+        }
         let Position { x, y } = self.cursor_position;
-        let width = self.terminal.size().width as usize;
+        let Some(row) = self.document.row(y) else {
+            return;
+        };
+        let Some(range) = row.word_range(x).filter(|range| range.end == x) else {
+            return;
+        };
+        let word: String = range
+            .clone()
+            .filter_map(|index| row.char_at(index))
+            .collect();
+        let Some(expansion) = self.abbreviations.get(&word).cloned() else {
+            return;
+        };
+        let start = Position { x: range.start, y };
+        self.document.delete_range(&start, &self.cursor_position);
+        self.cursor_position = self.document.insert_str(&start, &expansion);
+        self.record_edit();
+    }
+    /// Binds a key to an action from the text after `:map`, `key action`.
+    fn map_key(&mut self, binding: &str) -> Result<(), String> {
+        match binding.split_whitespace().collect::<Vec<_>>()[..] {
+            [key, action] => self.keymap.bind(key, action),
+            _ => Err(format!(
+                "Expected a key and an action: map {}",
+                binding.trim()
+            )),
+        }
+    }
+    /// Does what a key mapped with `:map` is bound to.
+    fn run_action(&mut self, action: keymap::Action, selection: Option<(Position, Position)>) {
+        match action {
+            keymap::Action::Save => self.execute_command("w"),
+            keymap::Action::Quit => self.execute_command("q"),
+            keymap::Action::Find => self.start_search(),
+            keymap::Action::SearchNext => self.repeat_search(false),
+            keymap::Action::SearchPrevious => self.repeat_search(true),
+            keymap::Action::FindFile => self.open_finder(),
+            keymap::Action::Command => self.open_command_line(selection),
+            keymap::Action::NormalMode => self.mode = Mode::Normal,
+            keymap::Action::InsertMode => self.mode = Mode::Insert,
+            keymap::Action::DuplicateLine => {
+                self.extra_cursors.clear();
+                self.duplicate_line();
+            }
+            keymap::Action::JoinLines => {
+                self.extra_cursors.clear();
+                self.join_lines();
+            }
+            keymap::Action::ToggleComment => {
+                self.extra_cursors.clear();
+                self.toggle_comment(selection);
+            }
+            keymap::Action::MatchingBracket => self.jump_to_matching_bracket(),
+            keymap::Action::JumpBack => self.jump_back(),
+            keymap::Action::JumpForward => self.jump_forward(),
+            keymap::Action::AddCursor => self.add_cursor(),
+            keymap::Action::NextBuffer => self.execute_command("bn"),
+            keymap::Action::PreviousBuffer => self.execute_command("bp"),
+            keymap::Action::SwitchPane => {
+                self.extra_cursors.clear();
+                self.switch_pane();
+            }
+            keymap::Action::Repeat => self.repeat_last_action(),
+            keymap::Action::Help => self.execute_command("help"),
+        }
+    }
+    /// Acts on a key typed in Normal mode, where `selection` was selected before it.
+    /// Returns false for keys that work as in Insert mode, such as the arrows.
+    fn handle_normal_key(&mut self, key: KeyCode, selection: Option<(Position, Position)>) -> bool {
+        let Position { x, y } = self.cursor_position;
+        let motion = match key {
+            KeyCode::Char('h') | KeyCode::Backspace => KeyCode::Left,
+            KeyCode::Char('j') => KeyCode::Down,
+            KeyCode::Enter if !self.document.is_grep_results() => KeyCode::Down,
+            KeyCode::Char('k') => KeyCode::Up,
+            KeyCode::Char('l' | ' ') => KeyCode::Right,
+            KeyCode::Char('0') if self.pending_count.is_none() => KeyCode::Home,
+            KeyCode::Char('$') => KeyCode::End,
+            KeyCode::Char('x') => KeyCode::Delete,
+            // Tab only indents a selection of several lines
+            KeyCode::Tab => return selection.is_none_or(|(start, end)| start.y == end.y),
+            KeyCode::Char(digit @ '0'..='9') => {
+                self.push_count_digit(digit);
+                return true;
+            }
+            KeyCode::Char('i') => {
+                self.mode = Mode::Insert;
+                return true;
+            }
+            KeyCode::Char('a') => {
+                if x < self.document.row(y).map_or(0, Row::len) {
+                    self.cursor_position.x = x.saturating_add(1);
+                }
+                self.mode = Mode::Insert;
+                return true;
+            }
+            KeyCode::Char('A') => {
+                self.mode = Mode::Insert;
+                KeyCode::End
+            }
+            KeyCode::Char('I') => {
+                // Home from the first column goes to the indentation
+                self.cursor_position.x = 0;
+                self.mode = Mode::Insert;
+                KeyCode::Home
+            }
+            KeyCode::Char('o') => {
+                self.handle_key(KeyCode::End, KeyModifiers::NONE);
+                self.mode = Mode::Insert;
+                KeyCode::Enter
+            }
+            KeyCode::Char(':') => {
+                self.open_command_line(selection);
+                return true;
+            }
+            KeyCode::Char('/') => {
+                self.start_search();
+                return true;
+            }
+            KeyCode::Char('.') => {
+                self.repeat_last_action();
+                return true;
+            }
+            KeyCode::Char(c @ ('n' | 'N')) => {
+                for _ in 0..self.pending_count.take().unwrap_or(1).max(1) {
+                    self.repeat_search(c == 'N');
+                }
+                return true;
+            }
+            // Other letters do nothing rather than insert text
+            KeyCode::Char(_) => {
+                self.pending_count = None;
+                return true;
+            }
+            _ => return false,
+        };
+        self.handle_key(motion, KeyModifiers::NONE);
+        true
+    }
+    /// Adds `digit` to the repeat count for the next movement.
+    fn push_count_digit(&mut self, digit: char) {
+        let value = digit
+            .to_digit(10)
+            .and_then(|value| usize::try_from(value).ok())
+            .unwrap_or(0);
+        let count = self
+            .pending_count
+            .unwrap_or(0)
+            .saturating_mul(10)
+            .saturating_add(value)
+            .min(MAX_COUNT);
+        self.pending_count = Some(count);
+        self.status_message = StatusMessage::from(format!("Count: {count}"));
+    }
+    /// Whether a search, the command line, the file picker or an overlay is
+    /// taking the keys. Another of them must not open over it, since each
+    /// restores the cursor and highlighting as it found them when it closes.
+    fn prompt_open(&self) -> bool {
+        self.search.is_some()
+            || self.command_buffer.is_some()
+            || self.finder.is_some()
+            || self.overlay.is_some()
+    }
+    /// Opens the `:` prompt; the command typed acts on `selection`.
+    fn open_command_line(&mut self, selection: Option<(Position, Position)>) {
+        if self.prompt_open() {
+            return;
+        }
+        self.command_selection = selection;
+        self.command_buffer = Some(CommandLine::default());
+        self.status_message = StatusMessage::from("-- COMMAND MODE --".to_owned());
+        self.last_keys.clear();
+    }
+    /// Reloads a clean buffer whose file changed on disk, or warns once if it is
+    /// dirty. A deleted file is warned about once and the buffer kept, so `:w`
+    /// can write it again.
+    fn check_disk_changes(&mut self) {
+        if self.last_disk_check.elapsed() < DISK_CHECK_INTERVAL {
+            return;
+        }
+        self.last_disk_check = Instant::now();
+        if self.document.deleted_on_disk() {
+            if !self.disk_change_reported {
+                self.disk_change_reported = true;
+                self.status_message =
+                    StatusMessage::from("File deleted on disk - :w writes it again".to_owned());
+            }
+            return;
+        }
+        if !self.document.changed_on_disk() {
+            self.disk_change_reported = false;
+            return;
+        }
+        if !self.document.is_dirty() {
+            if self.document.reload().is_ok() {
+                self.clamp_cursor();
+                self.status_message =
+                    StatusMessage::from("File changed on disk and was reloaded.".to_owned());
+            }
+        } else if !self.disk_change_reported {
+            self.disk_change_reported = true;
+            self.status_message =
+                StatusMessage::from("File changed on disk - :e! to reload".to_owned());
+        }
+    }
+    /// Opens `file_name` in a new buffer, or switches to it if already open.
+    fn open_buffer(&mut self, file_name: &str) {
+        if file_name.is_empty() {
+            self.status_message = StatusMessage::from("No file name given.".to_owned());
+            return;
+        }
+        if self.document.file_name.as_deref() == Some(file_name) {
+            return;
+        }
+        if let Some(index) = self
+            .buffers
+            .iter()
+            .position(|buffer| buffer.document.file_name.as_deref() == Some(file_name))
+        {
+            self.switch_buffer(index);
+            return;
+        }
+        let document = match Document::open(file_name) {
+            Ok(document) => {
+                if let Some(dir) = &self.config_dir {
+                    recent::add(dir, file_name);
+                }
+                document
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                let mut document = Document::default();
+                document.file_name = Some(file_name.to_owned());
+                document
+            }
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!(
+                    "ERR: Could not open file: {file_name}: {}",
+                    os_error_message(&error)
+                ));
+                return;
+            }
+        };
+        let has_newer_swap = document.has_newer_swap();
+        if document.is_lossy() {
+            self.status_message = StatusMessage::from(format!(
+                "{file_name} is not valid UTF-8 - opened read-only."
+            ));
+        }
+        self.buffers.push(Buffer {
+            document,
+            ..Buffer::default()
+        });
+        self.switch_buffer(self.buffers.len().saturating_sub(1));
+        let position = self
+            .config_dir
+            .as_deref()
+            .and_then(|dir| positions::load(dir, file_name));
+        if let Some(position) = position {
+            self.cursor_position = position;
+            self.clamp_cursor();
+            self.reposition_view("zz");
+        }
+        if has_newer_swap {
+            self.pending_swap_recovery = true;
+            self.status_message = StatusMessage::from(format!(
+                "Swap file found for {file_name}. Recover it? (y/n)"
+            ));
+        }
+    }
+    /// Completes the command name, or the file path after `:e` and in the Save as
+    /// prompt, filling in the longest common prefix of the matches.
+    fn complete_command(&mut self) {
+        let Some(buffer) = self.command_buffer.as_mut() else {
+            return;
+        };
+        if let Some(completion) = &mut self.completion {
+            if let Some(next) = completion.matches.get(completion.next) {
+                buffer.set(&format!("{}{next}", completion.base));
+                completion.next = completion.next.saturating_add(1) % completion.matches.len();
+            }
+            return;
+        }
+        let text = buffer.as_str().to_owned();
+        let (base, word, candidates) = if self.pending_save_command.is_some() {
+            (String::new(), text.clone(), path_completions(&text))
+        } else if let Some((command, path)) = text.split_once(' ') {
+            if !matches!(command, "e" | "edit" | "r" | "read") {
+                return;
+            }
+            (
+                format!("{command} "),
+                path.to_owned(),
+                path_completions(path),
+            )
+        } else {
+            let candidates = COMMANDS.iter().map(|&command| command.to_owned()).collect();
+            (String::new(), text.clone(), candidates)
+        };
+        let matches: Vec<String> = candidates
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(&word))
+            .collect();
+        match matches.as_slice() {
+            [] => self.status_message = StatusMessage::from("No completions.".to_owned()),
+            [only] => buffer.set(&format!("{base}{only}")),
+            _ => {
+                buffer.set(&format!("{base}{}", common_prefix(&matches)));
+                self.completion = Some(Completion {
+                    matches,
+                    next: 0,
+                    base,
+                });
+            }
+        }
+    }
+    /// Parks the active buffer and makes the buffer at `index` active.
+    fn switch_buffer(&mut self, index: usize) {
+        if index == self.current_buffer || index >= self.buffers.len() {
+            return;
+        }
+        self.end_search();
+        self.selection_anchor = None;
+        self.block_anchor = None;
+        self.matched_brackets = None;
+        self.disk_change_reported = false;
+        self.edits_since_swap = 0;
+        // Jump positions and folds belong to the buffer they were made in
+        self.jump_list.clear();
+        self.jump_index = 0;
+        self.folds.clear();
+        let active = Buffer {
+            document: core::mem::take(&mut self.document),
+            cursor_position: core::mem::take(&mut self.cursor_position),
+            offset: core::mem::take(&mut self.offset),
+            marks: core::mem::take(&mut self.marks),
+        };
+        if let Some(slot) = self.buffers.get_mut(self.current_buffer) {
+            *slot = active;
+        }
+        if let Some(slot) = self.buffers.get_mut(index) {
+            let buffer = core::mem::take(slot);
+            self.document = buffer.document;
+            self.cursor_position = buffer.cursor_position;
+            self.offset = buffer.offset;
+            self.marks = buffer.marks;
+        }
+        self.current_buffer = index;
+        self.status_message = StatusMessage::from(format!(
+            "Buffer {} of {}: {}",
+            index.saturating_add(1),
+            self.buffers.len(),
+            self.document.file_name.as_deref().unwrap_or("[No Name]")
+        ));
+    }
+    /// Closes the active buffer like `close_buffer`, or quits when it is the last one.
+    fn quit_buffer(&mut self, force: bool) {
+        if self.buffers.len() > 1 {
+            self.close_buffer(force);
+        } else {
+            self.should_quit = true;
+        }
+    }
+    /// Closes the buffer after `:wq`. When the file was saved in place, the
+    /// warning in the message bar is also printed on exit, so it isn't lost.
+    fn quit_after_save(&mut self, replaced: bool) {
+        self.quit_buffer(false);
+        if self.should_quit && !replaced {
+            self.exit_message = Some(SAVED_IN_PLACE.to_owned());
+        }
+    }
+    /// Closes the active buffer, refusing to drop unsaved changes unless `force` is set.
+    fn close_buffer(&mut self, force: bool) {
+        if self.document.is_dirty() && !force {
+            self.status_message = StatusMessage::from(
+                "Buffer has unsaved changes! Use :w to save, or :q! or :bd! to discard them."
+                    .to_owned(),
+            );
+            return;
+        }
+        let saved_file = self
+            .document
+            .file_name
+            .as_deref()
+            .filter(|_| !self.document.is_dirty());
+        if let Some((dir, file_name)) = self.config_dir.as_deref().zip(saved_file) {
+            positions::store(dir, &[(file_name, self.cursor_position.clone())]);
+        }
+        if self.buffers.len() <= 1 {
+            self.end_search();
+            self.document = Document::default();
+            self.cursor_position = Position::default();
+            self.offset = Position::default();
+            self.marks.clear();
+            self.folds.clear();
+            self.status_message = StatusMessage::from("Buffer closed.".to_owned());
+            return;
+        }
+        let closed = self.current_buffer;
+        let next = if closed == 0 {
+            1
+        } else {
+            closed.saturating_sub(1)
+        };
+        self.switch_buffer(next);
+        self.buffers.remove(closed);
+        if self.current_buffer > closed {
+            self.current_buffer = self.current_buffer.saturating_sub(1);
+        }
+        if let Some(pane) = &mut self.split {
+            if pane.buffer == closed {
+                pane.buffer = self.current_buffer;
+            } else if pane.buffer > closed {
+                pane.buffer = pane.buffer.saturating_sub(1);
+            }
+        }
+        self.status_message = StatusMessage::from("Buffer closed.".to_owned());
+    }
+    /// The rows and display columns of the block selection, so that it stays
+    /// rectangular on rows with wide characters.
+    fn block(&self) -> Option<(Range<usize>, Range<usize>)> {
+        let anchor = self.block_anchor.as_ref()?;
+        let cursor = &self.cursor_position;
+        let (from, to) = (self.display_column(anchor), self.display_column(cursor));
+        Some((
+            anchor.y.min(cursor.y)..anchor.y.max(cursor.y).saturating_add(1),
+            from.min(to)..from.max(to),
+        ))
+    }
+    /// Display column at which `position` starts in its row.
+    fn display_column(&self, position: &Position) -> usize {
+        self.document
+            .row(position.y)
+            .map_or(0, |row| row.width_until(position.x))
+    }
+    /// Handles a key while a block is selected. Movement keys and anything not
+    /// handled here end up in the normal key handling; other keys also end the
+    /// block. Returns true when the key was handled.
+    fn handle_block_key(&mut self, key: KeyCode, modifiers: KeyModifiers) -> bool {
+        let Some((rows, columns)) = self.block() else {
+            return false;
+        };
+        if is_movement_key(key) {
+            return false;
+        }
+        let control = modifiers.contains(KeyModifiers::CONTROL);
+        match key {
+            KeyCode::Esc => {}
+            KeyCode::Char('b') if control => {}
+            KeyCode::Char('y') if control => {
+                self.block_register = self.document.block_text(rows, columns);
+                self.status_message = StatusMessage::from(format!(
+                    "Copied a block of {} rows",
+                    self.block_register.len()
+                ));
+            }
+            KeyCode::Char(c) if !control => {
+                if !self.refuse_read_only() {
+                    let lines = vec![c.to_string(); rows.len()];
+                    let at = Position {
+                        x: columns.start,
+                        y: rows.start,
+                    };
+                    self.document.insert_block(&at, &lines);
+                    let end = self.document.row(rows.start).map_or(0, |row| {
+                        let index = row.index_from_width(columns.start);
+                        row.width_until(index.saturating_add(1))
+                    });
+                    self.set_block_column(end);
+                    self.record_edit();
+                }
+                return true;
+            }
+            KeyCode::Backspace | KeyCode::Delete => {
+                // With no columns selected, delete the column next to the block
+                let columns = match key {
+                    _ if !columns.is_empty() => columns,
+                    KeyCode::Backspace if columns.start > 0 => {
+                        columns.start.saturating_sub(1)..columns.start
+                    }
+                    KeyCode::Delete => columns.start..columns.start.saturating_add(1),
+                    _ => return true,
+                };
+                if !self.refuse_read_only() {
+                    self.document.delete_block(rows, columns.clone());
+                    self.set_block_column(columns.start);
+                    self.record_edit();
+                }
+                return true;
+            }
+            _ => {
+                self.block_anchor = None;
+                return false;
+            }
+        }
+        self.block_anchor = None;
+        true
+    }
+    /// Narrows the block to no columns at display column `column`, keeping its rows.
+    fn set_block_column(&mut self, column: usize) {
+        let index = |y| {
+            self.document
+                .row(y)
+                .map_or(0, |row| row.index_from_width(column))
+        };
+        if let Some(anchor) = self.block_anchor.clone() {
+            self.block_anchor = Some(Position {
+                x: index(anchor.y),
+                y: anchor.y,
+            });
+        }
+        self.cursor_position.x = index(self.cursor_position.y);
+    }
+    /// Lists the lines containing `text` in the files under the current directory
+    /// in a results buffer, replacing earlier results.
+    fn grep(&mut self, text: &str) {
+        if text.is_empty() {
+            self.status_message = StatusMessage::from("Nothing to search for.".to_owned());
+            return;
+        }
+        let pattern = Pattern::from(text);
+        let mut results = Vec::new();
+        for file in finder::list_files(Path::new(".")) {
+            if fs::metadata(&file).map_or(true, |metadata| metadata.len() > MAX_GREP_FILE_SIZE) {
+                continue;
+            }
+            // Binary and other non-UTF-8 files are skipped
+            let Ok(contents) = fs::read_to_string(&file) else {
+                continue;
+            };
+            let matches = contents
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| pattern.is_match(line))
+                .map(|(index, line)| format!("{file}:{}: {line}", index.saturating_add(1)));
+            results.extend(matches.take(MAX_GREP_RESULTS.saturating_sub(results.len())));
+            if results.len() >= MAX_GREP_RESULTS {
+                break;
+            }
+        }
+        if results.is_empty() {
+            self.status_message = StatusMessage::from(format!("No matches for {text}"));
+            return;
+        }
+        let count = results.len();
+        let document = Document::grep_results(&results.join("\n"));
+        if let Some(index) = self
+            .buffers
+            .iter()
+            .position(|buffer| buffer.document.is_grep_results())
+            .filter(|&index| index != self.current_buffer)
+        {
+            self.switch_buffer(index);
+        } else if !self.document.is_grep_results() {
+            self.buffers.push(Buffer::default());
+            self.switch_buffer(self.buffers.len().saturating_sub(1));
+        }
+        self.document = document;
+        self.cursor_position = Position::default();
+        self.offset = Position::default();
+        self.folds.clear();
+        let limit = if count >= MAX_GREP_RESULTS {
+            " (limit reached)"
+        } else {
+            ""
+        };
+        self.status_message = StatusMessage::from(format!(
+            "{count} matches{limit} - Enter opens the one under the cursor"
+        ));
+    }
+    /// Opens the file of the `:grep` result under the cursor at its line.
+    fn open_grep_result(&mut self) {
+        let Some((file, line)) = self
+            .document
+            .row(self.cursor_position.y)
+            .and_then(|row| parse_grep_result(row.as_str()))
+        else {
+            return;
+        };
+        let file = file.to_owned();
+        self.open_buffer(&file);
+        if self.document.file_name.as_deref() == Some(file.as_str()) {
+            self.push_jump(self.cursor_position.clone());
+            self.cursor_position = Position {
+                x: 0,
+                y: line.saturating_sub(1),
+            };
+            self.clamp_cursor();
+            self.reposition_view("zz");
+        }
+    }
+    /// Opens the fuzzy file picker on the files under the current directory.
+    fn open_finder(&mut self) {
+        if self.prompt_open() {
+            return;
+        }
+        self.finder = Some(Finder::new(Path::new(".")));
+        self.show_finder();
+    }
+    /// Opens the file picker on the recently opened files.
+    fn show_recent_files(&mut self) {
+        let files = self
+            .config_dir
+            .as_deref()
+            .map(recent::files)
+            .unwrap_or_default();
+        if files.is_empty() {
+            self.status_message = StatusMessage::from("No recent files.".to_owned());
+            return;
+        }
+        self.finder = Some(Finder::with_files(files));
+        self.show_finder();
+    }
+    /// Redraws the picker's candidates in the overlay, scrolled to the selection.
+    fn show_finder(&mut self) {
+        let Some(finder) = &self.finder else {
+            return;
+        };
         let height = self.terminal.size().height as usize;
-        let offset = &mut self.offset;
-        if y < offset.y {
-            offset.y = y;
-        } else if y >= offset.y.saturating_add(height) {
-            offset.y = y.saturating_sub(height).saturating_add(1);
+        self.overlay = Some(Overlay {
+            title: String::new(),
+            lines: finder.lines(),
+            scroll: finder
+                .selected_index()
+                .saturating_add(1)
+                .saturating_sub(height),
+        });
+    }
+    fn close_finder(&mut self) {
+        self.finder = None;
+        self.overlay = None;
+    }
+    /// Inserts the block copied with Ctrl-Y at the cursor.
+    fn paste_block(&mut self) {
+        if self.block_register.is_empty() {
+            self.status_message = StatusMessage::from("No block copied".to_owned());
+        } else if !self.refuse_read_only() {
+            let at = Position {
+                x: self.display_column(&self.cursor_position),
+                y: self.cursor_position.y,
+            };
+            self.document.insert_block(&at, &self.block_register);
+            self.record_edit();
         }
-        if x < offset.x {
-            offset.x = x;
-        } else if x >= offset.x.saturating_add(width) {
-            offset.x = x.saturating_sub(width).saturating_add(1);
+    }
+    /// Adds a cursor at the next search match after the last cursor, or on the
+    /// line below it when no search is highlighted. Like search, says so when
+    /// the match is found by wrapping around to the top.
+    fn add_cursor(&mut self) {
+        let last = self
+            .extra_cursors
+            .last()
+            .unwrap_or(&self.cursor_position)
+            .clone();
+        let position = if let Some(pattern) = &self.highlighted_word {
+            let after = Position {
+                x: last.x.saturating_add(1),
+                y: last.y,
+            };
+            self.document
+                .find(pattern, &after, SearchDirection::Forward)
+                .map(|(position, _, wrapped)| (position, wrapped))
+        } else {
+            let y = last.y.saturating_add(1);
+            self.document.row(y).map(|row| {
+                let position = Position {
+                    x: last.x.min(row.len()),
+                    y,
+                };
+                (position, false)
+            })
+        };
+        match position {
+            Some((position, wrapped))
+                if position != self.cursor_position && !self.extra_cursors.contains(&position) =>
+            {
+                self.extra_cursors.push(position);
+                let count = self.extra_cursors.len().saturating_add(1);
+                self.status_message = StatusMessage::from(if wrapped {
+                    format!(
+                        "{count} cursors - Esc leaves one ({})",
+                        wrap_message(SearchDirection::Forward)
+                    )
+                } else {
+                    format!("{count} cursors - Esc leaves one")
+                });
+            }
+            _ => {
+                self.status_message = StatusMessage::from("No place for another cursor.".to_owned())
+            }
+        }
+    }
+    /// Applies `edit` at every cursor, starting from the end of the document so
+    /// that each edit leaves the cursors before it in place. Edits that would join
+    /// rows are skipped.
+    fn edit_at_cursors(&mut self, edit: CursorEdit) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let mut cursors = core::mem::take(&mut self.extra_cursors);
+        cursors.push(self.cursor_position.clone());
+        let primary = cursors.len().saturating_sub(1);
+        let mut order: Vec<usize> = (0..cursors.len()).collect();
+        order.sort_by_key(|&index| {
+            cursors
+                .get(index)
+                .map(|cursor| core::cmp::Reverse((cursor.y, cursor.x)))
+        });
+        for index in order {
+            let Some(at) = cursors.get(index).cloned() else {
+                continue;
+            };
+            if matches!(edit, CursorEdit::Newline) {
+                self.document.insert(&at, '\n');
+                for cursor in &mut cursors {
+                    if cursor.y > at.y {
+                        cursor.y = cursor.y.saturating_add(1);
+                    } else if cursor.y == at.y && cursor.x >= at.x {
+                        cursor.y = cursor.y.saturating_add(1);
+                        cursor.x = cursor.x.saturating_sub(at.x);
+                    }
+                }
+                continue;
+            }
+            let row_len = self.document.row(at.y).map_or(0, Row::len);
+            // Column of the edit, and whether text was inserted there
+            let (x, inserted) = match edit {
+                CursorEdit::Insert(c) => {
+                    self.document.insert(&at, c);
+                    (at.x, true)
+                }
+                CursorEdit::Backspace if at.x > 0 => {
+                    let x = at.x.saturating_sub(1);
+                    self.document.delete(&Position { x, y: at.y });
+                    (x, false)
+                }
+                CursorEdit::Delete if at.x < row_len => {
+                    self.document.delete(&at);
+                    (at.x, false)
+                }
+                _ => continue,
+            };
+            for cursor in cursors.iter_mut().filter(|cursor| cursor.y == at.y) {
+                if inserted && cursor.x >= x {
+                    cursor.x = cursor.x.saturating_add(1);
+                } else if !inserted && cursor.x > x {
+                    cursor.x = cursor.x.saturating_sub(1);
+                }
+            }
+        }
+        self.cursor_position = cursors.swap_remove(primary);
+        for cursor in cursors {
+            if cursor != self.cursor_position && !self.extra_cursors.contains(&cursor) {
+                self.extra_cursors.push(cursor);
+            }
+        }
+        self.record_edit();
+    }
+    /// Moves every extra cursor `repeat` times with `key`, like the main cursor.
+    fn move_extra_cursors(&mut self, key: KeyCode, repeat: usize) {
+        let primary = self.cursor_position.clone();
+        for cursor in core::mem::take(&mut self.extra_cursors) {
+            self.cursor_position = cursor;
+            for _ in 0..repeat {
+                self.move_cursor(key);
+            }
+            if self.cursor_position != primary
+                && !self.extra_cursors.contains(&self.cursor_position)
+            {
+                self.extra_cursors.push(self.cursor_position.clone());
+            }
+        }
+        self.cursor_position = primary;
+    }
+    /// Saves under `file_name`, asking first when that would replace a different
+    /// file that already exists, unless `force` is set. Quits afterwards when
+    /// `quit` is set.
+    fn save_as(&mut self, file_name: &str, quit: bool, force: bool) {
+        let current = self
+            .document
+            .file_name
+            .as_deref()
+            .and_then(|name| fs::canonicalize(name).ok());
+        let target = fs::canonicalize(file_name).ok();
+        if !force && target.is_some() && target != current {
+            self.pending_overwrite = Some((file_name.to_owned(), quit));
+            self.status_message =
+                StatusMessage::from(format!("{file_name} exists. Overwrite? (y/n)"));
+            return;
+        }
+        self.write_as(file_name, quit, force);
+    }
+    /// Saves under `file_name`. The buffer keeps its old name when the write fails.
+    fn write_as(&mut self, file_name: &str, quit: bool, force: bool) {
+        let previous = self.document.file_name.replace(file_name.to_owned());
+        match self.document.save() {
+            Ok(replaced) => {
+                if !replaced {
+                    self.status_message = StatusMessage::from(SAVED_IN_PLACE.to_owned());
+                } else if force {
+                    self.status_message =
+                        StatusMessage::from(format!("File force-saved as: {file_name}"));
+                } else {
+                    self.status_message =
+                        StatusMessage::from(format!("File saved as: {file_name}"));
+                }
+                if quit {
+                    self.quit_after_save(replaced);
+                }
+            }
+            Err(error) => {
+                self.document.file_name = previous;
+                self.status_message = StatusMessage::from(write_error_message(&error));
+            }
+        }
+    }
+    /// Remembers where the cursor is in each open file, for the next time it's opened.
+    fn store_positions(&self) {
+        if let Some(dir) = &self.config_dir {
+            positions::store(dir, &self.positions_to_store());
+        }
+    }
+    /// The cursor position in each open file whose changes were saved. Files
+    /// left with discarded edits (`:q!`) aren't included, since the position
+    /// may not exist in the file on disk.
+    fn positions_to_store(&self) -> Vec<(&str, Position)> {
+        let mut files = vec![(&self.document, self.cursor_position.clone())];
+        for (index, buffer) in self.buffers.iter().enumerate() {
+            if index != self.current_buffer {
+                files.push((&buffer.document, buffer.cursor_position.clone()));
+            }
+        }
+        files
+            .into_iter()
+            .filter(|(document, _)| !document.is_dirty())
+            .filter_map(|(document, position)| Some((document.file_name.as_deref()?, position)))
+            .collect()
+    }
+    /// Deletes from the cursor to the end of the line (Ctrl-K), to its start
+    /// (Ctrl-U), or back to the start of the previous word (Ctrl-W). Ctrl-K at
+    /// the end of a line joins the next one.
+    fn delete_in_line(&mut self, key: KeyCode) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let Some(row) = self.document.row(self.cursor_position.y) else {
+            return;
+        };
+        let Position { x, y } = self.cursor_position;
+        let (start, end) = match key {
+            KeyCode::Char('k') if x >= row.len() => (
+                x,
+                Position {
+                    x: 0,
+                    y: y.saturating_add(1),
+                },
+            ),
+            KeyCode::Char('k') => (x, Position { x: row.len(), y }),
+            KeyCode::Char('u') => (0, Position { x, y }),
+            _ => (row.previous_word_start(x), Position { x, y }),
+        };
+        let start = Position { x: start, y };
+        if start == end || end.y >= self.document.len() {
+            return;
+        }
+        self.document.delete_range(&start, &end);
+        self.cursor_position = start;
+        self.record_edit();
+    }
+    /// Makes the inactive pane of a split active, keeping each pane's view.
+    fn switch_pane(&mut self) {
+        let Some(pane) = self.split.take() else {
+            self.status_message = StatusMessage::from("No split window - use :split.".to_owned());
+            return;
+        };
+        self.split = Some(Pane {
+            buffer: self.current_buffer,
+            cursor_position: self.cursor_position.clone(),
+            offset: self.offset.clone(),
+            top: !pane.top,
+        });
+        self.selection_anchor = None;
+        self.block_anchor = None;
+        self.switch_buffer(pane.buffer);
+        self.cursor_position = pane.cursor_position;
+        self.offset = pane.offset;
+        self.clamp_cursor();
+    }
+    /// Lists the open buffers in the status bar, marking the active one with `%`
+    /// and modified ones with `+`.
+    fn list_buffers(&mut self) {
+        let entries: Vec<String> = self
+            .buffers
+            .iter()
+            .enumerate()
+            .map(|(index, buffer)| {
+                let document = if index == self.current_buffer {
+                    &self.document
+                } else {
+                    &buffer.document
+                };
+                format!(
+                    "{}{}{} {}",
+                    index.saturating_add(1),
+                    if index == self.current_buffer {
+                        "%"
+                    } else {
+                        ""
+                    },
+                    if document.is_dirty() { "+" } else { "" },
+                    document.file_name.as_deref().unwrap_or("[No Name]")
+                )
+            })
+            .collect();
+        self.status_message = StatusMessage::from(entries.join(" | "));
+    }
+    fn insert_newline(&mut self) {
+        self.document.insert(&self.cursor_position, '\n');
+        self.cursor_position.x = 0;
+        self.cursor_position.y = self.cursor_position.y.saturating_add(1);
+        self.record_edit();
+    }
+    /// Deletes the grapheme before the cursor, and the closer of an empty pair.
+    fn backspace(&mut self) -> bool {
+        if self.cursor_position.x == 0 && self.cursor_position.y == 0 {
+            return false;
+        }
+        let empty_pair = self.options.autopairs && self.is_in_empty_pair();
+        self.move_cursor(KeyCode::Left);
+        if empty_pair {
+            self.document.delete(&self.cursor_position);
+        }
+        self.document.delete(&self.cursor_position);
+        self.record_edit();
+        true
+    }
+    /// Remembers `action` for `:.`, extending the previous one when the same kind
+    /// of edit continues on consecutive keypresses.
+    fn record_action(&mut self, action: Action) {
+        let continues = self.last_action_key == self.keypress_count.wrapping_sub(1);
+        self.last_action_key = self.keypress_count;
+        match (&mut self.last_action, action) {
+            (Some(Action::Insert(text)), Action::Insert(more)) if continues => {
+                text.push_str(&more);
+            }
+            (Some(Action::Delete(count)), Action::Delete(more))
+            | (Some(Action::Backspace(count)), Action::Backspace(more))
+                if continues =>
+            {
+                *count = count.saturating_add(more);
+            }
+            (_, action) => self.last_action = Some(action),
+        }
+    }
+    /// Replays the last recorded edit at the cursor.
+    fn repeat_last_action(&mut self) {
+        let Some(action) = self.last_action.clone() else {
+            self.status_message = StatusMessage::from("Nothing to repeat.".to_owned());
+            return;
+        };
+        if self.refuse_read_only() {
+            return;
+        }
+        match action {
+            Action::Insert(text) => {
+                for c in text.chars() {
+                    if c == '\n' {
+                        self.insert_newline();
+                    } else {
+                        self.insert_char(c);
+                    }
+                }
+            }
+            Action::Delete(count) => {
+                for _ in 0..count {
+                    self.document.delete(&self.cursor_position);
+                }
+                self.record_edit();
+            }
+            Action::Backspace(count) => {
+                for _ in 0..count {
+                    self.backspace();
+                }
+            }
+            Action::Join => self.join_lines(),
+            Action::Duplicate => self.duplicate_line(),
+        }
+    }
+    /// Types `c` at the cursor, pairing brackets and quotes when `autopairs` is set.
+    fn insert_char(&mut self, c: char) {
+        let Position { x, y } = self.cursor_position;
+        let row = self.document.row(y);
+        let next = row.and_then(|row| row.char_at(x));
+        let previous = x
+            .checked_sub(1)
+            .and_then(|before| row.and_then(|row| row.char_at(before)));
+        // Type over the closing character that was inserted with its opener
+        if self.options.autopairs && next == Some(c) && matches!(c, ')' | ']' | '}' | '"' | '\'') {
+            self.move_cursor(KeyCode::Right);
+            return;
+        }
+        self.document.insert(&self.cursor_position, c);
+        if let Some(close) = closing_pair(c).filter(|_| self.options.autopairs) {
+            // Don't pair in front of a word, or turn an apostrophe into a quote pair
+            let word_before = previous.is_some_and(char::is_alphanumeric);
+            let word_after = next.is_some_and(char::is_alphanumeric);
+            if !(word_after || close == c && word_before) {
+                let after = Position {
+                    x: x.saturating_add(1),
+                    y,
+                };
+                self.document.insert(&after, close);
+            }
+        }
+        self.move_cursor(KeyCode::Right);
+        self.record_edit();
+    }
+    /// True when the cursor sits between an auto-paired opener and its closer.
+    fn is_in_empty_pair(&self) -> bool {
+        let Position { x, y } = self.cursor_position;
+        let Some(row) = self.document.row(y) else {
+            return false;
+        };
+        let previous = x.checked_sub(1).and_then(|before| row.char_at(before));
+        match (previous.and_then(closing_pair), row.char_at(x)) {
+            (Some(close), Some(next)) => close == next,
+            _ => false,
+        }
+    }
+    /// Reports and returns true when an edit is blocked by read-only mode.
+    fn refuse_read_only(&mut self) -> bool {
+        if self.document.is_lossy() {
+            self.status_message =
+                StatusMessage::from("File is not valid UTF-8 and is read-only".to_owned());
+        } else if self.options.read_only {
+            self.status_message = StatusMessage::from("File is read-only".to_owned());
+        }
+        self.is_read_only()
+    }
+    /// True when edits are refused, by `:set readonly` or because the file was not
+    /// valid UTF-8.
+    fn is_read_only(&self) -> bool {
+        self.options.read_only || self.document.is_lossy() || self.document.is_grep_results()
+    }
+    /// Keeps the cursor inside the document after its rows were replaced.
+    fn clamp_cursor(&mut self) {
+        let Position { mut x, mut y } = self.cursor_position;
+        y = y.min(self.document.len());
+        x = x.min(self.document.row(y).map_or(0, Row::len));
+        self.cursor_position = Position { x, y };
+    }
+    fn start_search(&mut self) {
+        if self.prompt_open() {
+            return;
+        }
+        self.current_match = None;
+        self.search = Some(Search {
+            query: String::new(),
+            regex: false,
+            invalid: false,
+            matches: (None, 0),
+            origin: self.cursor_position.clone(),
+            origin_offset: self.offset.clone(),
+            wrapped: None,
+            direction: SearchDirection::Forward,
+        });
+    }
+    fn process_search_key(&mut self, key: KeyCode, modifiers: KeyModifiers) {
+        let Some(search) = self.search.as_mut() else {
+            return;
+        };
+        let mut direction = None;
+        match key {
+            KeyCode::Esc => {
+                self.cursor_position = search.origin.clone();
+                self.offset = search.origin_offset.clone();
+                self.end_search();
+                self.status_message = StatusMessage::from("Search cancelled.".to_owned());
+                return;
+            }
+            KeyCode::Enter => {
+                // With hlsearch the matches stay highlighted until the next edit
+                // or Esc, but not the current one, which the cursor is about to leave
+                let origin = search.origin.clone();
+                if !search.query.is_empty() && !search.invalid {
+                    self.last_search = Some(LastSearch {
+                        query: search.query.clone(),
+                        regex: search.regex,
+                        direction: search.direction,
+                    });
+                }
+                self.search = None;
+                self.current_match = None;
+                if !self.options.hlsearch {
+                    self.set_highlighted_word(None);
+                }
+                if origin != self.cursor_position {
+                    self.push_jump(origin);
+                }
+                return;
+            }
+            KeyCode::Char('r') if modifiers.contains(KeyModifiers::CONTROL) => {
+                search.regex = !search.regex;
+            }
+            KeyCode::Char(c) if !modifiers.contains(KeyModifiers::CONTROL) => {
+                search.query.push(c);
+            }
+            KeyCode::Backspace => {
+                search.query.pop();
+            }
+            KeyCode::Right | KeyCode::Down => direction = Some(SearchDirection::Forward),
+            KeyCode::Left | KeyCode::Up => direction = Some(SearchDirection::Backward),
+            _ => return,
+        }
+        if let Some(direction) = direction {
+            search.direction = direction;
+        }
+
+        let pattern = match Pattern::new(&search.query, search.regex) {
+            Ok(pattern) => {
+                search.invalid = false;
+                pattern
+            }
+            Err(_) => {
+                search.invalid = true;
+                self.set_highlighted_word(None);
+                return;
+            }
+        };
+        if pattern.is_empty() {
+            self.set_highlighted_word(None);
+            return;
+        }
+        // Typing re-runs the search from the current match; arrows step past it
+        let mut at = self.cursor_position.clone();
+        if direction == Some(SearchDirection::Forward) {
+            at.x = at.x.saturating_add(1);
+        }
+        let step = direction.unwrap_or(SearchDirection::Forward);
+        search.wrapped = None;
+        if let Some((position, len, wrapped)) = self.document.find(&pattern, &at, step) {
+            if wrapped {
+                search.wrapped = Some(step);
+                self.status_message = StatusMessage::from(wrap_message(step).to_owned());
+            }
+            self.cursor_position = position.clone();
+            self.current_match = Some((position, len));
+        } else if direction.is_none() {
+            self.current_match = None;
+        }
+        search.matches = self.document.match_stats(&pattern, &self.cursor_position);
+        self.set_highlighted_word(Some(pattern));
+    }
+    /// Moves to the next match of the last accepted search in the direction it
+    /// was stepped in, or the opposite one when `reverse` is set, wrapping around
+    /// the document.
+    fn repeat_search(&mut self, reverse: bool) {
+        let Some(last) = &self.last_search else {
+            self.status_message =
+                StatusMessage::from("No matches: nothing searched yet.".to_owned());
+            return;
+        };
+        let Ok(pattern) = Pattern::new(&last.query, last.regex) else {
+            return;
+        };
+        let direction = match (last.direction, reverse) {
+            (SearchDirection::Forward, false) | (SearchDirection::Backward, true) => {
+                SearchDirection::Forward
+            }
+            _ => SearchDirection::Backward,
+        };
+        let mut at = self.cursor_position.clone();
+        if direction == SearchDirection::Forward {
+            at.x = at.x.saturating_add(1);
+        }
+        let Some((position, _, wrapped)) = self.document.find(&pattern, &at, direction) else {
+            self.status_message = StatusMessage::from(format!("No matches for {}", last.query));
+            return;
+        };
+        self.status_message = StatusMessage::from(if wrapped {
+            wrap_message(direction).to_owned()
+        } else {
+            format!("/{}", last.query)
+        });
+        if position != self.cursor_position {
+            self.push_jump(self.cursor_position.clone());
+            self.cursor_position = position;
+        }
+        if self.options.hlsearch && self.highlighted_word.is_none() {
+            self.set_highlighted_word(Some(pattern));
+        }
+    }
+    fn end_search(&mut self) {
+        self.search = None;
+        self.current_match = None;
+        self.set_highlighted_word(None);
+    }
+    /// Changes the highlighted search pattern, forcing rows to be re-highlighted.
+    fn set_highlighted_word(&mut self, word: Option<Pattern>) {
+        self.highlighted_word = word;
+        self.document
+            .invalidate_highlighting(0..self.document.len());
+    }
+    /// Inserts the contents of a file, or the output of `!command`, below the
+    /// current line.
+    fn read_below(&mut self, source: &str) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let text = if let Some(command) = source.strip_prefix('!') {
+            run_shell(command.trim(), None)
+        } else if source.is_empty() {
+            Err("Usage: :r <file> or :r !command".to_owned())
+        } else {
+            std::fs::read_to_string(source).map_err(|error| format!("ERR: {error}: {source}"))
+        };
+        match text {
+            Ok(text) => {
+                let y = self.cursor_position.y.saturating_add(1);
+                let count = self.document.insert_rows(y, &text);
+                if count > 0 {
+                    self.cursor_position = Position {
+                        x: 0,
+                        y: y.min(self.document.len().saturating_sub(count)),
+                    };
+                    self.record_edit();
+                }
+                let plural = if count == 1 { "" } else { "s" };
+                self.status_message = StatusMessage::from(format!("Inserted {count} line{plural}"));
+            }
+            Err(message) => self.status_message = StatusMessage::from(message),
+        }
+    }
+    /// Runs `command` and shows what it prints in an overlay. Ctrl-C stops it.
+    fn show_command_output(&mut self, command: &str) {
+        use std::io::Read as _;
+        use std::process::{Command, Stdio};
+        if command.is_empty() {
+            self.status_message = StatusMessage::from("No command given.".to_owned());
+            return;
+        }
+        let child = Command::new("sh")
+            .args(["-c", &format!("exec 2>&1\n{command}")])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("ERR: {error}: {command}"));
+                return;
+            }
+        };
+        self.status_message = StatusMessage::from(format!("Running {command} - Ctrl-C stops it"));
+        if let Err(error) = self.refresh_screen() {
+            die(error);
+        }
+        // Output arrives through a channel so that a stopped command's children,
+        // which may keep the pipe open, cannot block the editor
+        let (sender, receiver) = std::sync::mpsc::channel();
+        if let Some(mut stdout) = child.stdout.take() {
+            std::thread::spawn(move || {
+                let mut chunk = [0; 4096];
+                while let Ok(read @ 1..) = stdout.read(&mut chunk) {
+                    if sender
+                        .send(chunk.get(..read).unwrap_or_default().to_vec())
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+        let mut output = Vec::new();
+        let status = loop {
+            output.extend(receiver.try_iter().flatten());
+            match child.try_wait() {
+                Ok(Some(status)) => break format!("{status}"),
+                Ok(None) => (),
+                Err(error) => break format!("{error}"),
+            }
+            let key = self
+                .terminal
+                .poll_key(Duration::from_millis(50))
+                .unwrap_or_default();
+            if key == Some((KeyCode::Char('c'), KeyModifiers::CONTROL)) {
+                // The command may have just exited, in which case there is nothing to kill
+                let _ = child.kill();
+                let _ = child.wait();
+                break "interrupted".to_owned();
+            }
+        };
+        // Collect what was written just before the command exited
+        let exited = Instant::now();
+        while let Ok(chunk) =
+            receiver.recv_timeout(Duration::from_millis(100).saturating_sub(exited.elapsed()))
+        {
+            output.extend(chunk);
+        }
+        let mut lines: Vec<String> = String::from_utf8_lossy(&output)
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        if lines.is_empty() {
+            lines.push("(no output)".to_owned());
+        }
+        self.status_message = StatusMessage::from(String::new());
+        self.overlay = Some(Overlay {
+            title: format!("!{command} ({status})"),
+            lines,
+            scroll: 0,
+        });
+    }
+    /// Replaces `rows` with the output of `command` run with them as its input.
+    /// The rows are left alone when the command fails.
+    fn filter(&mut self, rows: Range<usize>, command: &str) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let mut input = String::new();
+        for y in rows.clone() {
+            if let Some(row) = self.document.row(y) {
+                input.push_str(row.as_str());
+                input.push('\n');
+            }
+        }
+        match run_shell(command.trim(), Some(&input)) {
+            Ok(output) => {
+                let count = rows.len();
+                self.document.replace_rows(rows, &output);
+                self.clamp_cursor();
+                self.record_edit();
+                let plural = if count == 1 { "" } else { "s" };
+                self.status_message = StatusMessage::from(format!(
+                    "Filtered {count} line{plural} through {}",
+                    command.trim()
+                ));
+            }
+            Err(message) => self.status_message = StatusMessage::from(message),
+        }
+    }
+    /// Adds `delta` to the number at or after the cursor.
+    fn add_to_number(&mut self, delta: i64) {
+        if self.refuse_read_only() {
+            return;
+        }
+        if let Some(x) = self.document.add_to_number(&self.cursor_position, delta) {
+            self.cursor_position.x = x;
+            self.record_edit();
+        } else {
+            self.status_message = StatusMessage::from("No number to change.".to_owned());
+        }
+    }
+    fn duplicate_line(&mut self) {
+        if self.refuse_read_only() {
+            return;
+        }
+        if self.document.duplicate_row(self.cursor_position.y) {
+            self.cursor_position.y = self.cursor_position.y.saturating_add(1);
+            self.record_action(Action::Duplicate);
+            self.record_edit();
+        } else {
+            self.status_message = StatusMessage::from("Nothing to duplicate.".to_owned());
+        }
+    }
+    fn move_line_up(&mut self) {
+        let y = self.cursor_position.y;
+        if y > 0 && self.document.swap_rows(y.saturating_sub(1), y) {
+            self.cursor_position.y = y.saturating_sub(1);
+            self.record_edit();
+        }
+    }
+    fn move_line_down(&mut self) {
+        let y = self.cursor_position.y;
+        if self.document.swap_rows(y, y.saturating_add(1)) {
+            self.cursor_position.y = y.saturating_add(1);
+            self.record_edit();
+        }
+    }
+    /// Converts the selection the command was opened with, or the word under the
+    /// cursor, to upper, lower or title case.
+    fn change_case(&mut self, case: &str) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let range = self.command_selection.clone().or_else(|| {
+            let y = self.cursor_position.y;
+            let word = self.document.row(y)?.word_range(self.cursor_position.x)?;
+            Some((Position { x: word.start, y }, Position { x: word.end, y }))
+        });
+        let Some((start, end)) = range else {
+            self.status_message =
+                StatusMessage::from("Nothing selected and no word under the cursor.".to_owned());
+            return;
+        };
+        self.reselect(self.command_selection.clone());
+        let rows = start.y..end.y.saturating_add(1);
+        self.edit_rows(rows.clone(), |document| {
+            match case {
+                "upper" => document.transform_range(&start, &end, |c| c.to_uppercase().collect()),
+                "lower" => document.transform_range(&start, &end, |c| c.to_lowercase().collect()),
+                _ => {
+                    let mut word_start = true;
+                    document.transform_range(&start, &end, |c| {
+                        let converted = if word_start {
+                            c.to_uppercase().collect()
+                        } else {
+                            c.to_lowercase().collect()
+                        };
+                        word_start = !c.is_alphanumeric();
+                        converted
+                    });
+                }
+            }
+            Some(rows)
+        });
+        self.record_edit();
+    }
+    /// Rows touched by a selection; one ending at the start of a row doesn't include it.
+    fn selected_rows(&self, (start, end): &(Position, Position)) -> Range<usize> {
+        let end_row = if end.x == 0 && end.y > start.y {
+            end.y
+        } else {
+            end.y.saturating_add(1)
+        };
+        start.y..end_row.min(self.document.len())
+    }
+    /// Sorts the rows of the command's selection, or the whole document. `args`
+    /// may contain `n` for numeric order.
+    fn sort(&mut self, args: &str, reverse: bool) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let numeric = match args.trim() {
+            "" => false,
+            "n" => true,
+            _ => {
+                self.status_message = StatusMessage::from("Usage: :sort[!] [n]".to_owned());
+                return;
+            }
+        };
+        let rows = self
+            .command_selection
+            .as_ref()
+            .map_or(0..self.document.len(), |selection| {
+                self.selected_rows(selection)
+            });
+        if self
+            .document
+            .sort_rows(rows, SortOptions { reverse, numeric })
+        {
+            self.record_edit();
+        }
+    }
+    /// Removes repeated consecutive rows from the command's selection, or the
+    /// whole document.
+    fn uniq(&mut self) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let rows = self
+            .command_selection
+            .as_ref()
+            .map_or(0..self.document.len(), |selection| {
+                self.selected_rows(selection)
+            });
+        let end = rows.end;
+        let removed = self.document.dedup_rows(rows);
+        if self.cursor_position.y >= end {
+            self.cursor_position.y = self.cursor_position.y.saturating_sub(removed);
+        } else {
+            self.cursor_position.y = self
+                .cursor_position
+                .y
+                .min(end.saturating_sub(removed).saturating_sub(1));
+        }
+        self.clamp_cursor();
+        if removed > 0 {
+            self.record_edit();
+        }
+        self.status_message = StatusMessage::from(format!(
+            "Removed {removed} duplicate line{}.",
+            if removed == 1 { "" } else { "s" }
+        ));
+    }
+    /// Toggles line comments on the selected rows, or the cursor's row.
+    fn toggle_comment(&mut self, selection: Option<(Position, Position)>) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let Some(token) = self.document.line_comment_start().map(str::to_owned) else {
+            self.status_message =
+                StatusMessage::from("No line comment defined for this file type.".to_owned());
+            return;
+        };
+        let rows = match &selection {
+            Some(selection) => self.selected_rows(selection),
+            None => self.cursor_position.y..self.cursor_position.y.saturating_add(1),
+        };
+        let rows = rows.start..rows.end.min(self.document.len());
+        self.reselect(selection);
+        if self.edit_rows(rows.clone(), |document| {
+            document
+                .toggle_comment(rows.clone(), &token)
+                .then_some(rows)
+        }) {
+            self.record_edit();
+        }
+    }
+    /// Indents the selected rows, or the cursor's row, by one level, or unindents
+    /// them with `unindent`. The selection stays selected.
+    fn indent(&mut self, selection: Option<(Position, Position)>, unindent: bool) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let rows = match &selection {
+            Some(selection) => self.selected_rows(selection),
+            None => self.cursor_position.y..self.cursor_position.y.saturating_add(1),
+        };
+        self.reselect(selection);
+        let width = self.options.tab_width;
+        if self.edit_rows(rows.clone(), |document| {
+            document
+                .indent_rows(rows.clone(), width, unindent)
+                .then_some(rows)
+        }) {
+            self.record_edit();
+        }
+    }
+    /// Selects `selection` again, anchored at the end the cursor isn't at.
+    fn reselect(&mut self, selection: Option<(Position, Position)>) {
+        self.selection_anchor = selection.map(|(start, end)| {
+            if start == self.cursor_position {
+                end
+            } else {
+                start
+            }
+        });
+    }
+    /// Rewrites `rows` of the document with `edit`, which returns the rows their
+    /// text takes afterwards, or None when nothing changed. The cursor and the
+    /// selection anchor move with their text; see `remap_position`.
+    fn edit_rows(
+        &mut self,
+        rows: Range<usize>,
+        edit: impl FnOnce(&mut Document) -> Option<Range<usize>>,
+    ) -> bool {
+        let row_len = |position: &Position| self.document.row(position.y).map_or(0, Row::len);
+        let cursor_len = row_len(&self.cursor_position);
+        let anchor_len = self.selection_anchor.as_ref().map_or(0, row_len);
+        let Some(new_rows) = edit(&mut self.document) else {
+            return false;
+        };
+        self.cursor_position = remap_position(
+            &self.cursor_position,
+            cursor_len,
+            &rows,
+            &new_rows,
+            &self.document,
+        );
+        self.selection_anchor = self
+            .selection_anchor
+            .as_ref()
+            .map(|anchor| remap_position(anchor, anchor_len, &rows, &new_rows, &self.document));
+        true
+    }
+    /// Starts recording keypresses into `register`, or stops the recording in
+    /// progress. The keys that typed the stopping `:rec` are not kept.
+    fn toggle_recording(&mut self, register: &str) {
+        if let Some((name, mut keys)) = self.recording.take() {
+            let command_start = keys.windows(3).rposition(|window| {
+                matches!(
+                    window,
+                    [
+                        (KeyCode::Char('i'), _),
+                        (KeyCode::Char('.'), _),
+                        (KeyCode::Char(':'), _)
+                    ]
+                )
+            });
+            keys.truncate(command_start.unwrap_or(keys.len()));
+            self.status_message =
+                StatusMessage::from(format!("Recorded {} keys into {name}", keys.len()));
+            self.macros.insert(name, keys);
+            return;
+        }
+        let mut chars = register.chars();
+        match (chars.next(), chars.next()) {
+            (Some(name), None) if name.is_ascii_alphabetic() => {
+                self.recording = Some((name, Vec::new()));
+                self.status_message =
+                    StatusMessage::from(format!("Recording into {name} - :rec to stop"));
+            }
+            _ => {
+                self.status_message = StatusMessage::from("Usage: :rec <a-z>".to_owned());
+            }
+        }
+    }
+    /// Replays the keys recorded into a register, given as `[count]<a-z>`.
+    fn play_macro(&mut self, args: &str) {
+        let Some(name) = args.chars().last() else {
+            self.status_message = StatusMessage::from("Usage: :@[count]<a-z>".to_owned());
+            return;
+        };
+        let count = match args.strip_suffix(name).unwrap_or_default() {
+            "" => Some(1),
+            digits => digits.parse::<usize>().ok().filter(|count| *count > 0),
+        };
+        let Some(count) = count.map(|count| count.min(MAX_COUNT)) else {
+            self.status_message = StatusMessage::from(format!("Invalid count: {args}"));
+            return;
+        };
+        if self.playing_macro {
+            self.status_message =
+                StatusMessage::from("A macro cannot play another macro".to_owned());
+            return;
+        }
+        let Some(keys) = self.macros.get(&name).cloned() else {
+            self.status_message = StatusMessage::from(format!("Register {name} is empty"));
+            return;
+        };
+        self.playing_macro = true;
+        for _ in 0..count {
+            for &(key, modifiers) in &keys {
+                self.handle_key(key, modifiers);
+            }
+        }
+        self.playing_macro = false;
+    }
+    /// Scrolls so the cursor's row is centred (`zz`), at the top (`zt`) or at the
+    /// bottom (`zb`) of the view, without moving the cursor.
+    #[expect(clippy::integer_division)]
+    fn reposition_view(&mut self, position: &str) {
+        let y = self.cursor_position.y;
+        let height = self.text_height();
+        self.offset.y = match position {
+            "zt" => y,
+            "zb" => y.saturating_add(1).saturating_sub(height),
+            _ => y.saturating_sub(height / 2),
+        };
+    }
+    /// Folds the command's selected rows into one line, or closes the fold at the
+    /// cursor again.
+    fn fold(&mut self) {
+        let y = self.cursor_position.y;
+        let start = if let Some(selection) = &self.command_selection {
+            let rows = self.selected_rows(selection);
+            if rows.len() < 2 {
+                self.status_message =
+                    StatusMessage::from("Select at least two lines to fold.".to_owned());
+                return;
+            }
+            // Earlier edits are already reflected in `rows`
+            self.document.take_row_edits();
+            self.folds
+                .retain(|&(start, end, _)| end <= rows.start || start >= rows.end);
+            self.folds.push((rows.start, rows.end, true));
+            rows.start
+        } else if let Some(fold) = self
+            .folds
+            .iter_mut()
+            .find(|&&mut (start, end, _)| (start..end).contains(&y))
+        {
+            fold.2 = true;
+            fold.0
+        } else {
+            self.status_message =
+                StatusMessage::from("No fold here. Select the lines to fold.".to_owned());
+            return;
+        };
+        self.cursor_position = Position { x: 0, y: start };
+    }
+    fn unfold_all(&mut self) {
+        for fold in &mut self.folds {
+            fold.2 = false;
+        }
+    }
+    /// Opens the fold containing row `y`.
+    fn open_fold(&mut self, y: usize) {
+        for fold in &mut self.folds {
+            if (fold.0..fold.1).contains(&y) {
+                fold.2 = false;
+            }
+        }
+    }
+    /// The rows of the closed fold containing row `y`.
+    fn closed_fold(&self, y: usize) -> Option<Range<usize>> {
+        self.folds
+            .iter()
+            .find(|&&(start, end, folded)| folded && (start..end).contains(&y))
+            .map(|&(start, end, _)| start..end)
+    }
+    /// Moves the cursor out of the inside of a closed fold: past it when moving
+    /// `down`, otherwise onto its first line.
+    fn skip_fold(&mut self, down: bool) {
+        let y = self.cursor_position.y;
+        let Some(rows) = self.closed_fold(y) else {
+            return;
+        };
+        if down && y > rows.start {
+            self.cursor_position.y = rows.end;
+            self.clamp_cursor();
+        } else {
+            self.cursor_position = Position {
+                x: 0,
+                y: rows.start,
+            };
+        }
+    }
+    /// Rewraps the paragraph under the cursor to `width` columns.
+    fn reflow(&mut self, width: usize) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let y = self.cursor_position.y;
+        if let Some(rows) = self.document.paragraph(y) {
+            self.edit_rows(rows, |document| document.reflow_paragraph(y, width));
+            self.record_edit();
+        } else {
+            self.status_message = StatusMessage::from("No paragraph to reflow.".to_owned());
+        }
+    }
+    fn join_lines(&mut self) {
+        if self.refuse_read_only() {
+            return;
+        }
+        if let Some(x) = self.document.join_rows(self.cursor_position.y) {
+            self.cursor_position.x = x;
+            self.record_action(Action::Join);
+            self.record_edit();
+        } else {
+            self.status_message = StatusMessage::from("No line below to join.".to_owned());
+        }
+    }
+    fn jump_to_matching_bracket(&mut self) {
+        if let Some(position) = self.document.find_matching_bracket(&self.cursor_position) {
+            self.matched_brackets = Some([self.cursor_position.clone(), position.clone()]);
+            self.push_jump(self.cursor_position.clone());
+            self.cursor_position = position;
+        } else {
+            self.status_message = StatusMessage::from("No matching bracket found.".to_owned());
+        }
+    }
+    /// Records `position` as a place to come back to with Ctrl-O, dropping any
+    /// entries that Ctrl-O had already stepped back past.
+    fn push_jump(&mut self, position: Position) {
+        self.jump_list.truncate(self.jump_index);
+        if self.jump_list.last() != Some(&position) {
+            self.jump_list.push(position);
+        }
+        if self.jump_list.len() > JUMP_LIST_LIMIT {
+            self.jump_list.remove(0);
+        }
+        self.jump_index = self.jump_list.len();
+    }
+    fn jump_back(&mut self) {
+        if self.jump_index >= self.jump_list.len() {
+            // Remember where we left from so Alt-I can return to it
+            let current = self.cursor_position.clone();
+            self.push_jump(current);
+            self.jump_index = self.jump_list.len().saturating_sub(1);
+        }
+        if self.jump_index == 0 {
+            self.status_message = StatusMessage::from("Already at oldest jump.".to_owned());
+            return;
+        }
+        self.jump_index = self.jump_index.saturating_sub(1);
+        self.go_to_jump();
+    }
+    fn jump_forward(&mut self) {
+        if self.jump_index.saturating_add(1) >= self.jump_list.len() {
+            self.status_message = StatusMessage::from("Already at newest jump.".to_owned());
+            return;
+        }
+        self.jump_index = self.jump_index.saturating_add(1);
+        self.go_to_jump();
+    }
+    fn go_to_jump(&mut self) {
+        if let Some(position) = self.jump_list.get(self.jump_index) {
+            self.cursor_position = position.clone();
+            self.clamp_cursor();
+        }
+    }
+    /// Records the cursor position under the single letter `name`.
+    fn set_mark(&mut self, name: &str) {
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(letter), None) if letter.is_ascii_alphabetic() => {
+                self.marks.insert(letter, self.cursor_position.clone());
+                self.status_message = StatusMessage::from(format!("Mark {letter} set."));
+            }
+            _ => {
+                self.status_message =
+                    StatusMessage::from("Mark name must be a single letter.".to_owned());
+            }
+        }
+    }
+    fn jump_to_mark(&mut self, name: &str) {
+        let mut chars = name.chars();
+        let mark = match (chars.next(), chars.next()) {
+            (Some(letter), None) => self.marks.get(&letter).cloned(),
+            _ => None,
+        };
+        if let Some(position) = mark {
+            self.push_jump(self.cursor_position.clone());
+            self.cursor_position = position;
+            self.clamp_cursor();
+        } else {
+            self.status_message = StatusMessage::from(format!("Mark not set: {name}"));
+        }
+    }
+    /// Counts an edit, clears search highlights, and writes the swap file every
+    /// `SWAP_INTERVAL` edits. `tick` writes it too, once editing pauses.
+    fn record_edit(&mut self) {
+        if self.highlighted_word.is_some() {
+            self.end_search();
+        }
+        for (edited, count) in self.document.take_row_edits() {
+            self.folds = self
+                .folds
+                .iter()
+                .filter_map(|&(start, end, folded)| {
+                    let rows = remap_rows(&(start..end), &edited, count)?;
+                    Some((rows.start, rows.end, folded))
+                })
+                .collect();
+        }
+        // Keep the edited line visible
+        self.open_fold(self.cursor_position.y);
+        self.last_edit = Instant::now();
+        self.edits_since_swap = self.edits_since_swap.saturating_add(1);
+        if self.edits_since_swap >= SWAP_INTERVAL {
+            self.write_swap();
+        }
+    }
+    fn write_swap(&mut self) {
+        self.edits_since_swap = 0;
+        if let Err(error) = self.document.write_swap() {
+            self.status_message = StatusMessage::from(format!(
+                "Error writing swap file: {}",
+                os_error_message(&error)
+            ));
+        }
+    }
+    /// Display column of the cursor within its row.
+    fn cursor_column(&self) -> usize {
+        self.document
+            .row(self.cursor_position.y)
+            .map_or(0, |row| row.width_until(self.cursor_position.x))
+    }
+    fn scroll(&mut self) {
+        self.update_layout();
+        // Searches and jumps can land inside a fold
+        let Position { x, y } = self.cursor_position;
+        if self
+            .closed_fold(y)
+            .is_some_and(|rows| rows.start != y || x != 0)
+        {
+            self.open_fold(y);
+        }
+        if self.options.wrap {
+            self.scroll_wrapped();
+            return;
+        }
+        let x = self.cursor_column();
+        let width = self.text_width();
+        let height = self.text_height();
+        let (above, below) = self.scroll_margins(height);
+        let lines = self.screen_lines(self.offset.y, y);
+        if y < self.offset.y || lines < above {
+            self.offset.y = self.rows_up(y, above);
+        } else if lines.saturating_add(below) >= height {
+            self.offset.y = self.rows_up(y, height.saturating_sub(1).saturating_sub(below));
+        }
+        let offset = &mut self.offset;
+        if x < offset.x {
+            offset.x = x;
+        } else if x >= offset.x.saturating_add(width) {
+            offset.x = x.saturating_sub(width).saturating_add(1);
+        }
+    }
+    /// Advances the top row until the cursor's visual line fits on screen.
+    fn scroll_wrapped(&mut self) {
+        let height = self.text_height();
+        let (above, below) = self.scroll_margins(height);
+        let y = self.cursor_position.y;
+        self.offset.x = 0;
+        if y < self.offset.y || self.screen_lines(self.offset.y, y) < above {
+            self.offset.y = self.rows_up(y, above);
+        }
+        while self.offset.y < y && self.cursor_screen_position().y.saturating_add(below) >= height {
+            self.offset.y = self
+                .closed_fold(self.offset.y)
+                .map_or(self.offset.y.saturating_add(1), |rows| rows.end);
+        }
+    }
+    /// Screen lines taken by rows `from..to`; a closed fold takes one.
+    fn screen_lines(&self, from: usize, to: usize) -> usize {
+        let mut lines: usize = 0;
+        let mut y = from;
+        while y < to {
+            if let Some(rows) = self.closed_fold(y) {
+                lines = lines.saturating_add(1);
+                y = rows.end;
+            } else {
+                lines = lines.saturating_add(self.wrap_points(y).len());
+                y = y.saturating_add(1);
+            }
+        }
+        lines
+    }
+    /// The row `count` rows above row `y`, counting a closed fold as one row.
+    fn rows_up(&self, y: usize, count: usize) -> usize {
+        (0..count).fold(y, |y, _| {
+            let above = y.saturating_sub(1);
+            self.closed_fold(above).map_or(above, |rows| rows.start)
+        })
+    }
+    /// Lines to keep above and below the cursor: `scrolloff`, limited to half
+    /// the screen and to the lines that exist on each side.
+    fn scroll_margins(&self, height: usize) -> (usize, usize) {
+        let margin = self.options.scroll_off.min(height.saturating_sub(1) / 2);
+        let y = self.cursor_position.y;
+        (
+            margin.min(y),
+            margin.min(self.document.len().saturating_sub(y)),
+        )
+    }
+    /// Columns available for text in the active pane.
+    fn text_width(&self) -> usize {
+        self.text_width_of(&self.document)
+    }
+    /// Columns available for text of `document`, leaving the gutter and the last
+    /// column to the scrollbar.
+    fn text_width_of(&self, document: &Document) -> usize {
+        let width =
+            (self.terminal.size().width as usize).saturating_sub(self.gutter_width(document));
+        if self.options.scrollbar {
+            width.saturating_sub(1)
+        } else {
+            width
+        }
+    }
+    /// Columns taken by line numbers: enough digits for the last line, at least
+    /// three, and a space.
+    fn gutter_width(&self, document: &Document) -> usize {
+        if !self.options.number && !self.options.relative_number {
+            return 0;
+        }
+        document
+            .len()
+            .max(1)
+            .to_string()
+            .len()
+            .max(3)
+            .saturating_add(1)
+    }
+    /// The gutter of row `y` when the cursor is on row `cursor_y`: its distance from
+    /// the cursor line with `relativenumber`, except that line itself, which shows
+    /// its number when `number` is also set.
+    fn line_number(&self, y: usize, cursor_y: usize, gutter: usize) -> String {
+        let number = if self.options.relative_number && !(self.options.number && y == cursor_y) {
+            y.abs_diff(cursor_y)
+        } else {
+            y.saturating_add(1)
+        };
+        format!("{number:>width$} ", width = gutter.saturating_sub(1))
+    }
+    /// Rows available for text in the active pane.
+    fn text_height(&self) -> usize {
+        match &self.split {
+            Some(pane) => self.pane_height(!pane.top),
+            None => self.terminal.size().height as usize,
+        }
+    }
+    /// Screen row where the active pane starts.
+    fn text_top(&self) -> usize {
+        let pane_top = match &self.split {
+            Some(pane) if pane.top => self.pane_height(true).saturating_add(1),
+            _ => 0,
+        };
+        pane_top.saturating_add(self.tab_bar_rows())
+    }
+    /// Screen row of the message bar, below the text and the status bar.
+    fn message_bar_row(&self) -> usize {
+        (self.terminal.size().height as usize)
+            .saturating_add(self.tab_bar_rows())
+            .saturating_add(1)
+    }
+    /// Rows taken by the buffer list at the top: one with `showtabs` and more than
+    /// one buffer open.
+    fn tab_bar_rows(&self) -> usize {
+        usize::from(self.options.showtabs && self.buffers.len() > 1)
+    }
+    /// Fits the text area to the rows the bars leave.
+    fn update_layout(&mut self) {
+        let rows = u16::try_from(self.tab_bar_rows()).unwrap_or(0);
+        self.terminal.set_extra_bar_rows(rows);
+    }
+    /// Height of the top or bottom pane of a split; one row divides them.
+    #[expect(clippy::integer_division)]
+    fn pane_height(&self, top: bool) -> usize {
+        let height = self.terminal.size().height as usize;
+        let top_height = height.saturating_sub(1) / 2;
+        if top {
+            top_height
+        } else {
+            height.saturating_sub(1).saturating_sub(top_height)
+        }
+    }
+    /// Grapheme indices where the visual lines of row `y` start (just `[0]` without wrap).
+    fn wrap_points(&self, y: usize) -> Vec<usize> {
+        match self.document.row(y) {
+            Some(row) if self.options.wrap => row.wrap_points(self.text_width()),
+            _ => vec![0],
+        }
+    }
+    /// Where the cursor is drawn, relative to the top-left of the text area.
+    fn cursor_screen_position(&self) -> Position {
+        self.screen_position(&self.cursor_position)
+    }
+    /// Where `position` is drawn, relative to the top-left of the text area.
+    fn screen_position(&self, position: &Position) -> Position {
+        if !self.options.wrap {
+            let column = self
+                .document
+                .row(position.y)
+                .map_or(0, |row| row.width_until(position.x));
+            return Position {
+                x: column.saturating_sub(self.offset.x),
+                y: self.screen_lines(self.offset.y, position.y),
+            };
+        }
+        let Position { x, y } = *position;
+        let rows_above = self.screen_lines(self.offset.y, y);
+        let points = self.wrap_points(y);
+        let segment = points.iter().rposition(|&start| start <= x).unwrap_or(0);
+        let segment_start = points.get(segment).copied().unwrap_or(0);
+        let column = self.document.row(y).map_or(0, |row| {
+            row.width_until(x)
+                .saturating_sub(row.width_until(segment_start))
+        });
+        Position {
+            x: column.min(self.text_width().saturating_sub(1)),
+            y: rows_above.saturating_add(segment),
+        }
+    }
+    /// Moves the cursor one visual line up or down, keeping its column within the line.
+    fn move_visual_line(&mut self, up: bool) {
+        // A closed fold is one visual line
+        if let Some(rows) = self.closed_fold(self.cursor_position.y).filter(|_| !up) {
+            self.cursor_position = Position {
+                x: 0,
+                y: rows.end.min(self.document.len()),
+            };
+            return;
         }
+        let Position { x, y } = self.cursor_position;
+        let points = self.wrap_points(y);
+        let segment = points.iter().rposition(|&start| start <= x).unwrap_or(0);
+        let column = self.document.row(y).map_or(0, |row| {
+            let segment_start = points.get(segment).copied().unwrap_or(0);
+            row.width_until(x)
+                .saturating_sub(row.width_until(segment_start))
+        });
+        let (new_y, new_segment) = if up {
+            if segment > 0 {
+                (y, segment.saturating_sub(1))
+            } else if y > 0 {
+                let above = y.saturating_sub(1);
+                (above, self.wrap_points(above).len().saturating_sub(1))
+            } else {
+                return;
+            }
+        } else if segment.saturating_add(1) < points.len() {
+            (y, segment.saturating_add(1))
+        } else if y < self.document.len() {
+            (y.saturating_add(1), 0)
+        } else {
+            return;
+        };
+        let new_points = self.wrap_points(new_y);
+        let new_x = self.document.row(new_y).map_or(0, |row| {
+            let start = new_points.get(new_segment).copied().unwrap_or(0);
+            let index = row.index_at_width(row.width_until(start).saturating_add(column));
+            // Stay on this visual line rather than landing on the next one's first grapheme
+            match new_points.get(new_segment.saturating_add(1)) {
+                Some(&next_start) => index.min(next_start.saturating_sub(1)),
+                None => index,
+            }
+        });
+        self.cursor_position = Position { x: new_x, y: new_y };
     }
     fn move_cursor(&mut self, key: KeyCode) {
-        let terminal_height = self.terminal.size().height as usize;
+        // Horizontal moves open a fold rather than leave it
+        if matches!(
+            key,
+            KeyCode::Left | KeyCode::Right | KeyCode::Home | KeyCode::End
+        ) && self.closed_fold(self.cursor_position.y).is_some()
+        {
+            self.open_fold(self.cursor_position.y);
+            return;
+        }
+        if self.options.wrap && matches!(key, KeyCode::Up | KeyCode::Down) {
+            self.move_visual_line(key == KeyCode::Up);
+            self.skip_fold(key == KeyCode::Down);
+            return;
+        }
+        let terminal_height = self.text_height();
         let Position { mut y, mut x } = self.cursor_position;
+        // Rows run up to `height`, the line past the last row, where typing
+        // appends a row. It is empty, so its width is 0
         let height = self.document.len();
-        let mut width = if let Some(row) = self.document.row(y) {
-            row.len()
-        } else {
-            0
-        };
+        let row_len = |y: usize| self.document.row(y).map_or(0, Row::len);
+        let width = row_len(y);
         match key {
             KeyCode::Up => y = y.saturating_sub(1),
-            KeyCode::Down => {
-                if y < height {
-                    y = y.saturating_add(1);
-                }
-            }
+            KeyCode::Down if y < height => y = y.saturating_add(1),
             KeyCode::Left => {
                 if x > 0 {
                     x -= 1;
                 } else if y > 0 {
                     y -= 1;
-                    if let Some(row) = self.document.row(y) {
-                        x = row.len();
-                    } else {
-                        x = 0;
-                    }
+                    x = row_len(y);
                 }
             }
             KeyCode::Right => {
@@ -402,108 +3953,1405 @@ impl Editor {
                     height
                 }
             }
-            KeyCode::Home => x = 0,
+            KeyCode::Home => {
+                // First non-blank, then column 0 when already there
+                let indent = self.document.row(y).map_or(0, |row| {
+                    row.as_str()
+                        .chars()
+                        .take_while(|c| c.is_whitespace())
+                        .count()
+                });
+                x = if x == indent { 0 } else { indent };
+            }
             KeyCode::End => x = width,
             _ => (),
         }
-        width = if let Some(row) = self.document.row(y) {
-            row.len()
-        } else {
-            0
-        };
-        if x > width {
-            x = width;
+        // Vertical moves keep the display column rather than the grapheme index
+        if y != self.cursor_position.y
+            && matches!(
+                key,
+                KeyCode::Up | KeyCode::Down | KeyCode::PageUp | KeyCode::PageDown
+            )
+        {
+            let column = self.cursor_column();
+            x = self
+                .document
+                .row(y)
+                .map_or(0, |row| row.index_at_width(column));
         }
+        x = x.min(row_len(y));
 
-        self.cursor_position = Position { x, y }
+        self.cursor_position = Position { x, y };
+        self.skip_fold(matches!(key, KeyCode::Down | KeyCode::PageDown));
     }
-    fn draw_welcome_message(&self) {
-        let mut welcome_message = format!("wd40 -- version {VERSION}");
-        let width = self.terminal.size().width as usize;
-        let len = welcome_message.len();
-        #[expect(clippy::arithmetic_side_effects, clippy::integer_division)]
-        let padding = width.saturating_sub(len) / 2;
+    /// What an empty buffer shows: the recent files with `startscreen`, otherwise
+    /// the lines of the welcome message.
+    fn welcome_lines(&self) -> Vec<String> {
+        if self.options.start_screen && !self.recent_files.is_empty() {
+            let mut lines = vec!["Recent files:".to_owned(), String::new()];
+            lines.extend(self.recent_files.iter().cloned());
+            return lines;
+        }
+        self.options
+            .welcome_message
+            .as_ref()
+            .map(|message| message.lines().map(str::to_owned).collect())
+            .unwrap_or_default()
+    }
+    /// Draws `line` of the welcome screen, which is moved right by `padding`
+    /// columns to center the widest line.
+    fn draw_welcome_line(&self, line: &str, padding: usize) -> Result<(), std::io::Error> {
         let spaces = " ".repeat(padding.saturating_sub(1));
-        welcome_message = format!("~{spaces}{welcome_message}");
-        welcome_message.truncate(width);
-        println!("{welcome_message}\r");
+        let mut line = format!("~{spaces}{line}");
+        truncate_to_width(&mut line, self.terminal.size().width as usize);
+        self.terminal.print_line(&line)
     }
-    pub fn draw_row(&self, row: &Row) {
-        let width = self.terminal.size().width as usize;
-        let start = self.offset.x;
-        let end = self.offset.x.saturating_add(width);
-        let row = row.render(start, end);
-        println!("{row}\r");
-    }
-    #[expect(clippy::integer_division, clippy::arithmetic_side_effects)]
-    fn draw_rows(&self) {
-        let height = self.terminal.size().height;
-        for terminal_row in 0..height {
-            Terminal::clear_current_line();
-            if let Some(row) = self
+    /// The selected text as an ordered `(start, end)` pair, if anything is selected.
+    fn selection(&self) -> Option<(Position, Position)> {
+        let anchor = self.selection_anchor.clone()?;
+        let cursor = self.cursor_position.clone();
+        match (anchor.y, anchor.x).cmp(&(cursor.y, cursor.x)) {
+            Ordering::Less => Some((anchor, cursor)),
+            Ordering::Greater => Some((cursor, anchor)),
+            Ordering::Equal => None,
+        }
+    }
+    /// The selected graphemes of row `y`.
+    fn selected_range(&self, y: usize, row: &Row) -> Option<Range<usize>> {
+        if let Some((rows, columns)) = self.block() {
+            return rows.contains(&y).then(|| row.indices_in_columns(columns));
+        }
+        let (start, end) = self.selection()?;
+        if y < start.y || y > end.y {
+            return None;
+        }
+        let from = if y == start.y { start.x } else { 0 };
+        let to = if y == end.y { end.x } else { row.len() };
+        Some(from..to)
+    }
+    /// Draws each extra cursor in view as a reverse-video cell.
+    fn draw_extra_cursors(&self) -> Result<(), std::io::Error> {
+        let height = self.text_height();
+        let width = self.text_width();
+        for cursor in &self.extra_cursors {
+            if cursor.y < self.offset.y || self.closed_fold(cursor.y).is_some() {
+                continue;
+            }
+            let column = self
                 .document
-                .row(self.offset.y.saturating_add(terminal_row as usize))
+                .row(cursor.y)
+                .map_or(0, |row| row.width_until(cursor.x));
+            let mut position = self.screen_position(cursor);
+            if position.y >= height
+                || position.x >= width
+                || (!self.options.wrap && column < self.offset.x)
+            {
+                continue;
+            }
+            let under = self
+                .document
+                .row(cursor.y)
+                .and_then(|row| row.char_at(cursor.x))
+                .filter(|c| !c.is_control())
+                .unwrap_or(' ');
+            position.x = position.x.saturating_add(self.gutter_width(&self.document));
+            position.y = position.y.saturating_add(self.text_top());
+            self.terminal.cursor_position(&position)?;
+            self.terminal.print(&format!(
+                "{}{under}{}",
+                SetAttribute(Attribute::Reverse),
+                SetAttribute(Attribute::NoReverse)
+            ))?;
+        }
+        Ok(())
+    }
+    /// Draws the columns `start..end` of `row` after `gutter`. Without wrapping, a `<`
+    /// or `>` in the first or last column marks text scrolled off that side.
+    pub fn draw_row(
+        &self,
+        row: &Row,
+        gutter: &str,
+        start: usize,
+        end: usize,
+        selected: Option<Range<usize>>,
+    ) -> Result<(), std::io::Error> {
+        if self.options.wrap {
+            let row = row.render_with(start, end, selected, self.options.render);
+            self.terminal.print_line(&format!("{gutter}{row}"))?;
+            return Ok(());
+        }
+        let clipped_left = start > 0 && end > start;
+        let clipped_right = row.width() > end && end > start;
+        let text = row.render_with(
+            if clipped_left {
+                start.saturating_add(1)
+            } else {
+                start
+            },
+            if clipped_right {
+                end.saturating_sub(1)
+            } else {
+                end
+            },
+            selected,
+            self.options.render,
+        );
+        let left = if clipped_left { "<" } else { "" };
+        let right = if clipped_right {
+            let column = end
+                .saturating_sub(start)
+                .saturating_sub(1)
+                .saturating_add(gutter.width());
+            format!(
+                "{}>",
+                MoveToColumn(u16::try_from(column).unwrap_or(u16::MAX))
+            )
+        } else {
+            String::new()
+        };
+        self.terminal
+            .print_line(&format!("{gutter}{left}{text}{right}"))?;
+        Ok(())
+    }
+    fn draw_rows(&self) -> Result<(), std::io::Error> {
+        let Some(pane) = &self.split else {
+            let height = self.terminal.size().height as usize;
+            self.draw_document(
+                &self.document,
+                &self.offset,
+                self.cursor_position.y,
+                height,
+                true,
+            )?;
+            self.draw_scrollbar(&self.document, &self.offset, self.tab_bar_rows(), height)?;
+            return Ok(());
+        };
+        let document = if pane.buffer == self.current_buffer {
+            &self.document
+        } else if let Some(buffer) = self.buffers.get(pane.buffer) {
+            &buffer.document
+        } else {
+            return Ok(());
+        };
+        let active = (&self.document, &self.offset, self.cursor_position.y);
+        let inactive = (document, &pane.offset, pane.cursor_position.y);
+        let (top, bottom) = if pane.top {
+            (inactive, active)
+        } else {
+            (active, inactive)
+        };
+        let top_height = self.pane_height(true);
+        let bottom_height = self.pane_height(false);
+        self.draw_document(top.0, top.1, top.2, top_height, !pane.top)?;
+        self.draw_pane_divider(top.0)?;
+        self.draw_document(bottom.0, bottom.1, bottom.2, bottom_height, pane.top)?;
+        let tab_bar_rows = self.tab_bar_rows();
+        self.draw_scrollbar(top.0, top.1, tab_bar_rows, top_height)?;
+        self.draw_scrollbar(
+            bottom.0,
+            bottom.1,
+            tab_bar_rows.saturating_add(top_height).saturating_add(1),
+            bottom_height,
+        )?;
+        Ok(())
+    }
+    /// Draws the scrollbar in the last column of the `height` rows starting at screen
+    /// row `top`, then returns the cursor to the row below them.
+    fn draw_scrollbar(
+        &self,
+        document: &Document,
+        offset: &Position,
+        top: usize,
+        height: usize,
+    ) -> Result<(), std::io::Error> {
+        if !self.options.scrollbar {
+            return Ok(());
+        }
+        let x = (self.terminal.size().width as usize).saturating_sub(1);
+        let len = document.len().max(height).max(1);
+        let thumb_len = height
+            .saturating_mul(height)
+            .checked_div(len)
+            .unwrap_or(0)
+            .max(1);
+        let thumb_start = offset
+            .y
+            .saturating_mul(height)
+            .checked_div(len)
+            .unwrap_or(0)
+            .min(height.saturating_sub(thumb_len));
+        for row in 0..height {
+            self.terminal.cursor_position(&Position {
+                x,
+                y: top.saturating_add(row),
+            })?;
+            if (thumb_start..thumb_start.saturating_add(thumb_len)).contains(&row) {
+                self.terminal.print("\u{2588}")?;
+            } else {
+                self.terminal.print("\u{2502}")?;
+            }
+        }
+        self.terminal.cursor_position(&Position {
+            x: 0,
+            y: top.saturating_add(height),
+        })?;
+        Ok(())
+    }
+    /// Draws `height` rows of `document` from `offset`, numbered relative to the
+    /// cursor row `cursor_y`; only the active pane shows the selection.
+    #[expect(clippy::integer_division)]
+    fn draw_document(
+        &self,
+        document: &Document,
+        offset: &Position,
+        cursor_y: usize,
+        height: usize,
+        active: bool,
+    ) -> Result<(), std::io::Error> {
+        let width = self.text_width_of(document);
+        let gutter = self.gutter_width(document);
+        let welcome = if document.is_empty() {
+            self.welcome_lines()
+        } else {
+            Vec::new()
+        };
+        let welcome_width = welcome.iter().map(|line| line.width()).max().unwrap_or(0);
+        let welcome_padding =
+            (self.terminal.size().width as usize).saturating_sub(welcome_width) / 2;
+        // A single line goes a third of the way down, and longer messages around it
+        let welcome_top = (height / 3)
+            .saturating_sub(welcome.len() / 2)
+            .min(height.saturating_sub(welcome.len()));
+        let mut y = offset.y;
+        let mut terminal_row = 0;
+        while terminal_row < height {
+            if let Some(rows) = self.closed_fold(y).filter(|_| active) {
+                let number = if gutter > 0 {
+                    self.line_number(y, cursor_y, gutter)
+                } else {
+                    String::new()
+                };
+                let mut line = format!("{number}+-- {} lines folded --", rows.len());
+                truncate_to_width(&mut line, width.saturating_add(gutter));
+                self.terminal.clear_current_line()?;
+                self.terminal.print_line(&line)?;
+                terminal_row = terminal_row.saturating_add(1);
+                y = rows.end;
+                continue;
+            }
+            if let Some(row) = document.row(y) {
+                let selected = if active {
+                    self.selected_range(y, row)
+                } else {
+                    None
+                };
+                let number = if gutter > 0 {
+                    self.line_number(y, cursor_y, gutter)
+                } else {
+                    String::new()
+                };
+                if self.options.wrap {
+                    let points = row.wrap_points(width);
+                    for (segment, &start) in points.iter().enumerate() {
+                        if terminal_row >= height {
+                            break;
+                        }
+                        let end = points.get(segment.saturating_add(1)).map_or_else(
+                            // Room for the `$` of `:set list` after the last segment
+                            || row.width_until(start).saturating_add(width),
+                            |&next| row.width_until(next),
+                        );
+                        self.terminal.clear_current_line()?;
+                        // Only the first visual line of a row is numbered
+                        let gutter = if segment == 0 {
+                            number.clone()
+                        } else {
+                            " ".repeat(gutter)
+                        };
+                        self.draw_row(row, &gutter, row.width_until(start), end, selected.clone())?;
+                        terminal_row = terminal_row.saturating_add(1);
+                    }
+                } else {
+                    self.terminal.clear_current_line()?;
+                    let start = offset.x;
+                    self.draw_row(row, &number, start, start.saturating_add(width), selected)?;
+                    terminal_row = terminal_row.saturating_add(1);
+                }
+                y = y.saturating_add(1);
+                continue;
+            }
+            self.terminal.clear_current_line()?;
+            if let Some(line) = terminal_row
+                .checked_sub(welcome_top)
+                .and_then(|index| welcome.get(index))
             {
-                self.draw_row(row);
-            } else if self.document.is_empty() && terminal_row == height / 3 {
-                self.draw_welcome_message();
+                self.draw_welcome_line(line, welcome_padding)?;
+            } else {
+                self.terminal.print_line("~")?;
+            }
+            terminal_row = terminal_row.saturating_add(1);
+        }
+        Ok(())
+    }
+    /// Draws `overlay` over the whole text area, from its first scrolled-to line.
+    fn draw_overlay(&self, overlay: &Overlay) -> Result<(), std::io::Error> {
+        let height = self.terminal.size().height as usize;
+        let width = self.terminal.size().width as usize;
+        for index in overlay.scroll..overlay.scroll.saturating_add(height) {
+            let mut line = overlay
+                .lines
+                .get(index)
+                .map_or("~", String::as_str)
+                .replace('\t', "    ");
+            truncate_to_width(&mut line, width);
+            self.terminal.clear_current_line()?;
+            self.terminal.print_line(&line)?;
+        }
+        Ok(())
+    }
+    /// Lists the open buffers on the top row, highlighting the active one and
+    /// marking modified ones with `*`.
+    fn draw_tab_bar(&self) -> Result<(), std::io::Error> {
+        let width = self.terminal.size().width as usize;
+        let mut used = 0_usize;
+        let mut bar = String::new();
+        for (index, buffer) in self.buffers.iter().enumerate() {
+            let document = if index == self.current_buffer {
+                &self.document
+            } else {
+                &buffer.document
+            };
+            let name = document.file_name.as_deref().map_or("[No Name]", |name| {
+                Path::new(name)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or(name)
+            });
+            let mut tab = format!(
+                " {}:{name}{} ",
+                index.saturating_add(1),
+                if document.is_dirty() { "*" } else { "" }
+            );
+            truncate_to_width(&mut tab, width.saturating_sub(used));
+            used = used.saturating_add(tab.width());
+            if index == self.current_buffer {
+                bar.push_str(&format!(
+                    "{}{tab}{}",
+                    SetAttribute(Attribute::Reverse),
+                    SetAttribute(Attribute::NoReverse)
+                ));
             } else {
-                println!("~\r");
+                bar.push_str(&tab);
             }
         }
+        bar.push_str(&" ".repeat(width.saturating_sub(used)));
+        self.terminal.clear_current_line()?;
+        self.print_bar(&bar)
+    }
+    /// Prints `text` as a row in the status bar colors, or plainly when colors are off.
+    fn print_bar(&self, text: &str) -> Result<(), std::io::Error> {
+        let color_mode = self.options.render.color_mode;
+        let set_colors = |fg, bg| -> Result<(), std::io::Error> {
+            if let Some(bg) = color_mode.apply(bg) {
+                self.terminal.set_bg_color(bg)?;
+            }
+            if let Some(fg) = color_mode.apply(fg) {
+                self.terminal.set_fg_color(fg)?;
+            }
+            Ok(())
+        };
+        set_colors(self.options.theme.status_fg, self.options.theme.status_bg)?;
+        self.terminal.print_line(text)?;
+        set_colors(Color::Reset, Color::Reset)
+    }
+    /// Names the top pane's file on the row separating the two panes.
+    fn draw_pane_divider(&self, document: &Document) -> Result<(), std::io::Error> {
+        let width = self.terminal.size().width as usize;
+        let mut divider = format!(
+            "{}{}",
+            document.file_name.as_deref().unwrap_or("[No Name]"),
+            if document.is_dirty() {
+                " (modified)"
+            } else {
+                ""
+            }
+        );
+        divider.push_str(&" ".repeat(width.saturating_sub(divider.width())));
+        truncate_to_width(&mut divider, width);
+        self.terminal.clear_current_line()?;
+        self.print_bar(&divider)
     }
-    fn draw_status_bar(&self) {
+    fn draw_status_bar(&self) -> Result<(), std::io::Error> {
         let mut status;
         let width = self.terminal.size().width as usize;
+        if let Some(completion) = &self.completion {
+            let mut candidates = completion.matches.join("  ");
+            candidates.push_str(&" ".repeat(width.saturating_sub(candidates.width())));
+            truncate_to_width(&mut candidates, width);
+            return self.print_bar(&candidates);
+        }
         let modified_indicator = if self.document.is_dirty() {
             " (modified)"
         } else {
             ""
         };
+        let read_only_indicator = if self.document.is_lossy() {
+            " [lossy]"
+        } else if self.options.read_only {
+            " [RO]"
+        } else {
+            ""
+        };
 
-        let mut path_display = "[No Name]".to_owned();
+        let mut path_display = if self.document.is_grep_results() {
+            "[grep]"
+        } else {
+            "[No Name]"
+        }
+        .to_owned();
         if let Some(name) = &self.document.file_name {
             path_display = name.clone();
         }
         status = format!(
-            "{} - {} lines{}",
+            "{} - {} lines, {} chars{}{}",
             path_display,
             self.document.len(),
-            modified_indicator
+            self.document.char_count(),
+            modified_indicator,
+            read_only_indicator
         );
 
-        let line_indicator = self.document.file_type();
+        // Ordered from least to most important; the front is dropped first when narrow
+        let mut fields = vec![
+            format!("{} bytes", self.document.byte_count()),
+            self.document.line_ending().name().to_owned(),
+            self.document.file_type(),
+        ];
+        if let Some((start, end)) = self.selection() {
+            fields.push(format!(
+                "{} selected",
+                self.document.count_between(&start, &end)
+            ));
+        }
+        if let Some((rows, columns)) = self.block() {
+            fields.push(format!("block {}x{}", rows.len(), columns.len()));
+        }
+        if let Some((name, _)) = &self.recording {
+            fields.push(format!("recording {name}"));
+        }
+        fields.push(format!(
+            "Ln {}, Col {}",
+            self.cursor_position.y.saturating_add(1),
+            self.cursor_position.x.saturating_add(1)
+        ));
+        let mut line_indicator = fields.join(" | ");
+        while fields.len() > 1 && status.len().saturating_add(line_indicator.len()) >= width {
+            fields.remove(0);
+            line_indicator = fields.join(" | ");
+        }
         #[expect(clippy::arithmetic_side_effects)]
         let len = status.len() + line_indicator.len();
         status.push_str(&" ".repeat(width.saturating_sub(len)));
         status = format!("{status}{line_indicator}");
-        status.truncate(width);
-        Terminal::set_bg_color(STATUS_BG_COLOR);
-        Terminal::set_fg_color(STATUS_FG_COLOR);
-        println!("{status}\r");
-        Terminal::reset_fg_color();
-        Terminal::reset_bg_color();
-    }
-    fn draw_message_bar(&self) {
-        Terminal::clear_current_line();
-        if let Some(ref buffer) = self.command_buffer {
+        truncate_to_width(&mut status, width);
+        self.print_bar(&status)
+    }
+    /// What the message bar shows before the command line text.
+    fn command_prompt(&self) -> String {
+        if self.pending_save_command.is_some() {
+            SAVE_AS_PROMPT.to_owned()
+        } else {
+            format!("{} :", Mode::Command.indicator())
+        }
+    }
+    fn draw_message_bar(&self) -> Result<(), std::io::Error> {
+        self.terminal.clear_current_line()?;
+        if let Some(finder) = &self.finder {
+            self.terminal
+                .print(&format!("{FINDER_PROMPT}{}", finder.query))?;
+        } else if let Some(overlay) = &self.overlay {
+            let mut message = format!(
+                "{} - Up/Down/PageUp/PageDown scroll, any other key closes",
+                overlay.title
+            );
+            truncate_to_width(&mut message, self.terminal.size().width as usize);
+            self.terminal.print(&message)?;
+        } else if let Some(search) = &self.search {
+            self.terminal
+                .print(&format!("{}{}", search.prompt(), search.query))?;
+        } else if let Some(buffer) = &self.command_buffer {
             if self.pending_save_command.is_some() {
-                print!("Save as: {}", buffer);
+                self.terminal
+                    .print(&format!("{SAVE_AS_PROMPT}{}", buffer.as_str()))?;
             } else {
-                print!(":{}", buffer);
+                self.terminal.print(&format!(
+                    "{}{}{} :{}",
+                    SetAttribute(Attribute::Bold),
+                    Mode::Command.indicator(),
+                    SetAttribute(Attribute::NormalIntensity),
+                    buffer.as_str()
+                ))?;
             }
         } else {
             let message = &self.status_message;
-            if message.time.elapsed() < Duration::new(5, 0) {
+            if message.time.elapsed() < Duration::new(5, 0) && !message.text.is_empty() {
                 let mut text = message.text.clone();
-                text.truncate(self.terminal.size().width as usize);
-                print!("{text}");
+                truncate_to_width(&mut text, self.terminal.size().width as usize);
+                self.terminal.print(&text)?;
+            } else {
+                self.terminal.print(&format!(
+                    "{}{}{}",
+                    SetAttribute(Attribute::Bold),
+                    self.mode.indicator(),
+                    SetAttribute(Attribute::NormalIntensity)
+                ))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The reason an I/O operation failed, such as "Permission denied", without
+/// the " (os error 13)" that `io::Error` adds.
+fn os_error_message(error: &std::io::Error) -> String {
+    let message = error.to_string();
+    match message.rfind(" (os error ") {
+        Some(index) if message.ends_with(')') => {
+            message.get(..index).unwrap_or_default().to_owned()
+        }
+        _ => message,
+    }
+}
+
+fn write_error_message(error: &std::io::Error) -> String {
+    format!("Error writing file: {}", os_error_message(error))
+}
+
+/// Splits a `:grep` result into its file and line number. The file ends at the
+/// first `:` followed by digits and another `:`.
+fn parse_grep_result(line: &str) -> Option<(&str, usize)> {
+    line.match_indices(':').find_map(|(index, _)| {
+        let rest = line.get(index.saturating_add(1)..)?;
+        let (number, _) = rest.split_once(':')?;
+        let number = number.parse().ok()?;
+        Some((line.get(..index)?, number))
+    })
+}
+
+/// Cuts `text` so it takes at most `width` terminal columns, never splitting a character.
+fn truncate_to_width(text: &mut String, width: usize) {
+    let mut used: usize = 0;
+    for (index, c) in text.char_indices() {
+        used = used.saturating_add(c.width().unwrap_or(0));
+        if used > width {
+            text.truncate(index);
+            return;
+        }
+    }
+}
+
+/// Entries of the directory named in `partial` (or the current one), as paths that
+/// keep `partial`'s directory part; directories end in `/`. Hidden entries are left
+/// out unless `partial`'s file part starts with a dot.
+fn path_completions(partial: &str) -> Vec<String> {
+    let (dir, file) = partial.rfind('/').map_or(("", partial), |slash| {
+        partial.split_at(slash.saturating_add(1))
+    });
+    let Ok(entries) = std::fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<String> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if name.starts_with('.') && !file.starts_with('.') {
+                return None;
+            }
+            let slash = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{dir}{name}{slash}"))
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Longest prefix shared by all of `words`.
+fn common_prefix(words: &[String]) -> &str {
+    let Some(first) = words.first() else {
+        return "";
+    };
+    let len = words.iter().skip(1).fold(first.len(), |len, word| {
+        first
+            .char_indices()
+            .zip(word.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(len.min(word.len()), |((index, _), _)| len.min(index))
+    });
+    first.get(..len).unwrap_or_default()
+}
+
+/// The character auto-inserted after typing `open`, if it starts a pair.
+fn closing_pair(open: char) -> Option<char> {
+    match open {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        '"' => Some('"'),
+        '\'' => Some('\''),
+        _ => None,
+    }
+}
+
+/// Splits a `file:line[:col]` argument into the file name and the cursor position
+/// it names, unless a file with the whole argument as its name exists.
+fn parse_file_location(arg: &str) -> (&str, Option<Position>) {
+    if std::path::Path::new(arg).exists() {
+        return (arg, None);
+    }
+    let number = |text: &str| text.parse::<usize>().ok();
+    if let Some((rest, last)) = arg.rsplit_once(':') {
+        if let Some(last) = number(last) {
+            if let Some((file_name, line)) = rest.rsplit_once(':') {
+                if let Some(line) = number(line).filter(|_| !file_name.is_empty()) {
+                    let position = Position {
+                        x: last.saturating_sub(1),
+                        y: line.saturating_sub(1),
+                    };
+                    return (file_name, Some(position));
+                }
+            }
+            if !rest.is_empty() {
+                let position = Position {
+                    x: 0,
+                    y: last.saturating_sub(1),
+                };
+                return (rest, Some(position));
             }
         }
     }
+    (arg, None)
+}
+
+/// Runs `command` with `sh -c`, feeding it `input`, and returns its standard
+/// output, or its standard error as the message when it fails.
+fn run_shell(command: &str, input: Option<&str>) -> Result<String, String> {
+    use std::io::Write as _;
+    use std::process::{Command, Stdio};
+    if command.is_empty() {
+        return Err("No command given.".to_owned());
+    }
+    let error_message = |error: std::io::Error| format!("ERR: {error}: {command}");
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(error_message)?;
+    // Written from another thread so a command that answers before reading all of
+    // its input cannot deadlock on a full pipe
+    let writer = child.stdin.take().zip(input).map(|(mut stdin, input)| {
+        let input = input.to_owned();
+        std::thread::spawn(move || stdin.write_all(input.as_bytes()))
+    });
+    let output = child.wait_with_output().map_err(error_message)?;
+    if let Some(writer) = writer {
+        // A command may exit without reading its input; that is not an error here
+        let _ = writer.join();
+    }
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "Command failed ({}): {}",
+            output.status,
+            stderr.trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Where the rows `rows` are after `edited` rows were replaced by `count` rows:
+/// moved along when the edit is before them, resized when it is within them,
+/// and None when it crosses their first or last row or leaves fewer than two.
+fn remap_rows(rows: &Range<usize>, edited: &Range<usize>, count: usize) -> Option<Range<usize>> {
+    let shift = |y: usize| {
+        y.saturating_add(edited.start)
+            .saturating_add(count)
+            .saturating_sub(edited.end)
+    };
+    let rows = if edited.end <= rows.start {
+        shift(rows.start)..shift(rows.end)
+    } else if edited.start >= rows.end {
+        rows.clone()
+    } else if rows.start <= edited.start && edited.end <= rows.end {
+        rows.start..shift(rows.end)
+    } else {
+        return None;
+    };
+    (rows.len() >= 2).then_some(rows)
+}
+
+/// Where `position`, on a row `old_len` graphemes long, belongs after the rows
+/// `old` were rewritten into the rows `new` of `document`. Positions below move
+/// with the rows. Positions inside keep their row and their distance from its
+/// end, or stay at column 0, so that text added or removed at the start of a row
+/// doesn't move them off their text; when the number of rows changed they are
+/// clamped into `new` instead.
+fn remap_position(
+    position: &Position,
+    old_len: usize,
+    old: &Range<usize>,
+    new: &Range<usize>,
+    document: &Document,
+) -> Position {
+    let Position { x, y } = *position;
+    if y < old.start {
+        return position.clone();
+    }
+    if y >= old.end {
+        return Position {
+            x,
+            y: y.saturating_add(new.end).saturating_sub(old.end),
+        };
+    }
+    let row_len = |y: usize| document.row(y).map_or(0, Row::len);
+    if old.len() == new.len() {
+        let new_len = row_len(y);
+        let x = if x == 0 {
+            0
+        } else {
+            x.saturating_add(new_len)
+                .saturating_sub(old_len)
+                .min(new_len)
+        };
+        return Position { x, y };
+    }
+    let y = y.min(new.end.saturating_sub(1)).max(new.start);
+    Position {
+        x: x.min(row_len(y)),
+        y,
+    }
 }
 
+fn is_movement_key(key: KeyCode) -> bool {
+    matches!(
+        key,
+        KeyCode::Up
+            | KeyCode::Down
+            | KeyCode::Left
+            | KeyCode::Right
+            | KeyCode::PageUp
+            | KeyCode::PageDown
+            | KeyCode::End
+            | KeyCode::Home
+    )
+}
+
+/// The panic hook restores the terminal before the error is printed.
 fn die(e: std::io::Error) {
-    Terminal::clear_screen();
     panic!("{}", e);
 }
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::TestTerminal;
+
+    /// Opens an editor with `args` on a screen of 80 by 8 cells, with a config
+    /// directory of its own so that the user's config is left out.
+    fn open(args: &[&str]) -> (Editor, TestTerminal) {
+        let config_dir = std::env::temp_dir().join("wd40_test_config");
+        let mut args: Vec<String> = args.iter().map(|&arg| arg.to_owned()).collect();
+        args.push(format!("--config-dir={}", config_dir.display()));
+        let terminal = TestTerminal::new(80, 8);
+        let editor = Editor::with_backend(Box::new(terminal.clone()), &args);
+        (editor, terminal)
+    }
+
+    /// Runs the main loop until the queued keys are handled, and draws the result.
+    /// Stops short of the cleanup done on quitting.
+    fn run_keys(editor: &mut Editor, terminal: &TestTerminal) {
+        while terminal.pending_keys() > 0 && !editor.should_quit() {
+            editor.step();
+        }
+        if !editor.should_quit() {
+            editor.step();
+        }
+    }
+
+    #[test]
+    fn test_type_and_save() {
+        let path = std::env::temp_dir().join("wd40_test_e2e_save.txt");
+        let file_name = path.to_str().unwrap();
+        fs::write(file_name, "world\n").unwrap();
+        let (mut editor, terminal) = open(&[file_name]);
+        terminal.type_text("hello i.:wq\n");
+        run_keys(&mut editor, &terminal);
+        assert!(editor.should_quit());
+        assert_eq!(fs::read_to_string(file_name).unwrap(), "hello world\n");
+        let _ = fs::remove_file(file_name);
+    }
+
+    #[test]
+    fn test_save_deleted_file() {
+        let path = std::env::temp_dir().join("wd40_test_e2e_deleted.txt");
+        let file_name = path.to_str().unwrap();
+        fs::write(file_name, "world\n").unwrap();
+        let (mut editor, terminal) = open(&[file_name]);
+        fs::remove_file(file_name).unwrap();
+        assert!(editor.document().deleted_on_disk());
+        assert!(!editor.document().changed_on_disk());
+        terminal.type_text("hello i.:w\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(fs::read_to_string(file_name).unwrap(), "hello world\n");
+        assert!(!editor.document().deleted_on_disk());
+        let _ = fs::remove_file(file_name);
+    }
+
+    #[test]
+    fn test_positions_only_stored_for_saved_files() {
+        let path = std::env::temp_dir().join("wd40_test_e2e_positions.txt");
+        let file_name = path.to_str().unwrap();
+        fs::write(file_name, "world\n").unwrap();
+        let (mut editor, terminal) = open(&[file_name]);
+        terminal.type_text("hello ");
+        run_keys(&mut editor, &terminal);
+        assert!(editor.positions_to_store().is_empty());
+        terminal.type_text("i.:w\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(
+            editor.positions_to_store(),
+            vec![(file_name, Position { x: 6, y: 0 })]
+        );
+        let _ = fs::remove_file(file_name);
+    }
+
+    #[test]
+    fn test_save_in_place_and_quit() {
+        let dir = std::env::temp_dir().join("wd40_test_e2e_in_place");
+        let path = dir.join("file.txt");
+        let file_name = path.to_str().unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(file_name, "world\n").unwrap();
+        // A directory where the temporary copy would go makes the save fall back
+        // to writing in place
+        fs::create_dir_all(dir.join(format!(".file.txt.{}.tmp", std::process::id()))).unwrap();
+        let (mut editor, terminal) = open(&[file_name]);
+        terminal.type_text("hello i.:wq\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(fs::read_to_string(file_name).unwrap(), "hello world\n");
+        assert!(editor.should_quit());
+        assert_eq!(editor.exit_message(), Some(SAVED_IN_PLACE));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_failed_save_as_keeps_file_name() {
+        let path = std::env::temp_dir().join("wd40_test_e2e_failed_save_as.txt");
+        let file_name = path.to_str().unwrap();
+        fs::write(file_name, "world\n").unwrap();
+        let (mut editor, terminal) = open(&[file_name]);
+        let missing = std::env::temp_dir()
+            .join("wd40_test_missing_dir")
+            .join("out.txt");
+        terminal.type_text(&format!("hello i.:w {}\n", missing.to_str().unwrap()));
+        run_keys(&mut editor, &terminal);
+        assert!(!missing.exists());
+        assert_eq!(editor.document().file_name.as_deref(), Some(file_name));
+        assert!(editor.document().is_dirty());
+        terminal.type_text("i.:w\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(fs::read_to_string(file_name).unwrap(), "hello world\n");
+        let _ = fs::remove_file(file_name);
+    }
+
+    #[test]
+    fn test_folds_follow_edits() {
+        let (mut editor, terminal) = open(&[]);
+        terminal.type_text("a\nb\nc\nd\ne");
+        for _ in 0..3 {
+            terminal.push_key(KeyCode::Up, KeyModifiers::NONE);
+        }
+        terminal.push_key(KeyCode::Home, KeyModifiers::NONE);
+        for _ in 0..3 {
+            terminal.push_key(KeyCode::Down, KeyModifiers::SHIFT);
+        }
+        terminal.type_text("i.:fold\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.folds, vec![(1, 4, true)]);
+        assert!(terminal.frame().contains("3 lines folded"));
+        // A line added above moves the fold down with its text
+        terminal.push_key(KeyCode::Up, KeyModifiers::NONE);
+        terminal.type_text("\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.folds, vec![(2, 5, true)]);
+        terminal.type_text("i.:unfold\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.folds, vec![(2, 5, false)]);
+        assert!(!terminal.frame().contains("lines folded"));
+        // A line added inside grows it
+        terminal.push_key(KeyCode::Down, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Down, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::End, KeyModifiers::NONE);
+        terminal.type_text("\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.document().text(), "\na\nb\nc\n\nd\ne");
+        assert_eq!(editor.folds, vec![(2, 6, false)]);
+        // Reordering lines across its edge drops it, though no line was added
+        terminal.type_text("i.:sort\n");
+        run_keys(&mut editor, &terminal);
+        assert!(editor.folds.is_empty());
+    }
+
+    #[test]
+    fn test_config_dir_argument() {
+        let dir = std::env::temp_dir().join("wd40_test_config_dir_argument");
+        fs::create_dir_all(dir.join("colors")).unwrap();
+        fs::write(dir.join("config"), "colorscheme mine\n").unwrap();
+        fs::write(dir.join("colors").join("mine"), "number = #010203\n").unwrap();
+        let terminal = TestTerminal::new(80, 8);
+        let args = [format!("--config-dir={}", dir.display())];
+        let editor = Editor::with_backend(Box::new(terminal), &args);
+        assert_eq!(editor.options.colorscheme, "mine");
+        assert_eq!(
+            editor.options.render.colors.number,
+            Color::Rgb { r: 1, g: 2, b: 3 }
+        );
+        // The directory isn't taken for the file to edit
+        assert_eq!(editor.document().file_name, None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_character_mapped_in_normal_mode_only() {
+        let (mut editor, terminal) = open(&[]);
+        terminal.type_text("i.:map x duplicate_line\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.mode(), Mode::Insert);
+        terminal.type_text("ax");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.document().text(), "ax");
+        terminal.push_key(KeyCode::Esc, KeyModifiers::NONE);
+        terminal.type_text("x");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.mode(), Mode::Normal);
+        assert_eq!(editor.document().text(), "ax\nax");
+    }
+
+    #[test]
+    fn test_status_bar_counts() {
+        let (mut editor, terminal) = open(&[]);
+        terminal.type_text("e\u{301}t\u{e9}\n!");
+        run_keys(&mut editor, &terminal);
+        assert!(terminal.line(6).contains("2 lines, 4 chars"));
+        assert_eq!(editor.document().byte_count(), 7);
+        // The counts kept between frames are redone after an edit
+        terminal.push_key(KeyCode::Backspace, KeyModifiers::NONE);
+        run_keys(&mut editor, &terminal);
+        assert!(terminal.line(6).contains("2 lines, 3 chars"));
+        assert_eq!(editor.document().byte_count(), 6);
+    }
+
+    #[test]
+    fn test_jump_back_and_forward() {
+        let (mut editor, terminal) = open(&[]);
+        terminal.type_text("a\nb\nc i.:1\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.cursor_position, Position { x: 0, y: 0 });
+        terminal.push_key(KeyCode::Char('o'), KeyModifiers::CONTROL);
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.cursor_position, Position { x: 2, y: 2 });
+        terminal.push_key(KeyCode::Char('i'), KeyModifiers::ALT);
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.cursor_position, Position { x: 0, y: 0 });
+    }
+
+    #[test]
+    fn test_repeat_edit_with_command() {
+        let (mut editor, terminal) = open(&[]);
+        terminal.type_text("abc");
+        terminal.push_key(KeyCode::Home, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Delete, KeyModifiers::NONE);
+        terminal.type_text("i.:.\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.document().text(), "c");
+
+        // Joining removes a row, and so does repeating it
+        terminal.type_text("\nd\ne");
+        terminal.push_key(KeyCode::Home, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Up, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Up, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Char('j'), KeyModifiers::CONTROL);
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.document().len(), 2);
+        terminal.type_text("i.:.\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.document().len(), 1);
+    }
+
+    #[test]
+    fn test_block_delete_with_wide_characters() {
+        let (mut editor, terminal) = open(&[]);
+        terminal.type_text("你b\nabc");
+        terminal.push_key(KeyCode::Up, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Home, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Char('b'), KeyModifiers::CONTROL);
+        terminal.push_key(KeyCode::Down, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Right, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Right, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Delete, KeyModifiers::NONE);
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.document().text(), "b\nc");
+    }
+
+    #[test]
+    fn test_search_moves_cursor() {
+        let path = std::env::temp_dir().join("wd40_test_e2e_search.txt");
+        let file_name = path.to_str().unwrap();
+        fs::write(file_name, "one\ntwo three\n").unwrap();
+        let (mut editor, terminal) = open(&[file_name]);
+        terminal.push_key(KeyCode::Char('f'), KeyModifiers::CONTROL);
+        terminal.type_text("three\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(terminal.cursor(), Position { x: 4, y: 1 });
+        assert!(
+            terminal.line(6).contains("Ln 2, Col 5"),
+            "{}",
+            terminal.frame()
+        );
+        assert!(!editor.document().is_dirty());
+        let _ = fs::remove_file(file_name);
+    }
+
+    #[test]
+    fn test_normal_mode() {
+        let (mut editor, terminal) = open(&[]);
+        terminal.type_text("abcd\nefgh");
+        terminal.push_key(KeyCode::Esc, KeyModifiers::NONE);
+        terminal.type_text("k0x2lx\t");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.mode(), Mode::Normal);
+        assert!(editor.document().rows().map(Row::as_str).eq(["bc", "efgh"]));
+        terminal.type_text("ja!");
+        terminal.push_key(KeyCode::Esc, KeyModifiers::NONE);
+        terminal.type_text("onew");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.mode(), Mode::Insert);
+        assert_eq!(editor.document().text(), "bc\nefg!h\nnew");
+    }
+
+    #[test]
+    fn test_add_cursor_reports_wrap() {
+        let (mut editor, terminal) = open(&[]);
+        terminal.type_text("ab x\nab");
+        terminal.push_key(KeyCode::Home, KeyModifiers::NONE);
+        run_keys(&mut editor, &terminal);
+        editor.set_highlighted_word(Some(Pattern::new("ab", false).unwrap()));
+        terminal.push_key(KeyCode::Char('n'), KeyModifiers::CONTROL);
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.extra_cursors, [Position { x: 0, y: 0 }]);
+        assert_eq!(
+            editor.status_message.text,
+            "2 cursors - Esc leaves one (search hit BOTTOM, continuing at TOP)"
+        );
+        // The next match is the main cursor's, so the matches are used up
+        terminal.push_key(KeyCode::Char('n'), KeyModifiers::CONTROL);
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.extra_cursors.len(), 1);
+        assert_eq!(editor.status_message.text, "No place for another cursor.");
+    }
+
+    #[test]
+    fn test_enter_with_several_cursors() {
+        let (mut editor, terminal) = open(&[]);
+        terminal.type_text("ab\ncd");
+        terminal.push_key(KeyCode::Up, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Home, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Char('n'), KeyModifiers::CONTROL);
+        terminal.type_text("\nX");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.document().text(), "\nXab\n\nXcd");
+        // Row edits made at the main cursor drop the others
+        terminal.push_key(KeyCode::Char('d'), KeyModifiers::CONTROL);
+        terminal.type_text("Y");
+        run_keys(&mut editor, &terminal);
+        assert!(editor.extra_cursors.is_empty());
+        assert_eq!(editor.document().text(), "\nXab\nXYab\n\nXcd");
+    }
+
+    #[test]
+    fn test_switch_between_search_and_command_line() {
+        let (mut editor, terminal) = open(&[]);
+        let ctrl = KeyModifiers::CONTROL;
+        terminal.type_text("one two\none");
+        // ':' is part of the query, and Ctrl-F does not start another search
+        terminal.push_key(KeyCode::Char('f'), ctrl);
+        terminal.type_text("t:");
+        terminal.push_key(KeyCode::Char('f'), ctrl);
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.mode(), Mode::Insert);
+        assert!(
+            terminal.line(7).contains("[no matches]"),
+            "{}",
+            terminal.frame()
+        );
+        assert!(terminal.line(7).ends_with(": t:"), "{}", terminal.frame());
+        terminal.push_key(KeyCode::Esc, KeyModifiers::NONE);
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.cursor_position, Position { x: 3, y: 1 });
+        assert!(editor.highlighted_word.is_none());
+
+        // Ctrl-F is not typed into the command line, nor does it leave it
+        terminal.push_key(KeyCode::Esc, KeyModifiers::NONE);
+        terminal.type_text(":");
+        terminal.push_key(KeyCode::Char('f'), ctrl);
+        terminal.push_key(KeyCode::Char('p'), ctrl);
+        terminal.type_text("/");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.mode(), Mode::Command);
+        assert!(editor.search.is_none() && editor.finder.is_none());
+        assert!(terminal.line(7).ends_with(":/"), "{}", terminal.frame());
+        terminal.push_key(KeyCode::Esc, KeyModifiers::NONE);
+        terminal.type_text("/two\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.mode(), Mode::Normal);
+        assert_eq!(editor.cursor_position, Position { x: 4, y: 0 });
+        assert!(editor.highlighted_word.is_some() && editor.current_match.is_none());
+        assert_eq!(editor.document().text(), "one two\none");
+    }
+
+    #[test]
+    fn test_hlsearch() {
+        let (mut editor, terminal) = open(&[]);
+        terminal.type_text("one two one");
+        terminal.push_key(KeyCode::Char('f'), KeyModifiers::CONTROL);
+        terminal.type_text("one\n");
+        run_keys(&mut editor, &terminal);
+        assert!(editor.highlighted_word.is_some());
+        editor.execute_command("noh");
+        assert!(editor.highlighted_word.is_none());
+
+        terminal.push_key(KeyCode::Char('f'), KeyModifiers::CONTROL);
+        terminal.type_text("two\n!");
+        run_keys(&mut editor, &terminal);
+        assert!(editor.highlighted_word.is_none());
+        assert_eq!(editor.document().text(), "one !two one");
+
+        editor.execute_command("set nohlsearch");
+        terminal.push_key(KeyCode::Char('f'), KeyModifiers::CONTROL);
+        terminal.type_text("one\n");
+        run_keys(&mut editor, &terminal);
+        assert!(editor.highlighted_word.is_none());
+        assert_eq!(editor.cursor_position, Position { x: 9, y: 0 });
+    }
+
+    #[test]
+    fn test_repeat_search() {
+        let (mut editor, terminal) = open(&[]);
+        terminal.type_text("one two\none two");
+        terminal.push_key(KeyCode::Esc, KeyModifiers::NONE);
+        terminal.type_text("n");
+        run_keys(&mut editor, &terminal);
+        assert!(
+            terminal.line(7).starts_with("No matches"),
+            "{}",
+            terminal.frame()
+        );
+
+        terminal.type_text("/two\nn");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.cursor_position, Position { x: 4, y: 1 });
+        terminal.type_text("n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.cursor_position, Position { x: 4, y: 0 });
+        assert!(
+            terminal.line(7).contains("search hit BOTTOM"),
+            "{}",
+            terminal.frame()
+        );
+        terminal.type_text("N");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.cursor_position, Position { x: 4, y: 1 });
+        assert!(
+            terminal.line(7).contains("search hit TOP"),
+            "{}",
+            terminal.frame()
+        );
+        terminal.type_text("3n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.cursor_position, Position { x: 4, y: 0 });
+        assert_eq!(editor.mode(), Mode::Normal);
+        assert_eq!(editor.document().text(), "one two\none two");
+    }
+
+    #[test]
+    fn test_expand_abbreviations() {
+        let (mut editor, terminal) = open(&[]);
+        editor.execute_command("ab teh the");
+        editor.execute_command("ab sig Best,\\nMe");
+        terminal.type_text("steh teh tehx sig\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.document().text(), "steh the tehx Best,\nMe\n");
+        assert_eq!(editor.cursor_position, Position { x: 0, y: 2 });
+    }
+
+    #[test]
+    fn test_move_cursor_at_document_end() {
+        let (mut editor, _) = open(&[]);
+        // An empty document only has the line past its end
+        for key in [
+            KeyCode::Down,
+            KeyCode::Right,
+            KeyCode::End,
+            KeyCode::PageDown,
+        ] {
+            editor.move_cursor(key);
+            assert_eq!(editor.cursor_position, Position::default(), "{key:?}");
+        }
+        editor.document.insert_str(&Position::default(), "ab\ncd");
+        editor.cursor_position = Position { x: 1, y: 1 };
+        editor.move_cursor(KeyCode::Down);
+        assert_eq!(editor.cursor_position, Position { x: 0, y: 2 });
+        for key in [KeyCode::Down, KeyCode::Right, KeyCode::End, KeyCode::Home] {
+            editor.move_cursor(key);
+            assert_eq!(editor.cursor_position, Position { x: 0, y: 2 }, "{key:?}");
+        }
+        editor.move_cursor(KeyCode::Left);
+        assert_eq!(editor.cursor_position, Position { x: 2, y: 1 });
+        editor.move_cursor(KeyCode::Right);
+        assert_eq!(editor.cursor_position, Position { x: 0, y: 2 });
+        editor.move_cursor(KeyCode::Up);
+        assert_eq!(editor.cursor_position, Position { x: 0, y: 1 });
+        editor.cursor_position = Position { x: 2, y: 0 };
+        editor.move_cursor(KeyCode::PageDown);
+        assert_eq!(editor.cursor_position, Position { x: 0, y: 2 });
+        // Typing on the line past the end appends a row
+        editor.process_keypress(KeyCode::Char('e'), KeyModifiers::NONE);
+        assert_eq!(editor.document().text(), "ab\ncd\ne");
+    }
+
+    #[test]
+    fn test_welcome_message() {
+        let (mut editor, terminal) = open(&[]);
+        editor.execute_command("set welcome=Hello\\nwide world");
+        editor.refresh_screen().unwrap();
+        // Both lines start where the widest one is centered
+        let spaces = " ".repeat((80 - "wide world".len()) / 2 - 1);
+        assert_eq!(terminal.line(1), format!("~{spaces}Hello"));
+        assert_eq!(terminal.line(2), format!("~{spaces}wide world"));
+        editor.execute_command("set nowelcome");
+        editor.refresh_screen().unwrap();
+        assert_eq!(terminal.line(1), "~");
+    }
+
+    #[test]
+    fn test_command_mode() {
+        let (mut editor, terminal) = open(&[]);
+        terminal.type_text("ab i.:");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(terminal.line(7), "-- COMMAND -- :");
+        terminal.type_text("set number\n");
+        run_keys(&mut editor, &terminal);
+        assert_eq!(terminal.line(0), "  1 ab");
+        assert_eq!(terminal.cursor(), Position { x: 7, y: 0 });
+    }
+
+    #[test]
+    fn test_indent_keeps_selection() {
+        let (mut editor, terminal) = open(&[]);
+        terminal.type_text("a\nb\nc\nd");
+        terminal.push_key(KeyCode::Up, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Up, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Home, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Down, KeyModifiers::SHIFT);
+        terminal.push_key(KeyCode::Down, KeyModifiers::SHIFT);
+        run_keys(&mut editor, &terminal);
+        let selected = |editor: &Editor| {
+            editor
+                .selection()
+                .map(|selection| editor.selected_rows(&selection))
+        };
+        assert_eq!(selected(&editor), Some(1..3));
+        terminal.push_key(KeyCode::Tab, KeyModifiers::NONE);
+        terminal.push_key(KeyCode::Tab, KeyModifiers::NONE);
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.document().text(), "a\n        b\n        c\nd");
+        assert_eq!(selected(&editor), Some(1..3));
+        terminal.push_key(KeyCode::BackTab, KeyModifiers::SHIFT);
+        run_keys(&mut editor, &terminal);
+        assert_eq!(editor.document().text(), "a\n    b\n    c\nd");
+        assert_eq!(selected(&editor), Some(1..3));
+    }
+
+    #[test]
+    fn test_remap_position_after_indent() {
+        let mut doc = Document::default();
+        doc.insert_str(&Position::default(), "a\nbc\nd");
+        let (anchor, cursor) = (Position { x: 0, y: 0 }, Position { x: 1, y: 1 });
+        let below = Position { x: 1, y: 2 };
+        let old_lens = [1, 2, 1];
+        let rows = 0..2;
+        assert!(doc.indent_rows(rows.clone(), 4, false));
+        let remap =
+            |position: &Position, old_len| remap_position(position, old_len, &rows, &rows, &doc);
+        // The selection still starts at the first row and ends after the same text
+        assert_eq!(remap(&anchor, old_lens[0]), Position { x: 0, y: 0 });
+        assert_eq!(remap(&cursor, old_lens[1]), Position { x: 5, y: 1 });
+        assert_eq!(remap(&below, old_lens[2]), below);
+
+        // Rows moved by a rewrite with fewer rows follow it
+        assert_eq!(
+            remap_position(&below, 1, &(0..2), &(0..1), &doc),
+            Position { x: 1, y: 1 }
+        );
+        assert_eq!(
+            remap_position(&cursor, 2, &(0..2), &(0..1), &doc),
+            Position { x: 1, y: 0 }
+        );
+    }
+
+    #[test]
+    fn test_parse_file_location() {
+        let at = |x, y| Some(Position { x, y });
+        assert_eq!(parse_file_location("main.rs:12:5"), ("main.rs", at(4, 11)));
+        // Without a column the cursor starts the line
+        assert_eq!(parse_file_location("main.rs:12"), ("main.rs", at(0, 11)));
+        // A suffix that isn't a number is part of the file name
+        assert_eq!(parse_file_location("main.rs:12:x"), ("main.rs:12:x", None));
+        assert_eq!(parse_file_location("main.rs:end"), ("main.rs:end", None));
+        // Line 0 goes to the first line rather than wrapping around
+        assert_eq!(parse_file_location("main.rs:0"), ("main.rs", at(0, 0)));
+        assert_eq!(parse_file_location(":12"), (":12", None));
+    }
+
+    #[test]
+    fn test_parse_grep_result() {
+        assert_eq!(
+            parse_grep_result("src/main.rs:3:fn main() {"),
+            Some(("src/main.rs", 3))
+        );
+        // The text after the line number may contain colons of its own
+        assert_eq!(parse_grep_result("a.txt:7:b:12:c"), Some(("a.txt", 7)));
+        assert_eq!(
+            parse_grep_result("C:\\src\\main.rs:3:fn main() {"),
+            Some(("C:\\src\\main.rs", 3))
+        );
+        assert_eq!(
+            parse_grep_result("notes:v2.txt:10:x"),
+            Some(("notes:v2.txt", 10))
+        );
+        assert_eq!(parse_grep_result("a.txt:ten:x"), None);
+        assert_eq!(parse_grep_result("a.txt:10"), None);
+        assert_eq!(parse_grep_result("no colon"), None);
+    }
+
+    #[test]
+    fn test_commands_are_sorted() {
+        // Completion lists them in this order
+        assert!(COMMANDS.is_sorted());
+    }
+}
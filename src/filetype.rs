@@ -1,28 +1,44 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock, PoisonError, RwLock};
 
 pub struct FileType {
     name: String,
     hl_opts: HighlightingOptions,
 }
 
-#[derive(Default)]
-
+#[derive(Default, Clone)]
 pub struct HighlightingOptions {
     numbers: bool,
 
-    strings: bool,
+    string_delimiters: Vec<char>,
 
     characters: bool,
 
-    comments: bool,
+    multiline_strings: bool,
+
+    line_comment_start: Option<String>,
+
+    multiline_comment_start: Option<String>,
 
-    multiline_comments: bool,
+    multiline_comment_end: Option<String>,
 
     primary_keywords: Vec<String>,
 
     secondary_keywords: Vec<String>,
+
+    comment_markers: Option<Vec<String>>,
 }
 
+/// Words highlighted inside comments when a syntax definition lists none.
+static DEFAULT_COMMENT_MARKERS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    ["TODO", "FIXME", "HACK", "NOTE", "XXX"]
+        .map(str::to_owned)
+        .into()
+});
+
 impl Default for FileType {
     fn default() -> Self {
         Self {
@@ -35,365 +51,68 @@ impl Default for FileType {
 
 impl FileType {
     #[must_use]
-
     pub fn name(&self) -> String {
         self.name.clone()
     }
 
     #[must_use]
-
     pub fn highlighting_options(&self) -> &HighlightingOptions {
         &self.hl_opts
     }
 
+    /// Detects the file type, then prefers a syntax definition file for that
+    /// language (see `HighlightingOptions::from_definition`) over the built-in options.
     #[must_use]
-
     pub fn from(file_name: &str) -> Self {
-        // Lowercased helpers
+        Self::detect(file_name).with_definition()
+    }
 
-        let file_path_str = file_name.to_string();
+    /// Like `from`, but a `#!` line naming a known interpreter decides the file
+    /// type before the file name does.
+    #[must_use]
+    pub fn from_contents(file_name: &str, first_line: &str) -> Self {
+        match shebang_language(first_line) {
+            Some(language) => Self::named(language).with_definition(),
+            None => Self::from(file_name),
+        }
+    }
 
-        let file_path_lower = file_path_str.to_ascii_lowercase();
+    fn with_definition(mut self) -> Self {
+        if let Some(hl_opts) = HighlightingOptions::load(&self.name) {
+            self.hl_opts = hl_opts;
+        }
+        self
+    }
 
-        let basename_lower = Path::new(&file_path_str)
-            .file_name()
-            .and_then(|s| s.to_str())
-            .map(|s| s.to_ascii_lowercase())
-            .unwrap_or_default();
+    /// A file type called `name`, highlighted as the built-in language it names.
+    fn named(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            hl_opts: HighlightingOptions::builtin(name).unwrap_or_default(),
+        }
+    }
 
-        let ext = Path::new(&file_path_str)
+    fn detect(file_name: &str) -> Self {
+        let ext = Path::new(file_name)
             .extension()
             .and_then(|s| s.to_str())
-            .map(|s| s.to_ascii_lowercase());
-
-        // Simple wildcard matcher (* and ?)
-        let matches_glob = |pattern: &str, text: &str| -> bool {
-            fn helper(p: &[u8], t: &[u8]) -> bool {
-                let (mut pi, mut ti) = (0usize, 0usize);
-
-                let mut star: Option<usize> = None;
-
-                let mut match_t: usize = 0;
-
-                while ti < t.len() {
-                    if pi < p.len() && (p[pi] == b'?' || p[pi] == t[ti]) {
-                        pi += 1;
-
-                        ti += 1;
-                    } else if pi < p.len() && p[pi] == b'*' {
-                        star = Some(pi);
-
-                        match_t = ti;
-
-                        pi += 1;
-                    } else if let Some(si) = star {
-                        pi = si + 1;
-
-                        match_t += 1;
-
-                        ti = match_t;
-                    } else {
-                        return false;
-                    }
-                }
-
-                while pi < p.len() && p[pi] == b'*' {
-                    pi += 1;
-                }
-
-                pi == p.len()
-            }
-
-            helper(pattern.as_bytes(), text.as_bytes())
-        };
-
-        // Candidate mapping files
-        let candidates = [
-            "wd40text/assets/filetypes.txt",
-            "assets/filetypes.txt",
-            "filetypes.txt",
-        ];
-
-        let mut contents_opt = None;
-
-        for p in &candidates {
-            if let Ok(c) = std::fs::read_to_string(p) {
-                contents_opt = Some(c);
-
-                break;
-            }
-        }
-
-        if let Some(contents) = contents_opt {
-            for raw_line in contents.lines() {
-                let mut line = raw_line.trim();
-
-                if line.is_empty() {
-                    continue;
-                }
-
-                if let Some((pre, _)) = line.split_once('#') {
-                    line = pre.trim();
-
-                    if line.is_empty() {
-                        continue;
-                    }
-                }
-
-                // Delimiters: =>, ->, :, =
-                let (lhs, rhs) = if let Some((l, r)) = line.split_once("=>") {
-                    (l.trim(), r.trim())
-                } else if let Some((l, r)) = line.split_once("->") {
-                    (l.trim(), r.trim())
-                } else if let Some((l, r)) = line.split_once(':') {
-                    (l.trim(), r.trim())
-                } else if let Some((l, r)) = line.split_once('=') {
-                    (l.trim(), r.trim())
-                } else {
-                    continue;
-                };
-
-                if lhs.is_empty() || rhs.is_empty() {
-                    continue;
-                }
-
-                let display_name = rhs.trim().trim_matches(|c| c == '"' || c == '\'');
-
-                let mut matched = false;
-
-                for token in lhs.split([',', ';']) {
-                    let token = token.trim();
-
-                    if token.is_empty() {
-                        continue;
-                    }
-
-                    for part in token.split_whitespace() {
-                        let mut pat = part
-                            .trim()
-                            .trim_matches(|c| c == '"' || c == '\'')
-                            .to_ascii_lowercase();
-
-                        if pat.is_empty() {
-                            continue;
-                        }
-
-                        if pat.starts_with('.') {
-                            pat.remove(0);
-                        }
-
-                        if pat.contains('*')
-                            || pat.contains('?')
-                            || pat.contains('/')
-                            || pat.contains('\\')
-                        {
-                            if matches_glob(&pat, &file_path_lower)
-                                || matches_glob(&pat, &basename_lower)
-                            {
-                                matched = true;
-                            }
-                        } else if pat.contains('.') {
-                            if basename_lower == pat {
-                                matched = true;
-                            }
-                        } else if let Some(ref e) = ext {
-                            if e == &pat {
-                                matched = true;
-                            }
-                        }
-
-                        if matched {
-                            break;
-                        }
-                    }
-
-                    if matched {
-                        let name = display_name.to_string();
-
-                        if ext.as_deref() == Some("rs") {
-                            return Self {
-                                name,
-
-                                hl_opts: HighlightingOptions {
-                                    numbers: true,
-
-                                    strings: true,
-
-                                    characters: true,
-
-                                    comments: true,
-
-                                    multiline_comments: true,
-
-                                    primary_keywords: vec![
-                                        "as".into(),
-                                        "break".into(),
-                                        "const".into(),
-                                        "continue".into(),
-                                        "crate".into(),
-                                        "else".into(),
-                                        "enum".into(),
-                                        "extern".into(),
-                                        "false".into(),
-                                        "fn".into(),
-                                        "for".into(),
-                                        "if".into(),
-                                        "impl".into(),
-                                        "in".into(),
-                                        "let".into(),
-                                        "loop".into(),
-                                        "match".into(),
-                                        "mod".into(),
-                                        "move".into(),
-                                        "mut".into(),
-                                        "pub".into(),
-                                        "ref".into(),
-                                        "return".into(),
-                                        "self".into(),
-                                        "Self".into(),
-                                        "static".into(),
-                                        "struct".into(),
-                                        "super".into(),
-                                        "trait".into(),
-                                        "true".into(),
-                                        "type".into(),
-                                        "unsafe".into(),
-                                        "use".into(),
-                                        "where".into(),
-                                        "while".into(),
-                                        "dyn".into(),
-                                        "abstract".into(),
-                                        "become".into(),
-                                        "box".into(),
-                                        "do".into(),
-                                        "final".into(),
-                                        "macro".into(),
-                                        "override".into(),
-                                        "priv".into(),
-                                        "typeof".into(),
-                                        "unsized".into(),
-                                        "virtual".into(),
-                                        "yield".into(),
-                                        "async".into(),
-                                        "await".into(),
-                                        "try".into(),
-                                    ],
-
-                                    secondary_keywords: vec![
-                                        "bool".into(),
-                                        "char".into(),
-                                        "i8".into(),
-                                        "i16".into(),
-                                        "i32".into(),
-                                        "i64".into(),
-                                        "isize".into(),
-                                        "u8".into(),
-                                        "u16".into(),
-                                        "u32".into(),
-                                        "u64".into(),
-                                        "usize".into(),
-                                        "f32".into(),
-                                        "f64".into(),
-                                    ],
-                                },
-                            };
-                        } else {
-                            return Self {
-                                name,
-                                hl_opts: HighlightingOptions::default(),
-                            };
-                        }
-                    }
-                }
-            }
+            .map(str::to_ascii_lowercase);
+        if let Some(name) = Mappings::get().file_type_name(file_name) {
+            // A name without built-in options still gets those of the extension
+            let hl_opts = HighlightingOptions::builtin(name)
+                .unwrap_or_else(|| Self::from_extension(ext.as_deref()).hl_opts);
+            return Self {
+                name: name.to_owned(),
+                hl_opts,
+            };
         }
+        Self::from_extension(ext.as_deref())
+    }
 
-        match ext.as_deref() {
-            Some("rs") => Self {
-                name: String::from("Rust"),
-
-                hl_opts: HighlightingOptions {
-                    numbers: true,
-
-                    strings: true,
-
-                    characters: true,
-
-                    comments: true,
-
-                    multiline_comments: true,
-
-                    primary_keywords: vec![
-                        "as".into(),
-                        "break".into(),
-                        "const".into(),
-                        "continue".into(),
-                        "crate".into(),
-                        "else".into(),
-                        "enum".into(),
-                        "extern".into(),
-                        "false".into(),
-                        "fn".into(),
-                        "for".into(),
-                        "if".into(),
-                        "impl".into(),
-                        "in".into(),
-                        "let".into(),
-                        "loop".into(),
-                        "match".into(),
-                        "mod".into(),
-                        "move".into(),
-                        "mut".into(),
-                        "pub".into(),
-                        "ref".into(),
-                        "return".into(),
-                        "self".into(),
-                        "Self".into(),
-                        "static".into(),
-                        "struct".into(),
-                        "super".into(),
-                        "trait".into(),
-                        "true".into(),
-                        "type".into(),
-                        "unsafe".into(),
-                        "use".into(),
-                        "where".into(),
-                        "while".into(),
-                        "dyn".into(),
-                        "abstract".into(),
-                        "become".into(),
-                        "box".into(),
-                        "do".into(),
-                        "final".into(),
-                        "macro".into(),
-                        "override".into(),
-                        "priv".into(),
-                        "typeof".into(),
-                        "unsized".into(),
-                        "virtual".into(),
-                        "yield".into(),
-                        "async".into(),
-                        "await".into(),
-                        "try".into(),
-                    ],
-                    secondary_keywords: vec![
-                        "bool".into(),
-                        "char".into(),
-                        "i8".into(),
-                        "i16".into(),
-                        "i32".into(),
-                        "i64".into(),
-                        "isize".into(),
-                        "u8".into(),
-                        "u16".into(),
-                        "u32".into(),
-                        "u64".into(),
-                        "usize".into(),
-                        "f32".into(),
-                        "f64".into(),
-                    ],
-                },
-            },
+    fn from_extension(ext: Option<&str>) -> Self {
+        match ext {
+            Some("rs") => Self::named("Rust"),
+            Some("py" | "pyw") => Self::named("Python"),
             Some("doc") => Self {
                 name: "MS Word 95-97".into(),
                 hl_opts: HighlightingOptions::default(),
@@ -447,46 +166,764 @@ impl FileType {
     }
 }
 
+/// The mappings, read on first use and replaced by `Mappings::reload`.
+static MAPPINGS: OnceLock<RwLock<Arc<Mappings>>> = OnceLock::new();
+/// Syntax definitions read so far, by file stem; None when there is no file.
+static DEFINITIONS: OnceLock<Mutex<HashMap<String, Option<HighlightingOptions>>>> = OnceLock::new();
+
+/// Names a mappings file to use instead of looking for `filetypes.txt`.
+const MAPPING_FILE_VARIABLE: &str = "WD40_FILETYPES";
+/// Where `filetypes.txt` is looked for; the first one found is used.
+const MAPPING_FILES: &[&str] = &[
+    "wd40text/assets/filetypes.txt",
+    "assets/filetypes.txt",
+    "filetypes.txt",
+];
+
+/// A line of `filetypes.txt`, or of a file it includes, that was skipped, and why.
+pub struct MappingWarning {
+    pub file: String,
+    /// Line number, counting from 1, or 0 when the whole file couldn't be read.
+    pub line: usize,
+    pub text: String,
+    pub reason: &'static str,
+}
+
+/// The rules of `filetypes.txt` mapping file names to file type names, such as
+/// `rs, !build.rs: Rust`. An `include other.txt` line reads the rules of another
+/// file, relative to the including one, at that point.
+#[derive(Default)]
+pub struct Mappings {
+    /// The file the rules were read from, if one was found.
+    pub path: Option<String>,
+    /// Whether `path` was given by the `WD40_FILETYPES` environment variable.
+    pub from_variable: bool,
+    rules: Vec<Rule>,
+    pub warnings: Vec<MappingWarning>,
+}
+
+/// Patterns, each possibly negated with `!`, and the file type name they give.
+struct Rule {
+    patterns: Vec<(bool, String)>,
+    name: String,
+}
+
+impl Mappings {
+    /// The mappings, read from disk the first time only.
+    #[must_use]
+    pub fn get() -> Arc<Self> {
+        let mappings = MAPPINGS.get_or_init(|| RwLock::new(Arc::new(Self::load())));
+        Arc::clone(&mappings.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Reads the mappings from disk again, and forgets the syntax definitions
+    /// read so far.
+    pub fn reload() -> Arc<Self> {
+        let reloaded = Arc::new(Self::load());
+        let mappings = MAPPINGS.get_or_init(|| RwLock::new(Arc::clone(&reloaded)));
+        *mappings.write().unwrap_or_else(PoisonError::into_inner) = Arc::clone(&reloaded);
+        if let Some(definitions) = DEFINITIONS.get() {
+            definitions
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+        reloaded
+    }
+
+    /// Reads the file named by `WD40_FILETYPES`, or else the first of
+    /// `MAPPING_FILES` that exists.
+    fn load() -> Self {
+        Self::load_from(env::var(MAPPING_FILE_VARIABLE).ok())
+    }
+
+    /// Reads the file named by `variable`, the value of `WD40_FILETYPES`, or
+    /// else the first of `MAPPING_FILES` that exists.
+    fn load_from(variable: Option<String>) -> Self {
+        let mut mappings = Self::default();
+        let path = if let Some(path) = variable.filter(|path| !path.is_empty()) {
+            mappings.from_variable = true;
+            path
+        } else if let Some(path) = MAPPING_FILES.iter().find(|path| Path::new(path).is_file()) {
+            (*path).to_owned()
+        } else {
+            return mappings;
+        };
+        if !mappings.read(Path::new(&path), &mut Vec::new()) {
+            mappings.warnings.push(MappingWarning {
+                file: path.clone(),
+                line: 0,
+                text: String::new(),
+                reason: "can't be read",
+            });
+        }
+        mappings.path = Some(path);
+        mappings
+    }
+
+    /// Adds the rules of the file at `path`, following its includes. Blank lines
+    /// and `#` comments are ignored; other lines that can't be used become
+    /// warnings. `including` holds the files whose includes led here, so that an
+    /// include cycle is reported rather than followed. Returns false when the
+    /// file can't be read.
+    fn read(&mut self, path: &Path, including: &mut Vec<PathBuf>) -> bool {
+        let Ok(contents) = fs::read_to_string(path) else {
+            return false;
+        };
+        let file = path.to_string_lossy().into_owned();
+        including.push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+        for (index, text) in contents.lines().enumerate() {
+            let result = if let Some(include) = include_directive(text) {
+                let include = path.parent().map_or_else(
+                    || PathBuf::from(include),
+                    |directory| directory.join(include),
+                );
+                let canonical = fs::canonicalize(&include).unwrap_or_else(|_| include.clone());
+                if including.contains(&canonical) {
+                    Err("include cycle")
+                } else if self.read(&include, including) {
+                    Ok(None)
+                } else {
+                    Err("included file can't be read")
+                }
+            } else {
+                parse_rule(text)
+            };
+            match result {
+                Ok(Some(rule)) => self.rules.push(rule),
+                Ok(None) => (),
+                Err(reason) => self.warnings.push(MappingWarning {
+                    file: file.clone(),
+                    line: index.saturating_add(1),
+                    text: text.trim().to_owned(),
+                    reason,
+                }),
+            }
+        }
+        including.pop();
+        true
+    }
+
+    #[must_use]
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// The file type name of the first rule matching `file_name`.
+    fn file_type_name(&self, file_name: &str) -> Option<&str> {
+        let path = file_name.to_ascii_lowercase();
+        let basename = Path::new(&path)
+            .file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or_default();
+        let ext = Path::new(&path).extension().and_then(|s| s.to_str());
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(&path, basename, ext))
+            .map(|rule| rule.name.as_str())
+    }
+}
+
+impl Rule {
+    /// A rule applies when at least one positive pattern matches and no `!`
+    /// pattern does, wherever the patterns appear on the line; an exclusion
+    /// always wins over a match.
+    fn matches(&self, path: &str, basename: &str, ext: Option<&str>) -> bool {
+        let mut matched = false;
+        for (negated, pattern) in &self.patterns {
+            let is_match = if pattern.contains(['*', '?', '/', '\\']) {
+                matches_glob(pattern, path) || matches_glob(pattern, basename)
+            } else if pattern.contains('.') {
+                basename == pattern
+            } else {
+                ext == Some(pattern.as_str())
+            };
+            if is_match && *negated {
+                return false;
+            }
+            matched |= is_match;
+        }
+        matched
+    }
+}
+
+/// The file named by an `include other.txt` line. A rule for files named
+/// `include`, like `include: Foo`, isn't one.
+fn include_directive(line: &str) -> Option<&str> {
+    let path = line.trim().strip_prefix("include ")?.trim();
+    (!path.is_empty() && !path.starts_with(['=', '-', ':'])).then_some(path)
+}
+
+/// Parses one line of `filetypes.txt`: patterns separated by commas, semicolons
+/// or spaces, one of the delimiters `=>`, `->`, `:` or `=`, and a file type name.
+/// Returns None for blank and comment lines, and why the line is unusable otherwise.
+fn parse_rule(line: &str) -> Result<Option<Rule>, &'static str> {
+    let line = line
+        .split_once('#')
+        .map_or(line, |(before, _)| before)
+        .trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let (lhs, rhs) = ["=>", "->", ":", "="]
+        .iter()
+        .find_map(|delimiter| line.split_once(delimiter))
+        .ok_or("no delimiter (=>, ->, : or =)")?;
+    let (lhs, rhs) = (lhs.trim(), rhs.trim());
+    if lhs.is_empty() {
+        return Err("no pattern before the delimiter");
+    }
+    let name = rhs.trim_matches(|c| c == '"' || c == '\'');
+    if name.is_empty() {
+        return Err("no file type name after the delimiter");
+    }
+    let mut patterns = Vec::new();
+    for part in lhs.split([',', ';']).flat_map(str::split_whitespace) {
+        let part = part.trim_matches(|c| c == '"' || c == '\'');
+        let (negated, part) = match part.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, part),
+        };
+        if part.is_empty() && !negated {
+            continue;
+        }
+        let pattern = part.strip_prefix('.').unwrap_or(part).to_ascii_lowercase();
+        if pattern.is_empty() {
+            return Err("empty pattern");
+        }
+        if pattern.contains(['[', ']', '{', '}']) {
+            return Err("bad glob: only * and ? wildcards are supported");
+        }
+        patterns.push((negated, pattern));
+    }
+    if patterns.iter().all(|(negated, _)| *negated) {
+        return Err("only ! patterns, so it never matches");
+    }
+    Ok(Some(Rule {
+        patterns,
+        name: name.to_owned(),
+    }))
+}
+
+/// Matches `text` against a pattern where `*` is any run of bytes and `?` any one byte.
+fn matches_glob(pattern: &str, text: &str) -> bool {
+    let (p, t) = (pattern.as_bytes(), text.as_bytes());
+    let (mut pi, mut ti) = (0usize, 0usize);
+
+    let mut star: Option<usize> = None;
+
+    let mut match_t: usize = 0;
+
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == b'?' || p[pi] == t[ti]) {
+            pi = pi.saturating_add(1);
+
+            ti = ti.saturating_add(1);
+        } else if pi < p.len() && p[pi] == b'*' {
+            star = Some(pi);
+
+            match_t = ti;
+
+            pi = pi.saturating_add(1);
+        } else if let Some(si) = star {
+            pi = si.saturating_add(1);
+
+            match_t = match_t.saturating_add(1);
+
+            ti = match_t;
+        } else {
+            return false;
+        }
+    }
+
+    while pi < p.len() && p[pi] == b'*' {
+        pi = pi.saturating_add(1);
+    }
+
+    pi == p.len()
+}
+
+/// The language run by a `#!/usr/bin/python3` or `#!/usr/bin/env bash` line.
+fn shebang_language(first_line: &str) -> Option<&'static str> {
+    let command = first_line.strip_prefix("#!")?.trim_start();
+    // Rust inner attributes (`#![...]`) also start with `#!`
+    if !command.starts_with('/') {
+        return None;
+    }
+    let program_name = |path: &str| path.rsplit('/').next().unwrap_or_default().to_owned();
+    let mut words = command.split_whitespace();
+    let mut program = program_name(words.next()?);
+    if program == "env" {
+        program = program_name(words.find(|word| !word.starts_with('-') && !word.contains('='))?);
+    }
+    match program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.') {
+        "python" => Some("Python"),
+        "sh" | "bash" | "dash" | "zsh" | "ksh" => Some("Shell"),
+        "node" | "nodejs" => Some("JavaScript"),
+        "ruby" => Some("Ruby"),
+        "perl" => Some("Perl"),
+        _ => None,
+    }
+}
+
 impl HighlightingOptions {
+    /// Built-in options for the language a file type name starts with ("Rust",
+    /// "Python Script"), if there are any.
+    #[must_use]
+    pub fn builtin(language: &str) -> Option<Self> {
+        let first_word = language
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match first_word.as_str() {
+            "rust" => Some(Self::rust()),
+            "python" => Some(Self::python()),
+            _ => None,
+        }
+    }
+
+    fn rust() -> Self {
+        Self {
+            numbers: true,
+
+            string_delimiters: vec!['"'],
+
+            characters: true,
+
+            multiline_strings: false,
+
+            line_comment_start: Some("//".into()),
+
+            multiline_comment_start: Some("/*".into()),
+
+            multiline_comment_end: Some("*/".into()),
+
+            primary_keywords: vec![
+                "as".into(),
+                "break".into(),
+                "const".into(),
+                "continue".into(),
+                "crate".into(),
+                "else".into(),
+                "enum".into(),
+                "extern".into(),
+                "false".into(),
+                "fn".into(),
+                "for".into(),
+                "if".into(),
+                "impl".into(),
+                "in".into(),
+                "let".into(),
+                "loop".into(),
+                "match".into(),
+                "mod".into(),
+                "move".into(),
+                "mut".into(),
+                "pub".into(),
+                "ref".into(),
+                "return".into(),
+                "self".into(),
+                "Self".into(),
+                "static".into(),
+                "struct".into(),
+                "super".into(),
+                "trait".into(),
+                "true".into(),
+                "type".into(),
+                "unsafe".into(),
+                "use".into(),
+                "where".into(),
+                "while".into(),
+                "dyn".into(),
+                "abstract".into(),
+                "become".into(),
+                "box".into(),
+                "do".into(),
+                "final".into(),
+                "macro".into(),
+                "override".into(),
+                "priv".into(),
+                "typeof".into(),
+                "unsized".into(),
+                "virtual".into(),
+                "yield".into(),
+                "async".into(),
+                "await".into(),
+                "try".into(),
+            ],
+            secondary_keywords: vec![
+                "bool".into(),
+                "char".into(),
+                "i8".into(),
+                "i16".into(),
+                "i32".into(),
+                "i64".into(),
+                "isize".into(),
+                "u8".into(),
+                "u16".into(),
+                "u32".into(),
+                "u64".into(),
+                "usize".into(),
+                "f32".into(),
+                "f64".into(),
+            ],
+
+            comment_markers: None,
+        }
+    }
+
+    fn python() -> Self {
+        Self {
+            numbers: true,
+
+            string_delimiters: vec!['"', '\''],
+
+            characters: false,
+
+            multiline_strings: true,
+
+            line_comment_start: Some("#".into()),
+
+            multiline_comment_start: None,
+
+            multiline_comment_end: None,
+
+            primary_keywords: vec![
+                "False".into(),
+                "None".into(),
+                "True".into(),
+                "and".into(),
+                "as".into(),
+                "assert".into(),
+                "async".into(),
+                "await".into(),
+                "break".into(),
+                "class".into(),
+                "continue".into(),
+                "def".into(),
+                "del".into(),
+                "elif".into(),
+                "else".into(),
+                "except".into(),
+                "finally".into(),
+                "for".into(),
+                "from".into(),
+                "global".into(),
+                "if".into(),
+                "import".into(),
+                "in".into(),
+                "is".into(),
+                "lambda".into(),
+                "match".into(),
+                "case".into(),
+                "nonlocal".into(),
+                "not".into(),
+                "or".into(),
+                "pass".into(),
+                "raise".into(),
+                "return".into(),
+                "try".into(),
+                "while".into(),
+                "with".into(),
+                "yield".into(),
+                "self".into(),
+            ],
+            secondary_keywords: vec![
+                "bool".into(),
+                "bytearray".into(),
+                "bytes".into(),
+                "complex".into(),
+                "dict".into(),
+                "float".into(),
+                "frozenset".into(),
+                "int".into(),
+                "list".into(),
+                "object".into(),
+                "range".into(),
+                "set".into(),
+                "str".into(),
+                "tuple".into(),
+                "type".into(),
+            ],
+
+            comment_markers: None,
+        }
+    }
+
+    /// Looks for `assets/syntax/<language>.txt`, where the language name is
+    /// lowercased and non-alphanumeric runs become `_` ("Godot Shader" -> `godot_shader`).
+    /// Each file is read once, until `Mappings::reload`.
+    #[must_use]
+    pub fn load(language: &str) -> Option<Self> {
+        let mut file_stem = String::new();
+        for c in language.chars() {
+            if c.is_alphanumeric() {
+                file_stem.extend(c.to_lowercase());
+            } else if !file_stem.ends_with('_') {
+                file_stem.push('_');
+            }
+        }
+        let file_stem = file_stem.trim_matches('_');
+        if file_stem.is_empty() {
+            return None;
+        }
+
+        let candidates = ["wd40text/assets/syntax", "assets/syntax", "syntax"];
+        let mut definitions = DEFINITIONS
+            .get_or_init(Mutex::default)
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        definitions
+            .entry(file_stem.to_owned())
+            .or_insert_with(|| {
+                candidates.iter().find_map(|dir| {
+                    fs::read_to_string(format!("{dir}/{file_stem}.txt"))
+                        .ok()
+                        .map(|contents| Self::from_definition(&contents))
+                })
+            })
+            .clone()
+    }
+
+    /// Parses a syntax definition: one `key: value` (or `key = value`) per line,
+    /// `#` comment lines, repeated keyword keys accumulate. Unknown keys are ignored.
     #[must_use]
+    pub fn from_definition(contents: &str) -> Self {
+        let mut opts = Self::default();
+        for raw_line in contents.lines() {
+            let line = raw_line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some(split) = line.find([':', '=']) else {
+                continue;
+            };
+            let key = line.get(..split).unwrap_or_default().trim();
+            let value = line
+                .get(split.saturating_add(1)..)
+                .unwrap_or_default()
+                .trim();
+            let flag = matches!(
+                value.to_ascii_lowercase().as_str(),
+                "true" | "yes" | "on" | "1"
+            );
+            let words = || {
+                value
+                    .split([',', ' ', '\t'])
+                    .map(str::trim)
+                    .filter(|w| !w.is_empty())
+                    .map(String::from)
+            };
+
+            match key.to_ascii_lowercase().as_str() {
+                "numbers" => opts.numbers = flag,
+                "strings" if flag && opts.string_delimiters.is_empty() => {
+                    opts.string_delimiters = vec!['"'];
+                }
+                "strings" if !flag => opts.string_delimiters.clear(),
+                "string_delimiters" => {
+                    opts.string_delimiters = value.chars().filter(|c| !c.is_whitespace()).collect();
+                }
+                "characters" => opts.characters = flag,
+                "multiline_strings" => opts.multiline_strings = flag,
+                // Shorthand for C-style `/* */` comments
+                "multiline_comments" if flag => {
+                    opts.multiline_comment_start = Some("/*".to_owned());
+                    opts.multiline_comment_end = Some("*/".to_owned());
+                }
+                "multiline_comment_start" if !value.is_empty() => {
+                    opts.multiline_comment_start = Some(value.to_owned());
+                }
+                "multiline_comment_end" if !value.is_empty() => {
+                    opts.multiline_comment_end = Some(value.to_owned());
+                }
+                "line_comment" if !value.is_empty() => {
+                    opts.line_comment_start = Some(value.to_owned());
+                }
+                "primary_keywords" => opts.primary_keywords.extend(words()),
+                "secondary_keywords" => opts.secondary_keywords.extend(words()),
+                "comment_markers" => opts
+                    .comment_markers
+                    .get_or_insert_with(Vec::new)
+                    .extend(words()),
+                _ => {}
+            }
+        }
+        opts
+    }
 
+    #[must_use]
     pub fn numbers(&self) -> bool {
         self.numbers
     }
 
     #[must_use]
-
     pub fn strings(&self) -> bool {
-        self.strings
+        !self.string_delimiters.is_empty()
     }
 
+    /// Characters that open and close a single-line string.
     #[must_use]
+    pub fn string_delimiters(&self) -> &[char] {
+        &self.string_delimiters
+    }
 
+    #[must_use]
     pub fn characters(&self) -> bool {
         self.characters
     }
 
     #[must_use]
-
     pub fn comments(&self) -> bool {
-        self.comments
+        self.line_comment_start.is_some() || self.multiline_comments()
     }
 
     #[must_use]
-
     pub fn primary_keywords(&self) -> &Vec<String> {
         &self.primary_keywords
     }
 
     #[must_use]
-
     pub fn secondary_keywords(&self) -> &Vec<String> {
         &self.secondary_keywords
     }
 
+    /// Words highlighted inside comments.
     #[must_use]
+    pub fn comment_markers(&self) -> &[String] {
+        self.comment_markers
+            .as_deref()
+            .unwrap_or(&DEFAULT_COMMENT_MARKERS)
+    }
 
+    #[must_use]
     pub fn multiline_comments(&self) -> bool {
-        self.multiline_comments
+        self.multiline_comment_start.is_some() && self.multiline_comment_end.is_some()
+    }
+
+    #[must_use]
+    pub fn multiline_strings(&self) -> bool {
+        self.multiline_strings
+    }
+
+    #[must_use]
+    pub fn line_comment_start(&self) -> Option<&str> {
+        self.line_comment_start.as_deref()
+    }
+
+    #[must_use]
+    pub fn multiline_comment_start(&self) -> Option<&str> {
+        self.multiline_comment_start.as_deref()
+    }
+
+    #[must_use]
+    pub fn multiline_comment_end(&self) -> Option<&str> {
+        self.multiline_comment_end.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_parse_rule() {
+        let rule = parse_rule("*.rs, .RS; build.gradle !target/* => \"rust\" # comment")
+            .unwrap()
+            .unwrap();
+        assert_eq!(rule.name, "rust");
+        let patterns = [
+            (false, "*.rs"),
+            (false, "rs"),
+            (false, "build.gradle"),
+            (true, "target/*"),
+        ]
+        .map(|(negated, pattern)| (negated, pattern.to_owned()));
+        assert_eq!(rule.patterns, patterns);
+        for delimiter in ["=>", "->", ":", "="] {
+            let rule = parse_rule(&format!("md {delimiter} 'markdown'"))
+                .unwrap()
+                .unwrap();
+            assert_eq!(rule.name, "markdown");
+            assert_eq!(rule.patterns, [(false, "md".to_owned())]);
+        }
+        // Blank and comment lines aren't rules
+        assert!(matches!(parse_rule(""), Ok(None)));
+        assert!(matches!(parse_rule("   # rs => rust"), Ok(None)));
+        let error = |line| parse_rule(line).err();
+        assert_eq!(error("rs rust"), Some("no delimiter (=>, ->, : or =)"));
+        assert_eq!(error("=> rust"), Some("no pattern before the delimiter"));
+        assert_eq!(
+            error("rs => \"\""),
+            Some("no file type name after the delimiter")
+        );
+        assert_eq!(error("! => rust"), Some("empty pattern"));
+        assert_eq!(
+            error("[ab].rs => rust"),
+            Some("bad glob: only * and ? wildcards are supported")
+        );
+        assert_eq!(
+            error("!rs => rust"),
+            Some("only ! patterns, so it never matches")
+        );
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = env::temp_dir().join("wd40_test_include_cycle");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "include b.txt\nrs => rust\n").unwrap();
+        fs::write(dir.join("b.txt"), "md => markdown\ninclude a.txt\n").unwrap();
+        fs::write(dir.join("self.txt"), "include self.txt\ntxt => text\n").unwrap();
+        let mut mappings = Mappings::default();
+        assert!(mappings.read(&dir.join("a.txt"), &mut Vec::new()));
+        assert_eq!(mappings.rule_count(), 2);
+        let warnings: Vec<(&str, usize, &str)> = mappings
+            .warnings
+            .iter()
+            .map(|warning| (warning.text.as_str(), warning.line, warning.reason))
+            .collect();
+        assert_eq!(warnings, [("include a.txt", 2, "include cycle")]);
+        assert!(mappings.warnings[0].file.ends_with("b.txt"));
+        let mut mappings = Mappings::default();
+        assert!(mappings.read(&dir.join("self.txt"), &mut Vec::new()));
+        assert_eq!(mappings.rule_count(), 1);
+        assert_eq!(mappings.warnings.len(), 1);
+        assert_eq!(mappings.warnings[0].reason, "include cycle");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_mapping_file_variable() {
+        let path = env::temp_dir().join("wd40_test_filetypes_variable.txt");
+        fs::write(&path, "foo => rust\n").unwrap();
+        let name = path.to_str().unwrap().to_owned();
+        let mappings = Mappings::load_from(Some(name.clone()));
+        assert!(mappings.from_variable);
+        assert_eq!(mappings.path, Some(name.clone()));
+        assert_eq!(mappings.file_type_name("main.foo"), Some("rust"));
+        assert_eq!(mappings.file_type_name("main.rs"), None);
+        // An empty variable is the same as none
+        assert!(!Mappings::load_from(Some(String::new())).from_variable);
+        assert!(!Mappings::load_from(None).from_variable);
+        let _ = fs::remove_file(&path);
+        // A file that can't be read is reported rather than silently skipped
+        let missing = Mappings::load_from(Some(name));
+        assert_eq!(missing.rule_count(), 0);
+        assert_eq!(missing.warnings.len(), 1);
+        assert_eq!(missing.warnings[0].reason, "can't be read");
+    }
+
+    #[test]
+    fn test_matches_glob() {
+        assert!(matches_glob("*.rs", "main.rs"));
+        assert!(matches_glob("a?c", "abc"));
+        assert!(matches_glob("a*b*c", "axxbyyc"));
+        assert!(matches_glob("*", ""));
+        assert!(!matches_glob("*.rs", "main.rsx"));
+        assert!(!matches_glob("a?c", "ac"));
+        assert!(!matches_glob("", "a"));
     }
 }
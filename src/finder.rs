@@ -0,0 +1,196 @@
+use std::fs;
+use std::path::Path;
+
+/// Deepest directory level listed below the starting directory.
+const MAX_DEPTH: usize = 8;
+/// Most files listed, so that huge trees stay responsive.
+const MAX_FILES: usize = 20_000;
+/// Most candidates shown for a query.
+const MAX_MATCHES: usize = 200;
+
+/// Fuzzy file picker: filters the files under a directory as a query is typed.
+pub struct Finder {
+    pub query: String,
+    files: Vec<String>,
+    /// Whether `files` are in order of preference, which ties between matches keep.
+    ordered: bool,
+    matches: Vec<String>,
+    selected: usize,
+}
+
+impl Finder {
+    /// Lists the files under `root`.
+    #[must_use]
+    pub fn new(root: &Path) -> Self {
+        Self::create(list_files(root), false)
+    }
+    /// Picks from `files`, best first; equally good matches keep their order.
+    #[must_use]
+    pub fn with_files(files: Vec<String>) -> Self {
+        Self::create(files, true)
+    }
+    fn create(files: Vec<String>, ordered: bool) -> Self {
+        let mut finder = Self {
+            query: String::new(),
+            files,
+            ordered,
+            matches: Vec::new(),
+            selected: 0,
+        };
+        finder.update();
+        finder
+    }
+    pub fn push(&mut self, c: char) {
+        self.query.push(c);
+        self.update();
+    }
+    pub fn pop(&mut self) {
+        self.query.pop();
+        self.update();
+    }
+    /// Moves the selection up (`down` false) or down one candidate.
+    pub fn move_selection(&mut self, down: bool) {
+        self.selected = if down {
+            self.selected
+                .saturating_add(1)
+                .min(self.matches.len().saturating_sub(1))
+        } else {
+            self.selected.saturating_sub(1)
+        };
+    }
+    #[must_use]
+    pub fn selected(&self) -> Option<&str> {
+        self.matches.get(self.selected).map(String::as_str)
+    }
+    #[must_use]
+    pub fn selected_index(&self) -> usize {
+        self.selected
+    }
+    /// The candidates, best first, with the selected one marked.
+    #[must_use]
+    pub fn lines(&self) -> Vec<String> {
+        if self.matches.is_empty() {
+            return vec!["(no matching files)".to_owned()];
+        }
+        self.matches
+            .iter()
+            .enumerate()
+            .map(|(index, file)| {
+                let marker = if index == self.selected { ">" } else { " " };
+                format!("{marker} {file}")
+            })
+            .collect()
+    }
+    fn update(&mut self) {
+        let mut scored: Vec<(usize, &String)> = self
+            .files
+            .iter()
+            .filter_map(|file| Some((fuzzy_score(&self.query, file)?, file)))
+            .collect();
+        if self.ordered {
+            scored.sort_by(|(a_score, _), (b_score, _)| b_score.cmp(a_score));
+        } else {
+            scored.sort_by(|(a_score, a), (b_score, b)| {
+                b_score
+                    .cmp(a_score)
+                    .then(a.len().cmp(&b.len()))
+                    .then(a.cmp(b))
+            });
+        }
+        self.matches = scored
+            .into_iter()
+            .take(MAX_MATCHES)
+            .map(|(_, file)| file.clone())
+            .collect();
+        self.selected = 0;
+    }
+}
+
+/// Paths of the files under `root`, relative to it, down to `MAX_DEPTH`
+/// directories and skipping `.git`. Stops after `MAX_FILES` files.
+#[must_use]
+pub fn list_files(root: &Path) -> Vec<String> {
+    let mut files = Vec::new();
+    let mut directories = vec![(root.to_path_buf(), 0_usize)];
+    while let Some((directory, depth)) = directories.pop() {
+        let Ok(entries) = fs::read_dir(&directory) else {
+            continue;
+        };
+        let mut entries: Vec<_> = entries.filter_map(Result::ok).collect();
+        entries.sort_by_key(fs::DirEntry::file_name);
+        for entry in entries.into_iter().rev() {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                if depth < MAX_DEPTH && entry.file_name() != ".git" {
+                    directories.push((path, depth.saturating_add(1)));
+                }
+            } else if files.len() >= MAX_FILES {
+                return files;
+            } else if let Some(relative) = path.strip_prefix(root).ok().and_then(Path::to_str) {
+                files.push(relative.to_owned());
+            }
+        }
+    }
+    files
+}
+
+/// Scores `candidate` when the characters of `query` appear in it in order,
+/// ignoring case. Runs of consecutive characters and matches at the start of a
+/// path component or word score higher.
+#[must_use]
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<usize> {
+    let mut score: usize = 0;
+    let mut previous: Option<char> = None;
+    let mut previous_matched = false;
+    let mut wanted = query.chars().flat_map(char::to_lowercase).peekable();
+    for c in candidate.chars() {
+        let Some(&next) = wanted.peek() else {
+            break;
+        };
+        let matched = c.to_lowercase().eq(core::iter::once(next));
+        if matched {
+            wanted.next();
+            score = score.saturating_add(1);
+            if previous_matched {
+                score = score.saturating_add(5);
+            }
+            if previous.is_none_or(|p| matches!(p, '/' | '_' | '-' | '.' | ' ')) {
+                score = score.saturating_add(3);
+            }
+        }
+        previous_matched = matched;
+        previous = Some(c);
+    }
+    wanted.peek().is_none().then_some(score)
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_fuzzy_score() {
+        // Matches at the start of a path component beat ones inside a word
+        assert!(fuzzy_score("main", "src/main.rs") > fuzzy_score("main", "src/domain.rs"));
+        // Consecutive characters beat scattered ones
+        assert!(fuzzy_score("abc", "abc.rs") > fuzzy_score("abc", "a_b_c.rs"));
+        assert!(fuzzy_score("abc", "a_b_c.rs") > fuzzy_score("abc", "axbxc.rs"));
+        // Every character of the query has to appear, in order
+        assert_eq!(fuzzy_score("ba", "abc"), None);
+        assert_eq!(fuzzy_score("abcd", "abc"), None);
+        assert_eq!(fuzzy_score("x", ""), None);
+        assert_eq!(fuzzy_score("", "abc"), Some(0));
+        // Case is ignored on both sides
+        assert_eq!(
+            fuzzy_score("MAIN", "src/main.rs"),
+            fuzzy_score("main", "src/main.rs")
+        );
+        assert_eq!(
+            fuzzy_score("main", "SRC/MAIN.RS"),
+            fuzzy_score("main", "src/main.rs")
+        );
+    }
+}
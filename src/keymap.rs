@@ -0,0 +1,209 @@
+use crossterm::event::{KeyCode, KeyModifiers};
+use std::collections::HashMap;
+
+/// Something a key can be mapped to with `:map`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Save,
+    Quit,
+    Find,
+    SearchNext,
+    SearchPrevious,
+    FindFile,
+    Command,
+    NormalMode,
+    InsertMode,
+    DuplicateLine,
+    JoinLines,
+    ToggleComment,
+    MatchingBracket,
+    JumpBack,
+    JumpForward,
+    AddCursor,
+    NextBuffer,
+    PreviousBuffer,
+    SwitchPane,
+    Repeat,
+    Help,
+}
+
+/// The names `:map` accepts for each action.
+const ACTIONS: &[(&str, Action)] = &[
+    ("save", Action::Save),
+    ("quit", Action::Quit),
+    ("find", Action::Find),
+    ("search_next", Action::SearchNext),
+    ("search_previous", Action::SearchPrevious),
+    ("find_file", Action::FindFile),
+    ("command", Action::Command),
+    ("normal_mode", Action::NormalMode),
+    ("insert_mode", Action::InsertMode),
+    ("duplicate_line", Action::DuplicateLine),
+    ("join_lines", Action::JoinLines),
+    ("toggle_comment", Action::ToggleComment),
+    ("matching_bracket", Action::MatchingBracket),
+    ("jump_back", Action::JumpBack),
+    ("jump_forward", Action::JumpForward),
+    ("add_cursor", Action::AddCursor),
+    ("next_buffer", Action::NextBuffer),
+    ("previous_buffer", Action::PreviousBuffer),
+    ("switch_pane", Action::SwitchPane),
+    ("repeat", Action::Repeat),
+    ("help", Action::Help),
+];
+
+impl Action {
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        ACTIONS
+            .iter()
+            .find(|(action_name, _)| *action_name == name)
+            .map(|&(_, action)| action)
+    }
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        ACTIONS
+            .iter()
+            .find(|(_, action)| *action == self)
+            .map_or("", |(name, _)| name)
+    }
+}
+
+/// Keys bound to actions with `:map`, which take the place of what the keys
+/// normally do.
+#[derive(Default)]
+pub struct Keymap {
+    /// The action of each key, and the key as it was written.
+    bindings: HashMap<(KeyCode, KeyModifiers), (String, Action)>,
+}
+
+impl Keymap {
+    /// Binds the key written as `spec`, such as `ctrl-s`, to the action called
+    /// `action`. The error says which of the two is not known.
+    pub fn bind(&mut self, spec: &str, action: &str) -> Result<(), String> {
+        let key = parse_key(spec).ok_or_else(|| format!("Unknown key: {spec}"))?;
+        let action = Action::from_name(action).ok_or_else(|| {
+            let names: Vec<&str> = ACTIONS.iter().map(|(name, _)| *name).collect();
+            format!("Unknown action: {action} (expected {})", names.join(", "))
+        })?;
+        self.bindings.insert(key, (spec.to_owned(), action));
+        Ok(())
+    }
+    /// Removes the binding of `spec`. Returns false when it had none.
+    pub fn unbind(&mut self, spec: &str) -> bool {
+        parse_key(spec).is_some_and(|key| self.bindings.remove(&key).is_some())
+    }
+    /// The action bound to a pressed key.
+    #[must_use]
+    pub fn get(&self, code: KeyCode, modifiers: KeyModifiers) -> Option<Action> {
+        self.bindings
+            .get(&normalize(code, modifiers))
+            .map(|&(_, action)| action)
+    }
+    /// One `key action` line per binding, sorted by key.
+    #[must_use]
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .bindings
+            .values()
+            .map(|(spec, action)| format!("{spec:<16} {}", action.name()))
+            .collect();
+        lines.sort();
+        lines
+    }
+}
+
+/// Parses a key such as `ctrl-s`, `alt-up`, `shift-tab`, `f5` or `x`. Modifiers
+/// are `ctrl-`, `alt-` and `shift-`, in any order.
+#[must_use]
+pub fn parse_key(spec: &str) -> Option<(KeyCode, KeyModifiers)> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut rest = spec;
+    // A trailing `-` is the minus key, as in `ctrl--`
+    while let Some((modifier, key)) = rest.split_once('-').filter(|(_, key)| !key.is_empty()) {
+        modifiers |= match modifier.to_ascii_lowercase().as_str() {
+            "ctrl" => KeyModifiers::CONTROL,
+            "alt" => KeyModifiers::ALT,
+            "shift" => KeyModifiers::SHIFT,
+            _ => return None,
+        };
+        rest = key;
+    }
+    let mut chars = rest.chars();
+    let code = match (chars.next(), chars.next()) {
+        (Some(c), None) => KeyCode::Char(c),
+        _ => match rest.to_ascii_lowercase().as_str() {
+            "enter" => KeyCode::Enter,
+            "esc" => KeyCode::Esc,
+            "tab" => KeyCode::Tab,
+            "backspace" => KeyCode::Backspace,
+            "delete" => KeyCode::Delete,
+            "insert" => KeyCode::Insert,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "space" => KeyCode::Char(' '),
+            name => KeyCode::F(
+                name.strip_prefix('f')?
+                    .parse()
+                    .ok()
+                    .filter(|number| (1..=24).contains(number))?,
+            ),
+        },
+    };
+    Some(normalize(code, modifiers))
+}
+
+/// Writes Shift into the key where terminals do: as an uppercase letter, or
+/// Shift-Tab as BackTab.
+fn normalize(code: KeyCode, modifiers: KeyModifiers) -> (KeyCode, KeyModifiers) {
+    if !modifiers.contains(KeyModifiers::SHIFT) {
+        return (code, modifiers);
+    }
+    let code = match code {
+        KeyCode::Char(c) => KeyCode::Char(c.to_ascii_uppercase()),
+        KeyCode::Tab | KeyCode::BackTab => KeyCode::BackTab,
+        _ => return (code, modifiers),
+    };
+    (code, modifiers.difference(KeyModifiers::SHIFT))
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_parse_key() {
+        let ctrl = KeyModifiers::CONTROL;
+        assert_eq!(parse_key("ctrl-s"), Some((KeyCode::Char('s'), ctrl)));
+        assert_eq!(parse_key("Ctrl--"), Some((KeyCode::Char('-'), ctrl)));
+        assert_eq!(
+            parse_key("alt-shift-up"),
+            Some((KeyCode::Up, KeyModifiers::ALT | KeyModifiers::SHIFT))
+        );
+        assert_eq!(
+            parse_key("shift-tab"),
+            Some((KeyCode::BackTab, KeyModifiers::NONE))
+        );
+        assert_eq!(parse_key("f12"), Some((KeyCode::F(12), KeyModifiers::NONE)));
+        assert_eq!(parse_key("super-s"), None);
+        assert_eq!(parse_key("f25"), None);
+
+        let mut keymap = Keymap::default();
+        assert!(keymap.bind("ctrl-shift-s", "save").is_ok());
+        assert!(keymap
+            .bind("ctrl-s", "sav")
+            .unwrap_err()
+            .starts_with("Unknown action: sav"));
+        assert_eq!(
+            keymap.get(KeyCode::Char('S'), ctrl | KeyModifiers::SHIFT),
+            Some(Action::Save)
+        );
+        assert_eq!(keymap.get(KeyCode::Char('s'), ctrl), None);
+    }
+}
@@ -0,0 +1,30 @@
+mod command_line;
+mod config;
+mod document;
+mod editor;
+mod filetype;
+mod finder;
+mod highlighting;
+mod keymap;
+mod options;
+mod pattern;
+mod positions;
+mod recent;
+mod row;
+mod state;
+mod terminal;
+mod test_terminal;
+mod theme;
+pub use command_line::CommandLine;
+pub use crossterm::event::{KeyCode, KeyModifiers};
+pub use document::{Document, LineEnding, SortOptions};
+pub use editor::{Editor, Mode, Position, SearchDirection};
+pub use filetype::{FileType, HighlightingOptions, Mappings};
+pub use finder::Finder;
+pub use keymap::Keymap;
+pub use options::Options;
+pub use pattern::Pattern;
+pub use row::{RenderOptions, Row};
+pub use terminal::{Backend, ColorMode, Size, Terminal};
+pub use test_terminal::TestTerminal;
+pub use theme::{ColorScheme, Theme};
@@ -1,15 +1,12 @@
-mod document;
-mod editor;
-mod filetype;
-mod highlighting;
-mod row;
-mod terminal;
-pub use document::Document;
-use editor::Editor;
-pub use editor::{Position, SearchDirection};
-pub use filetype::{FileType, HighlightingOptions};
-pub use row::Row;
-pub use terminal::Terminal;
+use wd40text::Editor;
+
 fn main() {
-    Editor::default().run();
+    let mut editor = Editor::default();
+    editor.run();
+    let exit_message = editor.exit_message().map(str::to_owned);
+    // Dropping the editor restores the terminal, which clears the screen
+    drop(editor);
+    if let Some(message) = exit_message {
+        eprintln!("{message}");
+    }
 }
@@ -0,0 +1,109 @@
+use crate::theme;
+use crate::{RenderOptions, Theme};
+
+const DEFAULT_TEXTWIDTH: usize = 80;
+const DEFAULT_CONFIRM: u8 = 3;
+const DEFAULT_TAB_WIDTH: usize = 4;
+
+/// Shown in an empty buffer unless changed with `:set welcome=text`.
+#[must_use]
+pub fn default_welcome_message() -> String {
+    format!("wd40 -- version {}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Editor settings changed with `:set`.
+pub struct Options {
+    pub wrap: bool,
+    pub read_only: bool,
+    pub scrollbar: bool,
+    /// Lists the open buffers on the top row when there is more than one.
+    pub showtabs: bool,
+    pub autopairs: bool,
+    pub render: RenderOptions,
+    pub theme: Theme,
+    /// Name of the color scheme in `render.colors`, set with `:colorscheme`.
+    pub colorscheme: String,
+    /// How many times `:q` is repeated to quit with unsaved changes.
+    pub confirm: u8,
+    /// Column limit used by `:reflow` when no width is given.
+    pub textwidth: usize,
+    /// Lines kept visible above and below the cursor when scrolling.
+    pub scroll_off: usize,
+    /// Spaces added or removed by one level of Tab / Shift-Tab indentation.
+    pub tab_width: usize,
+    /// Shows line numbers in a gutter; with `relative_number` only on the cursor line.
+    pub number: bool,
+    /// Shows each line's distance from the cursor line in the gutter.
+    pub relative_number: bool,
+    /// Drawn centered in an empty buffer, one line per `\n`; None shows nothing.
+    pub welcome_message: Option<String>,
+    /// Lists recently opened files in an empty buffer instead of the welcome message.
+    pub start_screen: bool,
+    /// Keeps the matches of an accepted search highlighted until the next edit,
+    /// Esc or `:noh`.
+    pub hlsearch: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            wrap: false,
+            read_only: false,
+            scrollbar: false,
+            showtabs: false,
+            autopairs: true,
+            render: RenderOptions::default(),
+            theme: Theme::default(),
+            colorscheme: "dark".to_owned(),
+            confirm: DEFAULT_CONFIRM,
+            textwidth: DEFAULT_TEXTWIDTH,
+            scroll_off: 0,
+            tab_width: DEFAULT_TAB_WIDTH,
+            number: false,
+            relative_number: false,
+            welcome_message: Some(default_welcome_message()),
+            start_screen: false,
+            hlsearch: true,
+        }
+    }
+}
+
+impl Options {
+    /// Each option the way `:set` takes it, such as `nowrap` or `textwidth=80`,
+    /// sorted by name.
+    #[must_use]
+    pub fn values(&self) -> Vec<String> {
+        vec![
+            flag("autopairs", self.autopairs),
+            format!("colorscheme={}", self.colorscheme),
+            format!("confirm={}", self.confirm),
+            flag("hlsearch", self.hlsearch),
+            flag("list", self.render.list),
+            flag("number", self.number),
+            flag("readonly", self.read_only),
+            flag("relativenumber", self.relative_number),
+            flag("scrollbar", self.scrollbar),
+            format!("scrolloff={}", self.scroll_off),
+            flag("showtabs", self.showtabs),
+            flag("showtrailing", self.render.show_trailing),
+            flag("startscreen", self.start_screen),
+            format!("statusbg={}", theme::format_hex_color(self.theme.status_bg)),
+            format!("statusfg={}", theme::format_hex_color(self.theme.status_fg)),
+            format!("tabwidth={}", self.tab_width),
+            format!("textwidth={}", self.textwidth),
+            self.welcome_message.as_ref().map_or_else(
+                || "nowelcome".to_owned(),
+                |message| format!("welcome={}", message.replace('\n', "\\n")),
+            ),
+            flag("wrap", self.wrap),
+        ]
+    }
+}
+
+fn flag(name: &str, on: bool) -> String {
+    if on {
+        name.to_owned()
+    } else {
+        format!("no{name}")
+    }
+}
@@ -0,0 +1,40 @@
+use regex::Regex;
+
+/// A search query: matched literally, or compiled as a regular expression.
+#[derive(Clone)]
+pub enum Pattern {
+    Literal(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// Compiles `query` as a regex when `regex` is set, otherwise matches it literally.
+    pub fn new(query: &str, regex: bool) -> Result<Self, regex::Error> {
+        if regex {
+            Regex::new(query).map(Self::Regex)
+        } else {
+            Ok(Self::Literal(query.to_owned()))
+        }
+    }
+    /// True when the pattern occurs anywhere in `text`.
+    #[must_use]
+    pub fn is_match(&self, text: &str) -> bool {
+        match self {
+            Self::Literal(query) => text.contains(query.as_str()),
+            Self::Regex(regex) => regex.is_match(text),
+        }
+    }
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Literal(query) => query.is_empty(),
+            Self::Regex(regex) => regex.as_str().is_empty(),
+        }
+    }
+}
+
+impl From<&str> for Pattern {
+    fn from(query: &str) -> Self {
+        Self::Literal(query.to_owned())
+    }
+}
@@ -0,0 +1,85 @@
+use crate::state;
+use crate::Position;
+use std::fs;
+use std::path::Path;
+
+/// Most files whose cursor position is remembered; the oldest are dropped first.
+const MAX_ENTRIES: usize = 1000;
+
+/// `~/.wd40/positions`, one `line column path` entry per line.
+const POSITIONS_FILE: &str = "positions";
+
+fn parse_entry(line: &str) -> Option<(Position, &str)> {
+    let mut parts = line.splitn(3, ' ');
+    let y = parts.next()?.parse().ok()?;
+    let x = parts.next()?.parse().ok()?;
+    Some((Position { x, y }, parts.next()?))
+}
+
+fn read_entries(dir: &Path) -> Vec<(Position, String)> {
+    state::read_lines(dir, POSITIONS_FILE)
+        .iter()
+        .filter_map(|line| parse_entry(line))
+        .map(|(position, path)| (position, path.to_owned()))
+        .collect()
+}
+
+/// Where the cursor was when `file_name` was last closed, as kept in `dir`.
+#[must_use]
+pub fn load(dir: &Path, file_name: &str) -> Option<Position> {
+    let path = fs::canonicalize(file_name).ok()?;
+    let path = path.to_str()?;
+    read_entries(dir)
+        .into_iter()
+        .rev()
+        .find(|(_, entry)| entry == path)
+        .map(|(position, _)| position)
+}
+
+/// Remembers the cursor position of each `(file_name, position)` in `dir`, and
+/// drops entries for files that no longer exist.
+pub fn store(dir: &Path, files: &[(&str, Position)]) {
+    let updated: Vec<(Position, String)> = files
+        .iter()
+        .filter_map(|(file_name, position)| {
+            let path = fs::canonicalize(file_name).ok()?;
+            Some((position.clone(), path.to_str()?.to_owned()))
+        })
+        .collect();
+    if updated.is_empty() {
+        return;
+    }
+    let mut entries: Vec<(Position, String)> = read_entries(dir)
+        .into_iter()
+        .filter(|(_, path)| !updated.iter().any(|(_, new)| new == path) && Path::new(path).exists())
+        .collect();
+    entries.extend(updated);
+    let skip = entries.len().saturating_sub(MAX_ENTRIES);
+    let lines: Vec<String> = entries
+        .iter()
+        .skip(skip)
+        .map(|(position, path)| format!("{} {} {path}", position.y, position.x))
+        .collect();
+    state::write_lines(dir, POSITIONS_FILE, &lines);
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_store_and_load() {
+        let dir = std::env::temp_dir().join("wd40_test_positions_state");
+        let _ = fs::remove_dir_all(&dir);
+        let file = std::env::temp_dir().join("wd40_test_positions_file");
+        fs::write(&file, "").unwrap();
+        let file_name = file.to_str().unwrap();
+        assert_eq!(load(&dir, file_name), None);
+        store(&dir, &[(file_name, Position { x: 3, y: 7 })]);
+        store(&dir, &[(file_name, Position { x: 1, y: 2 })]);
+        assert_eq!(load(&dir, file_name), Some(Position { x: 1, y: 2 }));
+        assert_eq!(read_entries(&dir).len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_file(&file);
+    }
+}
@@ -0,0 +1,57 @@
+use crate::state;
+use std::fs;
+use std::path::Path;
+
+/// Most files remembered.
+const MAX_FILES: usize = 20;
+/// `~/.wd40/recent`, one absolute path per line, most recent first.
+const RECENT_FILE: &str = "recent";
+
+/// Moves `file_name` to the top of the recently opened files kept in `dir`.
+pub fn add(dir: &Path, file_name: &str) {
+    let Some(path) = fs::canonicalize(file_name)
+        .ok()
+        .and_then(|path| path.to_str().map(str::to_owned))
+    else {
+        return;
+    };
+    let mut files = state::read_lines(dir, RECENT_FILE);
+    files.retain(|file| *file != path);
+    files.insert(0, path);
+    files.truncate(MAX_FILES);
+    state::write_lines(dir, RECENT_FILE, &files);
+}
+
+/// The recently opened files kept in `dir` that still exist, most recent first.
+#[must_use]
+pub fn files(dir: &Path) -> Vec<String> {
+    state::read_lines(dir, RECENT_FILE)
+        .into_iter()
+        .filter(|file| Path::new(file).exists())
+        .collect()
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_add_moves_to_top() {
+        let dir = std::env::temp_dir();
+        let paths: Vec<String> = ["wd40_test_recent_a", "wd40_test_recent_b"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                fs::write(&path, "").unwrap();
+                fs::canonicalize(path).unwrap().to_str().unwrap().to_owned()
+            })
+            .collect();
+        let state_dir = dir.join("wd40_test_recent_state");
+        let _ = fs::remove_dir_all(&state_dir);
+        add(&state_dir, &paths[1]);
+        add(&state_dir, &paths[0]);
+        add(&state_dir, &paths[1]);
+        assert_eq!(files(&state_dir), [paths[1].clone(), paths[0].clone()]);
+        let _ = fs::remove_dir_all(&state_dir);
+    }
+}
@@ -1,15 +1,34 @@
 use crate::highlighting;
+use crate::ColorMode;
+use crate::ColorScheme;
 use crate::HighlightingOptions;
+use crate::Pattern;
 use crate::SearchDirection;
-use crossterm::style::{Color, SetForegroundColor};
-use core::cmp;
+use core::ops::Range;
+use crossterm::style::{Attribute, Color, SetAttribute, SetBackgroundColor, SetForegroundColor};
 use unicode_segmentation::UnicodeSegmentation as _;
+use unicode_width::UnicodeWidthStr as _;
+
+/// Display-only transforms applied by `Row::render_with`.
+#[derive(Default, Clone, Copy)]
+pub struct RenderOptions {
+    /// Gives trailing spaces and tabs a red background.
+    pub show_trailing: bool,
+    /// Shows tabs as `→`, spaces as `·` and the end of the row as `$`.
+    pub list: bool,
+    pub colors: ColorScheme,
+    /// Colors are left out entirely in `ColorMode::None`.
+    pub color_mode: ColorMode,
+}
 
 #[derive(Default)]
 pub struct Row {
     string: String,
     highlighting: Vec<highlighting::Type>,
     pub is_highlighted: bool,
+    /// Block left open by the previous row when this row was last highlighted.
+    start_block: highlighting::Block,
+    open_block: highlighting::Block,
     len: usize,
 }
 
@@ -19,52 +38,187 @@ impl From<&str> for Row {
             string: String::from(slice),
             highlighting: Vec::new(),
             is_highlighted: false,
-            len: slice.graphemes(true).count(),
+            start_block: highlighting::Block::None,
+            open_block: highlighting::Block::None,
+            len: grapheme_count(slice),
         }
     }
 }
 
+/// Graphemes in `text`. Each ASCII character is one, except in `\r\n`, so the
+/// common case skips the grapheme segmentation that makes opening large files slow.
+fn grapheme_count(text: &str) -> usize {
+    if text.is_ascii() && !text.contains('\r') {
+        text.len()
+    } else {
+        text.graphemes(true).count()
+    }
+}
+
 impl Row {
-    #[must_use] 
-    pub fn render(&self, start: usize, end: usize) -> String {
-        let end = cmp::min(end, self.string.len());
-        let start = cmp::min(start, end);
+    /// Renders the display columns `start..end`, drawing the `selected` graphemes
+    /// in reverse video. A wide grapheme cut by the left edge is shown as padding,
+    /// one that does not fit at the right edge is left out.
+    #[must_use]
+    pub fn render(&self, start: usize, end: usize, selected: Option<Range<usize>>) -> String {
+        self.render_with(start, end, selected, RenderOptions::default())
+    }
+    /// Like `render`, with the display transforms in `options`.
+    #[must_use]
+    pub fn render_with(
+        &self,
+        start: usize,
+        end: usize,
+        selected: Option<Range<usize>>,
+        options: RenderOptions,
+    ) -> String {
+        let trailing_start = if options.show_trailing {
+            self.string
+                .trim_end_matches([' ', '\t'])
+                .graphemes(true)
+                .count()
+        } else {
+            usize::MAX
+        };
+        let mut in_trailing = false;
         let mut result = String::new();
         let mut current_highlighting = &highlighting::Type::None;
-        #[expect(clippy::arithmetic_side_effects)]
-        for (index, grapheme) in self.string[..]
-            .graphemes(true)
-            .enumerate()
-            .skip(start)
-            .take(end - start)
-        {
-            if let Some(c) = grapheme.chars().next() {
-                let highlighting_type = self
-                    .highlighting
-                    .get(index)
-                    .unwrap_or(&highlighting::Type::None);
-                if highlighting_type != current_highlighting {
-                    current_highlighting = highlighting_type;
-                    let start_highlight =
-                        format!("{}", SetForegroundColor(highlighting_type.to_color()));
-                    result.push_str(&start_highlight);
+        let mut in_selection = false;
+        let mut column: usize = 0;
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            let next_column = column.saturating_add(grapheme_width(grapheme));
+            if next_column > end {
+                break;
+            }
+            if column < start {
+                if next_column > start {
+                    result.push_str(&" ".repeat(next_column.saturating_sub(start)));
                 }
-                if c == '\t' {
-                    result.push(' ');
+                column = next_column;
+                continue;
+            }
+            column = next_column;
+            let is_selected = selected
+                .as_ref()
+                .is_some_and(|range| range.contains(&index));
+            if is_selected != in_selection {
+                in_selection = is_selected;
+                let attribute = if is_selected {
+                    Attribute::Reverse
                 } else {
-                    result.push(c);
+                    Attribute::NoReverse
+                };
+                result.push_str(&format!("{}", SetAttribute(attribute)));
+            }
+            if index >= trailing_start && !in_trailing {
+                in_trailing = true;
+                if let Some(color) = options.color_mode.apply(Color::DarkRed) {
+                    result.push_str(&format!("{}", SetBackgroundColor(color)));
+                }
+            }
+            let highlighting_type = self
+                .highlighting
+                .get(index)
+                .unwrap_or(&highlighting::Type::None);
+            if highlighting_type != current_highlighting {
+                current_highlighting = highlighting_type;
+                let color = options.colors.color(*highlighting_type);
+                if let Some(color) = options.color_mode.apply(color) {
+                    result.push_str(&format!("{}", SetForegroundColor(color)));
                 }
             }
+            match grapheme {
+                "\t" if options.list => result.push('→'),
+                "\t" => result.push(' '),
+                " " if options.list => result.push('·'),
+                _ => result.push_str(grapheme),
+            }
+        }
+        if in_selection {
+            result.push_str(&format!("{}", SetAttribute(Attribute::NoReverse)));
+        }
+        if in_trailing && options.color_mode != ColorMode::None {
+            result.push_str(&format!("{}", SetBackgroundColor(Color::Reset)));
+        }
+        if options.list && (start..end).contains(&self.width()) {
+            result.push('$');
+        }
+        if options.color_mode != ColorMode::None {
+            let end_highlight = format!("{}", SetForegroundColor(Color::Reset));
+            result.push_str(&end_highlight);
         }
-        let end_highlight = format!("{}", SetForegroundColor(Color::Reset));
-        result.push_str(&end_highlight);
         result
     }
-    #[must_use] 
+    /// Display width of the whole row in terminal columns.
+    #[must_use]
+    pub fn width(&self) -> usize {
+        self.width_until(self.len)
+    }
+    /// Display column at which grapheme `index` starts.
+    #[must_use]
+    pub fn width_until(&self, index: usize) -> usize {
+        self.string[..]
+            .graphemes(true)
+            .take(index)
+            .map(grapheme_width)
+            .sum()
+    }
+    /// Grapheme indices at which each visual line starts when the row is soft-wrapped
+    /// to `width` columns. Always starts with 0; a wide grapheme is never split.
+    #[must_use]
+    pub fn wrap_points(&self, width: usize) -> Vec<usize> {
+        let mut points = vec![0];
+        let mut column: usize = 0;
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            let grapheme_width = grapheme_width(grapheme);
+            if column > 0 && column.saturating_add(grapheme_width) > width {
+                points.push(index);
+                column = 0;
+            }
+            column = column.saturating_add(grapheme_width);
+        }
+        points
+    }
+    /// Index of the grapheme covering display column `column`, or `len()` past the end.
+    #[must_use]
+    pub fn index_at_width(&self, column: usize) -> usize {
+        let mut width: usize = 0;
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            width = width.saturating_add(grapheme_width(grapheme));
+            if width > column {
+                return index;
+            }
+        }
+        self.len
+    }
+    /// Index of the first grapheme starting at or after display column `column`,
+    /// or `len()` past the end.
+    #[must_use]
+    pub fn index_from_width(&self, column: usize) -> usize {
+        let mut width: usize = 0;
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            if width >= column {
+                return index;
+            }
+            width = width.saturating_add(grapheme_width(grapheme));
+        }
+        self.len
+    }
+    /// The graphemes overlapping display `columns`, so a wide grapheme cut by
+    /// either edge is included. Empty when `columns` is.
+    #[must_use]
+    pub fn indices_in_columns(&self, columns: Range<usize>) -> Range<usize> {
+        let end = self.index_from_width(columns.end);
+        if columns.is_empty() {
+            return end..end;
+        }
+        self.index_at_width(columns.start).min(end)..end
+    }
+    #[must_use]
     pub fn len(&self) -> usize {
         self.len
     }
-    #[must_use] 
+    #[must_use]
     pub fn is_empty(&self) -> bool {
         self.len == 0
     }
@@ -72,6 +226,7 @@ impl Row {
         if at >= self.len() {
             self.string.push(c);
             self.len += 1;
+            self.is_highlighted = false;
             return;
         }
         let mut result: String = String::new();
@@ -86,6 +241,7 @@ impl Row {
         }
         self.len = length;
         self.string = result;
+        self.is_highlighted = false;
     }
     pub fn delete(&mut self, at: usize) {
         if at >= self.len() {
@@ -101,10 +257,12 @@ impl Row {
         }
         self.len = length;
         self.string = result;
+        self.is_highlighted = false;
     }
     pub fn append(&mut self, new: &Self) {
         self.string = format!("{}{}", self.string, new.string);
         self.len += new.len;
+        self.is_highlighted = false;
     }
     pub fn split(&mut self, at: usize) -> Self {
         let mut row: String = String::new();
@@ -128,14 +286,85 @@ impl Row {
             string: splitted_row,
             len: splitted_length,
             is_highlighted: false,
+            start_block: highlighting::Block::None,
+            open_block: highlighting::Block::None,
             highlighting: Vec::new(),
         }
     }
-    #[must_use] 
+    #[must_use]
     pub fn as_bytes(&self) -> &[u8] {
         self.string.as_bytes()
     }
-    #[must_use] 
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.string
+    }
+    /// Start of the word before `at`, skipping whitespace first. A run of
+    /// punctuation counts as a word.
+    #[must_use]
+    pub fn previous_word_start(&self, at: usize) -> usize {
+        let class = |index: usize| {
+            self.char_at(index).map(|c| {
+                if c.is_whitespace() {
+                    0
+                } else if c.is_alphanumeric() || c == '_' {
+                    1
+                } else {
+                    2
+                }
+            })
+        };
+        let mut start = at.min(self.len);
+        while start > 0 && class(start.saturating_sub(1)) == Some(0) {
+            start = start.saturating_sub(1);
+        }
+        let word = start.checked_sub(1).and_then(class);
+        while start > 0 && class(start.saturating_sub(1)) == word {
+            start = start.saturating_sub(1);
+        }
+        start
+    }
+    /// Graphemes of the word (alphanumerics and `_`) at or just before `at`.
+    #[must_use]
+    pub fn word_range(&self, at: usize) -> Option<Range<usize>> {
+        let is_word = |index: usize| {
+            self.char_at(index)
+                .is_some_and(|c| c.is_alphanumeric() || c == '_')
+        };
+        let inside = if is_word(at) {
+            at
+        } else {
+            at.checked_sub(1).filter(|&before| is_word(before))?
+        };
+        let mut start = inside;
+        while start > 0 && is_word(start.saturating_sub(1)) {
+            start = start.saturating_sub(1);
+        }
+        let mut end = inside.saturating_add(1);
+        while is_word(end) {
+            end = end.saturating_add(1);
+        }
+        Some(start..end)
+    }
+    /// The first character of the grapheme at `index`.
+    #[must_use]
+    pub fn char_at(&self, index: usize) -> Option<char> {
+        self.string.graphemes(true).nth(index)?.chars().next()
+    }
+    #[must_use]
+    pub fn highlighting_at(&self, index: usize) -> highlighting::Type {
+        self.highlighting
+            .get(index)
+            .copied()
+            .unwrap_or(highlighting::Type::None)
+    }
+    /// Overrides the highlighting of a single grapheme until the row is re-highlighted.
+    pub fn set_highlight(&mut self, index: usize, hl_type: highlighting::Type) {
+        if let Some(current) = self.highlighting.get_mut(index) {
+            *current = hl_type;
+        }
+    }
+    #[must_use]
     pub fn find(&self, query: &str, at: usize, direction: SearchDirection) -> Option<usize> {
         if at > self.len || query.is_empty() {
             return None;
@@ -174,20 +403,70 @@ impl Row {
         None
     }
 
-    fn highlight_match(&mut self, word: &Option<String>) {
+    /// Finds `pattern` starting at grapheme `at` (forward) or strictly before it
+    /// (backward). Returns the grapheme index and length of the match.
+    #[must_use]
+    pub fn find_pattern(
+        &self,
+        pattern: &Pattern,
+        at: usize,
+        direction: SearchDirection,
+    ) -> Option<(usize, usize)> {
+        match pattern {
+            Pattern::Literal(query) => self
+                .find(query, at, direction)
+                .map(|x| (x, query[..].graphemes(true).count())),
+            Pattern::Regex(regex) => {
+                if at > self.len {
+                    return None;
+                }
+                let byte_at = self.byte_index(at);
+                let found = if direction == SearchDirection::Forward {
+                    regex.find_at(&self.string, byte_at)
+                } else {
+                    regex
+                        .find_iter(&self.string)
+                        .take_while(|found| found.start() < byte_at)
+                        .last()
+                }?;
+                let start = self.grapheme_index(found.start());
+                let end = self.grapheme_index(found.end());
+                Some((start, end.saturating_sub(start)))
+            }
+        }
+    }
+    /// Byte offset of grapheme `index`, or the string length past the end.
+    fn byte_index(&self, index: usize) -> usize {
+        self.string[..]
+            .grapheme_indices(true)
+            .nth(index)
+            .map_or(self.string.len(), |(byte, _)| byte)
+    }
+    /// Index of the grapheme containing byte offset `byte`.
+    fn grapheme_index(&self, byte: usize) -> usize {
+        self.string[..]
+            .grapheme_indices(true)
+            .take_while(|(start, _)| *start < byte)
+            .count()
+    }
+
+    fn highlight_match(&mut self, word: &Option<Pattern>) {
         if let Some(word) = word {
             if word.is_empty() {
                 return;
             }
             let mut index = 0;
-            while let Some(search_match) = self.find(word, index, SearchDirection::Forward) {
-                if let Some(next_index) = search_match.checked_add(word[..].graphemes(true).count())
-                {
-                    #[expect(clippy::indexing_slicing)]
+            while let Some((search_match, len)) =
+                self.find_pattern(word, index, SearchDirection::Forward)
+            {
+                if let Some(next_index) = search_match.checked_add(len) {
                     for i in search_match..next_index {
-                        self.highlighting[i] = highlighting::Type::Match;
+                        if let Some(hl_type) = self.highlighting.get_mut(i) {
+                            *hl_type = highlighting::Type::Match;
+                        }
                     }
-                    index = next_index;
+                    // Step past empty matches so zero-width patterns terminate
+                    index = next_index.max(search_match.saturating_add(1));
                 } else {
                     break;
                 }
@@ -309,50 +588,97 @@ impl Row {
         &mut self,
         index: &mut usize,
         opts: &HighlightingOptions,
-        c: char,
         chars: &[char],
     ) -> bool {
-        if opts.comments() && c == '/' && *index < chars.len() {
-            if let Some(next_char) = chars.get(index.saturating_add(1)) {
-                if *next_char == '/' {
-                    for _ in *index..chars.len() {
-                        self.highlighting.push(highlighting::Type::Comment);
-                        *index += 1;
-                    }
-                    return true;
+        if let Some(token) = opts.line_comment_start() {
+            if starts_with_at(chars, *index, token) {
+                for _ in *index..chars.len() {
+                    self.highlighting.push(highlighting::Type::Comment);
+                    *index += 1;
                 }
+                return true;
             }
         }
         false
     }
-    #[expect(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
     fn highlight_multiline_comment(
+        &mut self,
+        index: &mut usize,
+        opts: &HighlightingOptions,
+        chars: &[char],
+        block: &mut highlighting::Block,
+    ) -> bool {
+        let (Some(start), Some(end)) =
+            (opts.multiline_comment_start(), opts.multiline_comment_end())
+        else {
+            return false;
+        };
+        if starts_with_at(chars, *index, start) {
+            let closed = self.highlight_block(
+                index,
+                chars,
+                end,
+                index.saturating_add(start.chars().count()),
+                highlighting::Type::MultilineComment,
+            );
+            *block = if closed {
+                highlighting::Block::None
+            } else {
+                highlighting::Block::Comment
+            };
+            return true;
+        }
+        false
+    }
+
+    fn highlight_multiline_string(
         &mut self,
         index: &mut usize,
         opts: &HighlightingOptions,
         c: char,
         chars: &[char],
+        block: &mut highlighting::Block,
     ) -> bool {
-        if opts.comments() && c == '/' && *index < chars.len() {
-            if let Some(next_char) = chars.get(index.saturating_add(1)) {
-                if *next_char == '*' {
-                    let closing_index =
-                        if let Some(closing_index) = self.string[*index + 2..].find("*/") {
-                            *index + closing_index + 4
-                        } else {
-                            chars.len()
-                        };
-                    for _ in *index..closing_index {
-                        self.highlighting.push(highlighting::Type::MultilineComment);
-                        *index += 1;
-                    }
-                    return true;
-                }
+        if opts.multiline_strings() && (c == '"' || c == '\'') {
+            let delimiter = c.to_string().repeat(3);
+            if starts_with_at(chars, *index, &delimiter) {
+                let closed = self.highlight_block(
+                    index,
+                    chars,
+                    &delimiter,
+                    index.saturating_add(3),
+                    highlighting::Type::String,
+                );
+                *block = if closed {
+                    highlighting::Block::None
+                } else {
+                    highlighting::Block::String(c)
+                };
+                return true;
             }
         }
         false
     }
 
+    /// Highlights from `index` through the first `closing` found at or after `search_from`,
+    /// or to the end of the row. Returns whether the closing token was found.
+    fn highlight_block(
+        &mut self,
+        index: &mut usize,
+        chars: &[char],
+        closing: &str,
+        search_from: usize,
+        hl_type: highlighting::Type,
+    ) -> bool {
+        let closing_index = find_at(chars, search_from, closing)
+            .map(|found| found.saturating_add(closing.chars().count()));
+        for _ in *index..closing_index.unwrap_or(chars.len()) {
+            self.highlighting.push(hl_type);
+            *index += 1;
+        }
+        closing_index.is_some()
+    }
+
     fn highlight_string(
         &mut self,
         index: &mut usize,
@@ -360,16 +686,19 @@ impl Row {
         c: char,
         chars: &[char],
     ) -> bool {
-        if opts.strings() && c == '"' {
+        if opts.string_delimiters().contains(&c) {
             loop {
                 self.highlighting.push(highlighting::Type::String);
                 *index += 1;
-                if let Some(next_char) = chars.get(*index) {
-                    if *next_char == '"' {
-                        break;
+                match chars.get(*index) {
+                    // Keep an escaped character, including an escaped delimiter, in the string
+                    Some('\\') if chars.get(index.saturating_add(1)).is_some() => {
+                        self.highlighting.push(highlighting::Type::String);
+                        *index += 1;
                     }
-                } else {
-                    break;
+                    Some(next_char) if *next_char == c => break,
+                    Some(_) => {}
+                    None => break,
                 }
             }
             self.highlighting.push(highlighting::Type::String);
@@ -408,47 +737,72 @@ impl Row {
         }
         false
     }
-    #[expect(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
-    pub fn highlight(
-        &mut self,
-        opts: &HighlightingOptions,
-        word: &Option<String>,
-        start_with_comment: bool,
-    ) -> bool {
-        let chars: Vec<char> = self.string.chars().collect();
-        if self.is_highlighted && word.is_none() {
-            if let Some(hl_type) = self.highlighting.last() {
-                if *hl_type == highlighting::Type::MultilineComment
-                    && self.string.len() > 1
-                    && self.string[self.string.len() - 2..] == *"*/"
+    /// Recolours the whole words among `markers` that lie inside comments.
+    fn highlight_comment_markers(&mut self, markers: &[String], chars: &[char]) {
+        let is_comment = |hl_type: Option<&highlighting::Type>| {
+            matches!(
+                hl_type,
+                Some(highlighting::Type::Comment | highlighting::Type::MultilineComment)
+            )
+        };
+        for index in 0..chars.len() {
+            let after_word = index
+                .checked_sub(1)
+                .and_then(|previous| chars.get(previous))
+                .is_some_and(|c| !is_separator(*c));
+            if after_word || !is_comment(self.highlighting.get(index)) {
+                continue;
+            }
+            for marker in markers {
+                let end = index.saturating_add(marker.chars().count());
+                if starts_with_at(chars, index, marker)
+                    && chars.get(end).is_none_or(|c| is_separator(*c))
+                    && (index..end).all(|at| is_comment(self.highlighting.get(at)))
                 {
-                    return true;
+                    for hl_type in self.highlighting.get_mut(index..end).into_iter().flatten() {
+                        *hl_type = highlighting::Type::CommentMarker;
+                    }
                 }
             }
-            return false;
         }
+    }
+    #[expect(clippy::arithmetic_side_effects)]
+    pub fn highlight(
+        &mut self,
+        opts: &HighlightingOptions,
+        word: &Option<Pattern>,
+        open_block: highlighting::Block,
+    ) -> highlighting::Block {
+        if self.is_highlighted && word.is_none() && self.start_block == open_block {
+            return self.open_block;
+        }
+        // One entry per grapheme so highlighting indices line up with rendering
+        let chars: Vec<char> = self.string[..]
+            .graphemes(true)
+            .map(|grapheme| grapheme.chars().next().unwrap_or(' '))
+            .collect();
         self.highlighting = Vec::new();
         let mut index = 0;
-        let mut in_ml_comment = start_with_comment;
-        if in_ml_comment {
-            let closing_index = if let Some(closing_index) = self.string.find("*/") {
-                closing_index + 2
-            } else {
-                chars.len()
-            };
-            for _ in 0..closing_index {
-                self.highlighting.push(highlighting::Type::MultilineComment);
+        let mut block = open_block;
+        let continuation = match open_block {
+            highlighting::Block::Comment => opts
+                .multiline_comment_end()
+                .map(|end| (end.to_owned(), highlighting::Type::MultilineComment)),
+            highlighting::Block::String(quote) => {
+                Some((quote.to_string().repeat(3), highlighting::Type::String))
+            }
+            highlighting::Block::None => None,
+        };
+        if let Some((closing, hl_type)) = continuation {
+            if self.highlight_block(&mut index, &chars, &closing, 0, hl_type) {
+                block = highlighting::Block::None;
             }
-            index = closing_index;
         }
         while let Some(c) = chars.get(index) {
-            if self.highlight_multiline_comment(&mut index, opts, *c, &chars) {
-                in_ml_comment = true;
-                continue;
-            }
-            in_ml_comment = false;
-            if self.highlight_char(&mut index, opts, *c, &chars)
-                || self.highlight_comment(&mut index, opts, *c, &chars)
+            if self.highlight_multiline_comment(&mut index, opts, &chars, &mut block)
+                || self.highlight_multiline_string(&mut index, opts, *c, &chars, &mut block)
+                || self.highlight_char(&mut index, opts, *c, &chars)
+                || self.highlight_comment(&mut index, opts, &chars)
                 || self.highlight_primary_keywords(&mut index, opts, &chars)
                 || self.highlight_secondary_keywords(&mut index, opts, &chars)
                 || self.highlight_string(&mut index, opts, *c, &chars)
@@ -459,12 +813,23 @@ impl Row {
             self.highlighting.push(highlighting::Type::None);
             index += 1;
         }
-        self.highlight_match(word);
-        if in_ml_comment && &self.string[self.string.len().saturating_sub(2)..] != "*/" {
-            return true;
+        if opts.comments() {
+            self.highlight_comment_markers(opts.comment_markers(), &chars);
         }
+        self.highlight_match(word);
+        self.start_block = open_block;
+        self.open_block = block;
         self.is_highlighted = true;
-        false
+        block
+    }
+}
+
+/// Terminal columns taken by a grapheme; never zero so every grapheme stays addressable.
+fn grapheme_width(grapheme: &str) -> usize {
+    if grapheme == "\t" {
+        1
+    } else {
+        grapheme.width().max(1)
     }
 }
 
@@ -472,10 +837,30 @@ fn is_separator(c: char) -> bool {
     c.is_ascii_punctuation() || c.is_ascii_whitespace()
 }
 
+fn starts_with_at(chars: &[char], index: usize, token: &str) -> bool {
+    !token.is_empty()
+        && token
+            .chars()
+            .enumerate()
+            .all(|(offset, c)| chars.get(index.saturating_add(offset)) == Some(&c))
+}
+
+fn find_at(chars: &[char], from: usize, token: &str) -> Option<usize> {
+    (from..chars.len()).find(|&index| starts_with_at(chars, index, token))
+}
+
 #[cfg(test)]
 mod test_super {
     use super::*;
 
+    #[test]
+    fn test_grapheme_count() {
+        assert_eq!(Row::from("plain ascii").len(), 11);
+        assert_eq!(Row::from("a\r\nb").len(), 3);
+        assert_eq!(Row::from("a\rb").len(), 3);
+        assert_eq!(Row::from("e\u{301}👍🏽").len(), 2);
+    }
+
     #[test]
     fn test_highlight_find() {
         let mut row = Row::from("1testtest");
@@ -490,7 +875,7 @@ mod test_super {
             highlighting::Type::None,
             highlighting::Type::None,
         ];
-        row.highlight_match(&Some("t".to_string()));
+        row.highlight_match(&Some(Pattern::from("t")));
         assert_eq!(
             vec![
                 highlighting::Type::Number,
@@ -514,4 +899,134 @@ mod test_super {
         assert_eq!(row.find("t", 2, SearchDirection::Forward), Some(4));
         assert_eq!(row.find("t", 5, SearchDirection::Forward), Some(5));
     }
+
+    #[test]
+    fn test_highlight_python_block_string() {
+        let file_type = crate::FileType::from("script.py");
+        let opts = file_type.highlighting_options();
+        let mut first = Row::from("x = \"\"\"doc");
+        let mut second = Row::from("end\"\"\" # note");
+        let block = first.highlight(opts, &None, highlighting::Block::None);
+        assert_eq!(block, highlighting::Block::String('"'));
+        assert_eq!(
+            second.highlight(opts, &None, block),
+            highlighting::Block::None
+        );
+        assert_eq!(second.highlighting[5], highlighting::Type::String);
+        assert_eq!(second.highlighting[7], highlighting::Type::Comment);
+    }
+
+    #[test]
+    fn test_highlight_comment_tokens() {
+        let opts = HighlightingOptions::from_definition(
+            "multiline_comment_start: <!--\nmultiline_comment_end: -->\nstring_delimiters: '",
+        );
+        let mut row = Row::from("a <!-- b --> 'c\\'d' e");
+        row.highlight(&opts, &None, highlighting::Block::None);
+        assert_eq!(row.highlighting[1], highlighting::Type::None);
+        assert_eq!(row.highlighting[2], highlighting::Type::MultilineComment);
+        assert_eq!(row.highlighting[11], highlighting::Type::MultilineComment);
+        assert_eq!(row.highlighting[13], highlighting::Type::String);
+        assert_eq!(row.highlighting[18], highlighting::Type::String);
+        assert_eq!(row.highlighting[20], highlighting::Type::None);
+    }
+
+    #[test]
+    fn test_highlight_comment_markers() {
+        let opts = HighlightingOptions::from_definition("line_comment: #");
+        let mut row = Row::from("TODO # TODO: fix TODOS");
+        row.highlight(&opts, &None, highlighting::Block::None);
+        assert_eq!(row.highlighting[0], highlighting::Type::None);
+        assert_eq!(row.highlighting[7], highlighting::Type::CommentMarker);
+        assert_eq!(row.highlighting[11], highlighting::Type::Comment);
+        assert_eq!(row.highlighting[17], highlighting::Type::Comment);
+
+        let opts = HighlightingOptions::from_definition("line_comment: #\ncomment_markers: fix");
+        row.is_highlighted = false;
+        row.highlight(&opts, &None, highlighting::Block::None);
+        assert_eq!(row.highlighting[7], highlighting::Type::Comment);
+        assert_eq!(row.highlighting[13], highlighting::Type::CommentMarker);
+    }
+
+    #[test]
+    fn test_unicode_width() {
+        // "e" + combining acute, then a CJK and an emoji, both two columns wide
+        let row = Row::from("ae\u{301}漢😀c");
+        assert_eq!(row.len(), 5);
+        assert_eq!(row.width(), 7);
+        assert_eq!(row.width_until(2), 2);
+        assert_eq!(row.width_until(3), 4);
+        assert_eq!(row.index_at_width(3), 2);
+        assert_eq!(row.index_at_width(4), 3);
+        assert_eq!(row.index_at_width(10), 5);
+
+        let strip = |rendered: String| {
+            rendered
+                .replace(&format!("{}", SetForegroundColor(Color::Reset)), "")
+                .replace(
+                    &format!(
+                        "{}",
+                        SetForegroundColor(ColorScheme::default().color(highlighting::Type::None))
+                    ),
+                    "",
+                )
+        };
+        assert_eq!(strip(row.render(0, 7, None)), "ae\u{301}漢😀c");
+        // The left half of the CJK character is scrolled off, the emoji does not fit
+        assert_eq!(strip(row.render(3, 5, None)), " ");
+        assert_eq!(strip(row.render(4, 7, None)), "😀c");
+
+        let row = Row::from("a b \t");
+        let options = RenderOptions {
+            show_trailing: true,
+            ..RenderOptions::default()
+        };
+        let red = format!("{}", SetBackgroundColor(Color::DarkRed));
+        let reset = format!("{}", SetBackgroundColor(Color::Reset));
+        assert_eq!(
+            strip(row.render_with(0, 5, None, options)),
+            format!("a b{red}  {reset}")
+        );
+        assert_eq!(strip(row.render(0, 5, None)), "a b  ");
+        let options = RenderOptions {
+            list: true,
+            ..RenderOptions::default()
+        };
+        assert_eq!(strip(row.render_with(0, 6, None, options)), "a·b·→$");
+        assert_eq!(strip(row.render_with(0, 5, None, options)), "a·b·→");
+    }
+
+    #[test]
+    fn test_wrap_points() {
+        assert_eq!(Row::from("").wrap_points(4), vec![0]);
+        assert_eq!(Row::from("abcdefghij").wrap_points(4), vec![0, 4, 8]);
+        // The wide character does not fit in the last column and moves to the next line
+        assert_eq!(Row::from("abc漢de").wrap_points(4), vec![0, 3]);
+    }
+
+    #[test]
+    fn test_previous_word_start() {
+        let row = Row::from("let a_b = c(); ");
+        assert_eq!(row.previous_word_start(15), 11);
+        assert_eq!(row.previous_word_start(11), 10);
+        assert_eq!(row.previous_word_start(7), 4);
+        assert_eq!(row.previous_word_start(4), 0);
+        assert_eq!(row.previous_word_start(0), 0);
+    }
+
+    #[test]
+    fn test_find_regex() {
+        let row = Row::from("héllo wörld 42");
+        let pattern = Pattern::new(r"w\w+", true).unwrap_or_else(|_| Pattern::from(""));
+        assert_eq!(
+            row.find_pattern(&pattern, 0, SearchDirection::Forward),
+            Some((6, 5))
+        );
+        let digits = Pattern::new(r"\d", true).unwrap_or_else(|_| Pattern::from(""));
+        assert_eq!(
+            row.find_pattern(&digits, 14, SearchDirection::Backward),
+            Some((13, 1))
+        );
+        assert!(Pattern::new("(", true).is_err());
+    }
 }
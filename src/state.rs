@@ -0,0 +1,19 @@
+use std::fs;
+use std::path::Path;
+
+/// The lines of the state file `name` in `dir`, the directory of the files the
+/// editor keeps between runs, or none when it can't be read.
+#[must_use]
+pub fn read_lines(dir: &Path, name: &str) -> Vec<String> {
+    fs::read_to_string(dir.join(name))
+        .map(|contents| contents.lines().map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+/// Replaces the state file `name` in `dir` with `lines`. Errors are ignored,
+/// since the files only save the user some effort.
+pub fn write_lines(dir: &Path, name: &str, lines: &[String]) {
+    let contents: String = lines.iter().map(|line| format!("{line}\n")).collect();
+    let _ = fs::create_dir_all(dir);
+    let _ = fs::write(dir.join(name), contents);
+}
@@ -1,4 +1,5 @@
 use crate::Position;
+use core::time::Duration;
 use crossterm::{
     cursor,
     event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
@@ -6,49 +7,155 @@ use crossterm::{
     style::{Color, SetBackgroundColor, SetForegroundColor},
     terminal::{self, ClearType},
 };
-use std::io::{stdout, Write as _};
+use std::env;
+use std::io::{stdout, Error, Write as _};
 
+/// How much color the terminal is sent.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorMode {
+    /// No colors at all, for `NO_COLOR` or `--no-color`.
+    None,
+    /// RGB colors are sent as the nearest of the 256 indexed colors.
+    Ansi256,
+    #[default]
+    TrueColor,
+}
+
+impl ColorMode {
+    /// No colors when `no_color` is set or `NO_COLOR` is non-empty; RGB colors
+    /// when `COLORTERM` says the terminal supports them; otherwise 256 colors.
+    #[must_use]
+    pub fn detect(no_color: bool) -> Self {
+        let no_color_env = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        let truecolor = env::var("COLORTERM")
+            .is_ok_and(|value| matches!(value.as_str(), "truecolor" | "24bit"));
+        if no_color || no_color_env {
+            Self::None
+        } else if truecolor {
+            Self::TrueColor
+        } else {
+            Self::Ansi256
+        }
+    }
+    /// How `color` is sent in this mode, or None when colors are off.
+    #[must_use]
+    pub fn apply(self, color: Color) -> Option<Color> {
+        match (self, color) {
+            (Self::None, _) => None,
+            (Self::Ansi256, Color::Rgb { r, g, b }) => {
+                Some(Color::AnsiValue(nearest_ansi256(r, g, b)))
+            }
+            _ => Some(color),
+        }
+    }
+}
+
+/// Index of the entry of the 6x6x6 color cube or the gray ramp of the 256
+/// color palette closest to an RGB color.
+fn nearest_ansi256(r: u8, g: u8, b: u8) -> u8 {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    let distance = |color: [u8; 3]| {
+        [r, g, b]
+            .iter()
+            .zip(color)
+            .map(|(&a, b)| u32::from(a.abs_diff(b)).pow(2))
+            .sum::<u32>()
+    };
+    let level = |value: u8| {
+        (0_u8..6)
+            .zip(LEVELS)
+            .min_by_key(|&(_, level)| value.abs_diff(level))
+            .unwrap_or_default()
+    };
+    let (ri, rl) = level(r);
+    let (gi, gl) = level(g);
+    let (bi, bl) = level(b);
+    let cube = 16_u8
+        .saturating_add(ri.saturating_mul(36))
+        .saturating_add(gi.saturating_mul(6))
+        .saturating_add(bi);
+    // The gray ramp runs from 8 to 238 in steps of 10
+    let average = u32::from(r)
+        .saturating_add(u32::from(g))
+        .saturating_add(u32::from(b))
+        / 3;
+    let step = u8::try_from(average.saturating_sub(3) / 10)
+        .unwrap_or(23)
+        .min(23);
+    let gray = step.saturating_mul(10).saturating_add(8);
+    if distance([gray, gray, gray]) < distance([rl, gl, bl]) {
+        232_u8.saturating_add(step)
+    } else {
+        cube
+    }
+}
+
+#[derive(Clone)]
 pub struct Size {
     pub width: u16,
     pub height: u16,
 }
 
+/// Where the editor draws its frames and reads its keys. `Terminal` is the real
+/// screen; other implementations let another program drive the editor.
+pub trait Backend {
+    /// Columns, and rows left for the text.
+    fn size(&self) -> &Size;
+    /// Leaves `rows` more rows, besides the status and message bars, out of the
+    /// text height.
+    fn set_extra_bar_rows(&mut self, rows: u16);
+    fn clear_screen(&self) -> Result<(), Error>;
+    fn clear_current_line(&self) -> Result<(), Error>;
+    fn cursor_position(&self, position: &Position) -> Result<(), Error>;
+    fn cursor_hide(&self) -> Result<(), Error>;
+    fn cursor_show(&self) -> Result<(), Error>;
+    /// Draws `text` at the cursor. It may hold escape sequences for colors and
+    /// attributes.
+    fn print(&self, text: &str) -> Result<(), Error>;
+    /// Draws `text` and moves to the start of the next row.
+    fn print_line(&self, text: &str) -> Result<(), Error> {
+        self.print(text)?;
+        self.print("\r\n")
+    }
+    /// Sets the background color. Callers convert colors for the color mode
+    /// first, with `ColorMode::apply`.
+    fn set_bg_color(&self, color: Color) -> Result<(), Error>;
+    fn set_fg_color(&self, color: Color) -> Result<(), Error>;
+    fn reset_bg_color(&self) -> Result<(), Error> {
+        self.set_bg_color(Color::Reset)
+    }
+    fn reset_fg_color(&self) -> Result<(), Error> {
+        self.set_fg_color(Color::Reset)
+    }
+    fn flush(&self) -> Result<(), Error>;
+    /// Waits up to `timeout` for a keypress, returning None when there was none.
+    fn poll_key(&self, timeout: Duration) -> Result<Option<(KeyCode, KeyModifiers)>, Error>;
+}
+
+/// Rows below the text for the status and message bars.
+pub(crate) const BAR_ROWS: u16 = 2;
+
 pub struct Terminal {
     size: Size,
+    /// Rows of the whole screen, including those `size` leaves for bars.
+    rows: u16,
 }
 
 impl Terminal {
-    pub fn default() -> Result<Self, std::io::Error> {
+    #[expect(clippy::should_implement_trait)]
+    pub fn default() -> Result<Self, Error> {
         let size = terminal::size()?;
         terminal::enable_raw_mode()?;
         Ok(Self {
             size: Size {
                 width: size.0,
-                height: size.1.saturating_sub(2),
+                height: size.1.saturating_sub(BAR_ROWS),
             },
+            rows: size.1,
         })
     }
 
-    #[must_use] 
-    pub fn size(&self) -> &Size {
-        &self.size
-    }
-
-    pub fn clear_screen() {
-        execute!(stdout(), terminal::Clear(ClearType::All)).unwrap();
-    }
-
-    pub fn cursor_position(position: &Position) {
-        let x = position.x as u16;
-        let y = position.y as u16;
-        execute!(stdout(), cursor::MoveTo(x, y)).unwrap();
-    }
-
-    pub fn flush() -> Result<(), std::io::Error> {
-        stdout().flush()
-    }
-
-    pub fn read_key() -> Result<KeyCode, std::io::Error> {
+    pub fn read_key() -> Result<KeyCode, Error> {
         loop {
             if let Event::Key(KeyEvent {
                 code,
@@ -64,7 +171,7 @@ impl Terminal {
         }
     }
 
-    pub fn read_key_with_modifiers() -> Result<(KeyCode, KeyModifiers), std::io::Error> {
+    pub fn read_key_with_modifiers() -> Result<(KeyCode, KeyModifiers), Error> {
         loop {
             if let Event::Key(KeyEvent {
                 code,
@@ -79,40 +186,123 @@ impl Terminal {
             }
         }
     }
+}
+
+impl Backend for Terminal {
+    fn size(&self) -> &Size {
+        &self.size
+    }
 
-    pub fn cursor_hide() {
-        execute!(stdout(), cursor::Hide).unwrap();
+    fn set_extra_bar_rows(&mut self, rows: u16) {
+        self.size.height = self.rows.saturating_sub(BAR_ROWS).saturating_sub(rows);
     }
 
-    pub fn cursor_show() {
-        execute!(stdout(), cursor::Show).unwrap();
+    fn clear_screen(&self) -> Result<(), Error> {
+        execute!(stdout(), terminal::Clear(ClearType::All))
     }
 
-    pub fn clear_current_line() {
-        execute!(stdout(), terminal::Clear(ClearType::CurrentLine)).unwrap();
+    fn clear_current_line(&self) -> Result<(), Error> {
+        execute!(stdout(), terminal::Clear(ClearType::CurrentLine))
+    }
+
+    fn cursor_position(&self, position: &Position) -> Result<(), Error> {
+        let x = position.x as u16;
+        let y = position.y as u16;
+        execute!(stdout(), cursor::MoveTo(x, y))
     }
 
-    pub fn set_bg_color(color: Color) {
-        execute!(stdout(), SetBackgroundColor(color)).unwrap();
+    fn cursor_hide(&self) -> Result<(), Error> {
+        execute!(stdout(), cursor::Hide)
     }
 
-    pub fn reset_bg_color() {
-        execute!(stdout(), SetBackgroundColor(Color::Reset)).unwrap();
+    fn cursor_show(&self) -> Result<(), Error> {
+        execute!(stdout(), cursor::Show)
     }
 
-    pub fn set_fg_color(color: Color) {
-        execute!(stdout(), SetForegroundColor(color)).unwrap();
+    fn print(&self, text: &str) -> Result<(), Error> {
+        stdout().write_all(text.as_bytes())
     }
 
-    pub fn reset_fg_color() {
-        execute!(stdout(), SetForegroundColor(Color::Reset)).unwrap();
+    fn set_bg_color(&self, color: Color) -> Result<(), Error> {
+        execute!(stdout(), SetBackgroundColor(color))
+    }
+
+    fn set_fg_color(&self, color: Color) -> Result<(), Error> {
+        execute!(stdout(), SetForegroundColor(color))
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        stdout().flush()
+    }
+
+    fn poll_key(&self, timeout: Duration) -> Result<Option<(KeyCode, KeyModifiers)>, Error> {
+        while event::poll(timeout)? {
+            if let Event::Key(KeyEvent {
+                code,
+                modifiers,
+                kind: KeyEventKind::Press,
+                state: _,
+            }) = event::read()?
+            {
+                return Ok(Some((code, modifiers)));
+            }
+        }
+        Ok(None)
     }
 }
 
 impl Drop for Terminal {
     fn drop(&mut self) {
-        terminal::disable_raw_mode().unwrap();
-        Self::clear_screen();
-        Self::cursor_show();
+        // Nothing can be done about errors while shutting down
+        let _ = terminal::disable_raw_mode();
+        let _ = self.clear_screen();
+        let _ = self.cursor_show();
+    }
+}
+
+/// Makes a panic leave raw mode, show the cursor and clear the screen before
+/// the panic message is printed, so the shell is usable afterwards.
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // Errors are ignored: the terminal may be what failed
+        let _ = terminal::disable_raw_mode();
+        let _ = execute!(
+            stdout(),
+            SetForegroundColor(Color::Reset),
+            SetBackgroundColor(Color::Reset),
+            terminal::Clear(ClearType::All),
+            cursor::MoveTo(0, 0),
+            cursor::Show
+        );
+        default_hook(info);
+    }));
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_nearest_ansi256() {
+        // Corners of the color cube
+        assert_eq!(nearest_ansi256(0, 0, 0), 16);
+        assert_eq!(nearest_ansi256(255, 255, 255), 231);
+        assert_eq!(nearest_ansi256(255, 0, 0), 196);
+        assert_eq!(nearest_ansi256(0, 255, 0), 46);
+        assert_eq!(nearest_ansi256(0, 0, 255), 21);
+        assert_eq!(nearest_ansi256(255, 255, 0), 226);
+        // Grays between the cube's levels go to the gray ramp, from 8 to 238
+        assert_eq!(nearest_ansi256(8, 8, 8), 232);
+        assert_eq!(nearest_ansi256(128, 128, 128), 244);
+        assert_eq!(nearest_ansi256(238, 238, 238), 255);
+        assert_eq!(nearest_ansi256(250, 250, 250), 231);
+        // ...but a gray on one of the cube's levels stays in the cube
+        assert_eq!(nearest_ansi256(95, 95, 95), 59);
+        assert_eq!(
+            ColorMode::Ansi256.apply(Color::Rgb { r: 255, g: 0, b: 0 }),
+            Some(Color::AnsiValue(196))
+        );
+        assert_eq!(ColorMode::None.apply(Color::Reset), None);
     }
 }
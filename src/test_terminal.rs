@@ -0,0 +1,223 @@
+use crate::terminal::BAR_ROWS;
+use crate::{Backend, Position, Size};
+use core::cell::RefCell;
+use core::time::Duration;
+use crossterm::event::{KeyCode, KeyModifiers};
+use crossterm::style::Color;
+use std::collections::VecDeque;
+use std::io::Error;
+use std::rc::Rc;
+use unicode_width::UnicodeWidthChar as _;
+
+/// What has been drawn on a `TestTerminal`, and the keys waiting to be read.
+struct Screen {
+    /// One entry per cell; the cell right of a wide character holds None.
+    cells: Vec<Vec<Option<char>>>,
+    cursor: Position,
+    keys: VecDeque<(KeyCode, KeyModifiers)>,
+}
+
+impl Screen {
+    fn put(&mut self, c: char) {
+        let width = c.width().unwrap_or(0);
+        if width == 0 {
+            return;
+        }
+        let Position { x, y } = self.cursor;
+        if let Some(row) = self.cells.get_mut(y) {
+            for (offset, cell) in row.iter_mut().skip(x).take(width).enumerate() {
+                *cell = (offset == 0).then_some(c);
+            }
+        }
+        self.cursor.x = x.saturating_add(width);
+    }
+    /// Draws `text`, moving to the start of the row for `\r` and down a row for
+    /// `\n`. Escape sequences are skipped, except for moves to a column.
+    fn print(&mut self, text: &str) {
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\r' => self.cursor.x = 0,
+                '\n' => self.cursor.y = self.cursor.y.saturating_add(1),
+                '\u{1b}' if chars.peek() == Some(&'[') => {
+                    chars.next();
+                    let mut parameters = String::new();
+                    for c in chars.by_ref() {
+                        if c.is_ascii_alphabetic() {
+                            if c == 'G' {
+                                let column: usize = parameters.parse().unwrap_or(1);
+                                self.cursor.x = column.saturating_sub(1);
+                            }
+                            break;
+                        }
+                        parameters.push(c);
+                    }
+                }
+                _ => self.put(c),
+            }
+        }
+    }
+}
+
+/// A `Backend` that draws into memory instead of on the terminal, and reads
+/// keys queued with `push_key`, so that tests and other programs can drive the
+/// editor and look at the frames. Clones share the same screen and keys.
+#[derive(Clone)]
+pub struct TestTerminal {
+    screen: Rc<RefCell<Screen>>,
+    size: Size,
+    /// Rows of the whole screen, including those `size` leaves for bars.
+    rows: u16,
+}
+
+impl TestTerminal {
+    /// A blank screen of `width` columns and `rows` rows, two of which are
+    /// left for the status and message bars.
+    #[must_use]
+    pub fn new(width: u16, rows: u16) -> Self {
+        let screen = Screen {
+            cells: vec![vec![Some(' '); width as usize]; rows as usize],
+            cursor: Position::default(),
+            keys: VecDeque::new(),
+        };
+        let mut terminal = Self {
+            screen: Rc::new(RefCell::new(screen)),
+            size: Size { width, height: 0 },
+            rows,
+        };
+        terminal.set_extra_bar_rows(0);
+        terminal
+    }
+    /// Queues a key for the editor to read.
+    pub fn push_key(&self, code: KeyCode, modifiers: KeyModifiers) {
+        self.screen.borrow_mut().keys.push_back((code, modifiers));
+    }
+    /// Queues the keys that type `text`, with Enter for each newline.
+    pub fn type_text(&self, text: &str) {
+        for c in text.chars() {
+            let code = if c == '\n' {
+                KeyCode::Enter
+            } else {
+                KeyCode::Char(c)
+            };
+            self.push_key(code, KeyModifiers::NONE);
+        }
+    }
+    /// How many queued keys have not been read yet.
+    #[must_use]
+    pub fn pending_keys(&self) -> usize {
+        self.screen.borrow().keys.len()
+    }
+    /// Row `y` of the screen, without trailing spaces.
+    #[must_use]
+    pub fn line(&self, y: usize) -> String {
+        let screen = self.screen.borrow();
+        let line: String = screen
+            .cells
+            .get(y)
+            .map(|row| row.iter().flatten().collect())
+            .unwrap_or_default();
+        line.trim_end().to_owned()
+    }
+    /// The whole screen, one line per row.
+    #[must_use]
+    pub fn frame(&self) -> String {
+        (0..self.rows as usize)
+            .map(|y| self.line(y))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+    /// Where the cursor was left.
+    #[must_use]
+    pub fn cursor(&self) -> Position {
+        self.screen.borrow().cursor.clone()
+    }
+}
+
+impl Backend for TestTerminal {
+    fn size(&self) -> &Size {
+        &self.size
+    }
+
+    fn set_extra_bar_rows(&mut self, rows: u16) {
+        self.size.height = self.rows.saturating_sub(BAR_ROWS).saturating_sub(rows);
+    }
+
+    fn clear_screen(&self) -> Result<(), Error> {
+        for row in &mut self.screen.borrow_mut().cells {
+            row.fill(Some(' '));
+        }
+        Ok(())
+    }
+
+    fn clear_current_line(&self) -> Result<(), Error> {
+        let mut screen = self.screen.borrow_mut();
+        let y = screen.cursor.y;
+        if let Some(row) = screen.cells.get_mut(y) {
+            row.fill(Some(' '));
+        }
+        Ok(())
+    }
+
+    fn cursor_position(&self, position: &Position) -> Result<(), Error> {
+        self.screen.borrow_mut().cursor = position.clone();
+        Ok(())
+    }
+
+    fn cursor_hide(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn cursor_show(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn print(&self, text: &str) -> Result<(), Error> {
+        self.screen.borrow_mut().print(text);
+        Ok(())
+    }
+
+    fn set_bg_color(&self, _color: Color) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_fg_color(&self, _color: Color) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Returns the next queued key at once, without waiting for `timeout`.
+    fn poll_key(&self, _timeout: Duration) -> Result<Option<(KeyCode, KeyModifiers)>, Error> {
+        Ok(self.screen.borrow_mut().keys.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::Editor;
+
+    #[test]
+    fn test_print_skips_escapes() {
+        let terminal = TestTerminal::new(10, 3);
+        terminal.print_line("\u{1b}[1mab\u{1b}[22m").unwrap();
+        terminal.print("界x\u{1b}[6G>").unwrap();
+        assert_eq!(terminal.frame(), "ab\n界x  >\n");
+        assert_eq!(terminal.cursor(), Position { x: 6, y: 1 });
+    }
+
+    #[test]
+    fn test_render_editor_frame() {
+        let terminal = TestTerminal::new(40, 6);
+        let config_dir = std::env::temp_dir().join("wd40_test_config");
+        let args = [format!("--config-dir={}", config_dir.display())];
+        let mut editor = Editor::with_backend(Box::new(terminal.clone()), &args);
+        editor.refresh_screen().unwrap();
+        let frame = terminal.frame();
+        assert!(frame.contains("wd40 -- version"), "{frame}");
+        assert!(terminal.line(4).starts_with("[No Name]"), "{frame}");
+    }
+}
@@ -0,0 +1,223 @@
+use crate::highlighting::Type;
+use crossterm::style::Color;
+use std::fs;
+use std::path::Path;
+
+pub struct Theme {
+    pub status_fg: Color,
+    pub status_bg: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            status_fg: Color::Rgb {
+                r: 63,
+                g: 63,
+                b: 63,
+            },
+            status_bg: Color::Rgb {
+                r: 239,
+                g: 239,
+                b: 239,
+            },
+        }
+    }
+}
+
+/// Parses `#rrggbb` (the `#` is optional) into an RGB color.
+#[must_use]
+pub fn parse_hex_color(value: &str) -> Option<Color> {
+    let hex = value.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |range: core::ops::Range<usize>| u8::from_str_radix(hex.get(range)?, 16).ok();
+    Some(Color::Rgb {
+        r: channel(0..2)?,
+        g: channel(2..4)?,
+        b: channel(4..6)?,
+    })
+}
+
+/// Formats an RGB color as `#rrggbb`, the form `parse_hex_color` reads.
+#[must_use]
+pub fn format_hex_color(color: Color) -> String {
+    match color {
+        Color::Rgb { r, g, b } => format!("#{r:02x}{g:02x}{b:02x}"),
+        other => format!("{other:?}"),
+    }
+}
+
+/// Foreground color of each kind of highlighted text.
+#[derive(Clone, Copy)]
+pub struct ColorScheme {
+    pub number: Color,
+    pub search_match: Color,
+    pub current_match: Color,
+    pub matching_bracket: Color,
+    pub string: Color,
+    pub character: Color,
+    pub comment: Color,
+    pub comment_marker: Color,
+    pub primary_keywords: Color,
+    pub secondary_keywords: Color,
+}
+
+impl Default for ColorScheme {
+    fn default() -> Self {
+        Self::dark()
+    }
+}
+
+impl ColorScheme {
+    /// Colors for light text on a dark background.
+    #[must_use]
+    pub fn dark() -> Self {
+        Self {
+            number: rgb(220, 163, 163),
+            search_match: rgb(38, 139, 210),
+            current_match: rgb(203, 75, 22),
+            matching_bracket: rgb(255, 215, 0),
+            string: rgb(211, 54, 130),
+            character: rgb(108, 113, 196),
+            comment: rgb(133, 153, 0),
+            comment_marker: rgb(255, 85, 85),
+            primary_keywords: rgb(181, 137, 0),
+            secondary_keywords: rgb(42, 161, 152),
+        }
+    }
+    /// Darker colors that stay readable on a light background.
+    #[must_use]
+    pub fn light() -> Self {
+        Self {
+            number: rgb(160, 60, 60),
+            search_match: rgb(0, 95, 175),
+            current_match: rgb(175, 60, 0),
+            matching_bracket: rgb(175, 95, 0),
+            string: rgb(160, 30, 100),
+            character: rgb(80, 80, 170),
+            comment: rgb(95, 115, 0),
+            comment_marker: rgb(200, 0, 0),
+            primary_keywords: rgb(135, 95, 0),
+            secondary_keywords: rgb(0, 120, 110),
+        }
+    }
+    /// The built-in scheme called `name`, or the one in `colors/<name>` of
+    /// `config_dir`.
+    pub fn named(name: &str, config_dir: Option<&Path>) -> Result<Self, String> {
+        match name {
+            "dark" => Ok(Self::dark()),
+            "light" => Ok(Self::light()),
+            _ => {
+                let path = config_dir
+                    .map(|dir| dir.join("colors").join(name))
+                    .ok_or_else(|| format!("Unknown color scheme: {name}"))?;
+                let contents = fs::read_to_string(&path)
+                    .map_err(|_| format!("Unknown color scheme: {name}"))?;
+                Self::parse(&contents)
+            }
+        }
+    }
+    /// Reads `kind = #rrggbb` lines over the dark scheme. A `base = light` line
+    /// starts from the light scheme instead. Blank lines and `#` comments are
+    /// skipped.
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut scheme = Self::dark();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (kind, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Expected kind = #rrggbb: {line}"))?;
+            let (kind, value) = (kind.trim(), value.trim());
+            if kind == "base" {
+                scheme = match value {
+                    "dark" => Self::dark(),
+                    "light" => Self::light(),
+                    _ => return Err(format!("Unknown base: {value} (expected dark or light)")),
+                };
+                continue;
+            }
+            let color = parse_hex_color(value).ok_or_else(|| format!("Invalid color: {value}"))?;
+            let slot = match kind {
+                "number" => &mut scheme.number,
+                "match" => &mut scheme.search_match,
+                "current_match" => &mut scheme.current_match,
+                "bracket" => &mut scheme.matching_bracket,
+                "string" => &mut scheme.string,
+                "character" => &mut scheme.character,
+                "comment" => &mut scheme.comment,
+                "comment_marker" => &mut scheme.comment_marker,
+                "keyword" => &mut scheme.primary_keywords,
+                "keyword2" => &mut scheme.secondary_keywords,
+                _ => return Err(format!("Unknown kind: {kind}")),
+            };
+            *slot = color;
+        }
+        Ok(scheme)
+    }
+    /// Color text highlighted as `hl_type` is drawn in.
+    #[must_use]
+    pub fn color(&self, hl_type: Type) -> Color {
+        match hl_type {
+            Type::Number => self.number,
+            Type::Match => self.search_match,
+            Type::CurrentMatch => self.current_match,
+            Type::MatchingBracket => self.matching_bracket,
+            Type::String => self.string,
+            Type::Character => self.character,
+            Type::Comment | Type::MultilineComment => self.comment,
+            Type::CommentMarker => self.comment_marker,
+            Type::PrimaryKeywords => self.primary_keywords,
+            Type::SecondaryKeywords => self.secondary_keywords,
+            Type::None => Color::Reset,
+        }
+    }
+}
+
+const fn rgb(r: u8, g: u8, b: u8) -> Color {
+    Color::Rgb { r, g, b }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_parse_color_scheme() {
+        let scheme = ColorScheme::parse(
+            "# comments and blank lines are skipped\n\nbase = light\nnumber = #0a0B0c\n  keyword2=ff0000  \n",
+        )
+        .unwrap();
+        assert_eq!(scheme.number, rgb(10, 11, 12));
+        assert_eq!(scheme.secondary_keywords, rgb(255, 0, 0));
+        // Kinds that aren't listed keep the base scheme's colors
+        assert_eq!(scheme.string, ColorScheme::light().string);
+        assert_eq!(
+            ColorScheme::parse("").unwrap().string,
+            ColorScheme::dark().string
+        );
+
+        let error = |contents: &str| ColorScheme::parse(contents).err();
+        assert_eq!(
+            error("strings = #ffffff"),
+            Some("Unknown kind: strings".to_owned())
+        );
+        assert_eq!(
+            error("base = solarized"),
+            Some("Unknown base: solarized (expected dark or light)".to_owned())
+        );
+        for bad in ["#fff", "#gggggg", "#1234567", ""] {
+            assert_eq!(
+                error(&format!("number = {bad}")),
+                Some(format!("Invalid color: {bad}"))
+            );
+        }
+        assert_eq!(
+            error("number #ffffff"),
+            Some("Expected kind = #rrggbb: number #ffffff".to_owned())
+        );
+    }
+}